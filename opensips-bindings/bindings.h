@@ -1,8 +1,8 @@
-// These were copy/pasted from an arbitrary build of the `options`
-// module.
-//
-// TODO: These need to be pulled from the version of OpenSIPS we are
-// building against.
+// The `-D` flags of the OpenSIPS build come from `OPENSIPS_DEFS`
+// (see `build.rs`). Without them, these were copy/pasted from an
+// arbitrary build of the `options` module. The locking method isn't
+// among them, as a wrong guess breaks every lock shared with OpenSIPS.
+#ifndef OPENSIPS_DEFS
 #define PIC
 #define MOD_NAME "options"
 #define PKG_MALLOC
@@ -34,7 +34,6 @@
 #define HAVE_MSGHDR_MSG_CONTROL
 #define HAVE_ALLOCA_H
 #define HAVE_TIMEGM
-#define USE_POSIX_SEM
 #define HAVE_EPOLL
 #define HAVE_SIGIO_RT
 #define HAVE_SELECT
@@ -45,7 +44,27 @@
 #else
   #error "Unknown target architecture"
 #endif
+#endif
+
+#if !defined(FAST_LOCK) && !defined(USE_FUTEX) && !defined(USE_UMUTEX) && \
+    !defined(USE_PTHREAD_MUTEX) && !defined(USE_POSIX_SEM) && !defined(USE_SYSV_SEM)
+  #error "Unknown locking method, set OPENSIPS_DEFS to the -D flags OpenSIPS was built with"
+#endif
 
 #include "sr_module.h"
+#include "dprint.h"
+#include "ipc.h"
+#include "locking.h"
+#include "rw_locking.h"
+#include "mem/mem.h"
+#include "mem/shm_mem.h"
+#include "pt.h"
+#include "timer.h"
 #include "modules/signaling/signaling.h"
+#include "modules/tm/tm_load.h"
 #include "data_lump_rpl.h"
+#include "parser/parse_from.h"
+#include "parser/parse_uri.h"
+#include "pvar.h"
+#include "route_struct.h"
+#include "modules/usrloc/usrloc.h"
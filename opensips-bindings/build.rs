@@ -3,22 +3,43 @@ use bindgen::{
     callbacks::{IntKind, ParseCallbacks},
     EnumVariation,
 };
-use std::{env, path::PathBuf};
+use std::{env, error::Error, path::PathBuf};
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+fn main() -> Result<(), Box<dyn Error>> {
     let out_dir: PathBuf = env::var_os("OUT_DIR").ok_or("OUT_DIR not set")?.into();
     let cargo_cfg_target_arch = env::var("CARGO_CFG_TARGET_ARCH")?;
+    let arch = format!("CARGO_CFG_TARGET_ARCH__{cargo_cfg_target_arch}");
 
     let mut builder = builder();
+    let mut shim = cc::Build::new();
 
     if let Ok(src_dir) = env::var("OPENSIPS_SRC_DIR") {
-        builder = builder.clang_arg("-I").clang_arg(src_dir);
+        builder = builder.clang_arg("-I").clang_arg(&src_dir);
+        shim.include(src_dir);
     }
 
+    let defs = opensips_defs()?;
+    if !defs.is_empty() {
+        builder = builder.clang_arg("-D OPENSIPS_DEFS");
+        shim.define("OPENSIPS_DEFS", None);
+    }
+    for def in defs {
+        builder = builder.clang_arg(&def);
+        shim.flag(&def);
+    }
+
+    // Macros such as `lock_get` depend on the build of OpenSIPS, so
+    // they are wrapped in C instead of written again in Rust.
+    shim.file("shim.c")
+        .include(".")
+        .define(&arch, "1")
+        .warnings(false)
+        .compile("opensips-shim");
+    println!("cargo:rerun-if-changed=shim.c");
+    println!("cargo:rerun-if-changed=bindings.h");
+
     let bindings = builder
-        .clang_arg(format!(
-            "-D CARGO_CFG_TARGET_ARCH__{cargo_cfg_target_arch}=1"
-        ))
+        .clang_arg(format!("-D {arch}=1"))
         .header("bindings.h")
         // This has a duplicate definition
         .blocklist_item("IPPORT_RESERVED")
@@ -37,6 +58,20 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+/// The `-D` flags OpenSIPS was built with, as its `Makefile.defs`
+/// passes them to modules, from `OPENSIPS_DEFS`. They decide the
+/// layout of some types and what some macros do, e.g. the locking
+/// method, so they have to match the OpenSIPS running the module.
+fn opensips_defs() -> Result<Vec<String>, Box<dyn Error>> {
+    println!("cargo:rerun-if-env-changed=OPENSIPS_DEFS");
+
+    let Ok(defs) = env::var("OPENSIPS_DEFS") else {
+        return Ok(Vec::new());
+    };
+    let defs = shlex::split(&defs).ok_or("OPENSIPS_DEFS isn't quoted like a shell would")?;
+    Ok(defs.into_iter().filter(|d| d.starts_with("-D")).collect())
+}
+
 #[derive(Debug)]
 struct AdjustMacroTypes;
 
@@ -73,9 +108,14 @@ impl ParseCallbacks for AdjustMacroTypes {
             "LUMP_RPL_SHMEM",
         ];
 
+        let log_level_macro_names = [
+            "L_ALERT", "L_CRIT", "L_ERR", "L_WARN", "L_NOTICE", "L_INFO", "L_DBG",
+        ];
+
         if cmd_flag_macro_names.contains(&name)
             || cmd_param_macro_names.contains(&name)
             || lump_rpl_macro_names.contains(&name)
+            || log_level_macro_names.contains(&name)
         {
             Some(IntKind::Int)
         } else {
@@ -0,0 +1,45 @@
+// Functions for macros of OpenSIPS whose expansion depends on how it
+// was built, so the Rust side doesn't have to know.
+#include "bindings.h"
+
+gen_lock_t *rust_lock_init(gen_lock_t *lock)
+{
+	return lock_init(lock);
+}
+
+void rust_lock_destroy(gen_lock_t *lock)
+{
+#ifdef USE_POSIX_SEM
+	// `lock_destroy` does nothing for semaphores
+	sem_destroy(lock);
+#else
+	lock_destroy(lock);
+#endif
+}
+
+void rust_lock_get(gen_lock_t *lock)
+{
+	lock_get(lock);
+}
+
+void rust_lock_release(gen_lock_t *lock)
+{
+	lock_release(lock);
+}
+
+// Logs like `LM_GEN1` in a C module, so the prefix formats are those
+// of this build of OpenSIPS. Its macros name the module and function
+// of the call site, which come from Rust instead.
+#ifndef __DP_FUNC
+  #error "dprint.h no longer names the function with __DP_FUNC"
+#endif
+
+void rust_log(int level, const char *module, const char *func, int len,
+		const char *message)
+{
+#undef MOD_NAME
+#define MOD_NAME module
+#undef __DP_FUNC
+#define __DP_FUNC func
+	LM_GEN1(level, "%.*s\n", len, message);
+}
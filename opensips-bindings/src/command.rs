@@ -25,6 +25,21 @@ impl<'a> CommandFunctionParam for &'a str {
     }
 }
 
+impl<'a> CommandFunctionParam for &'a crate::pv::Variable {
+    const PARAM: opensips::cmd_param = opensips::cmd_param {
+        flags: opensips::CMD_PARAM_VAR,
+        fixup: None,
+        free_fixup: None,
+    };
+
+    /// # Safety
+    ///
+    /// This value needs to be a non-NULL variable parsed by OpenSIPS.
+    unsafe fn from_void_ptr(p: *mut c_void) -> Self {
+        crate::pv::Variable::from_ptr(p.cast())
+    }
+}
+
 pub trait CommandFunction<Args> {
     const PARAMS: [opensips::cmd_param; 9];
 
@@ -109,18 +124,64 @@ impl_command_function!([[A1, A2, A3, A4, A5, A6], [_, _]]);
 impl_command_function!([[A1, A2, A3, A4, A5, A6, A7], [_]]);
 impl_command_function!([[A1, A2, A3, A4, A5, A6, A7, A8], []]);
 
+/// A span describing the SIP message a command was invoked for.
+///
+/// Fields: `method` (requests only), `call_id`, `cseq` and `source`.
+#[cfg(feature = "tracing")]
+pub fn message_span(msg: *mut opensips::sip_msg) -> tracing::Span {
+    // SAFETY: [OpenSIPS::valid]
+    let Some(msg) = (unsafe { msg.as_mut() }) else {
+        return tracing::Span::none();
+    };
+
+    // A message that fails to parse still gets a span; the command
+    // will report the problem if it cares about these headers.
+    msg.parse_headers(crate::HDR_CALLID_F | crate::HDR_CSEQ_F);
+
+    tracing::info_span!(
+        "sip",
+        method = msg.method(),
+        call_id = msg.call_id(),
+        cseq = msg.cseq(),
+        source = msg.source().map(tracing::field::display),
+    )
+}
+
+#[doc(hidden)]
+#[cfg(feature = "tracing")]
+#[macro_export]
+macro_rules! __command_attribute {
+    (span, $msg:ident) => {
+        $crate::command::message_span($msg).entered()
+    };
+}
+
+#[doc(hidden)]
+#[cfg(not(feature = "tracing"))]
+#[macro_export]
+macro_rules! __command_attribute {
+    (span, $msg:ident) => {
+        compile_error!("`#[span]` requires the `tracing` feature of the bindings")
+    };
+}
+
 /// Generates a `static CMDS` with the specified functions. Shims that
 /// adapt from the OpenSIPS types will be automatically created. The
 /// provided functions must either have no arguments or one [`&mut
 /// opensips::sip_msg`] followed by up to eight additional arguments
 /// of [known types][CommandFunctionParam].
 ///
+/// With the `tracing` feature, `#[span]` makes the shim enter a
+/// [span describing the message][message_span] for the duration of
+/// the call.
+///
 /// ```rust,norun
 /// opensips::commands! {
 ///     #[name = "any-name-you-want"]
 ///     fn the_name_of_a_function;
 ///
 ///     #[name = "ReallyAnyName"]
+///     #[span]
 ///     fn another_function;
 /// }
 /// ```
@@ -128,6 +189,7 @@ impl_command_function!([[A1, A2, A3, A4, A5, A6, A7, A8], []]);
 macro_rules! commands {
     ($(
         #[name = $name:literal]
+        $(#[$attribute:ident])?
         fn $fn_name:ident;
     )*) => {
         mod command_shim {
@@ -147,6 +209,10 @@ macro_rules! commands {
                     arg7: *mut c_void,
                     arg8: *mut c_void,
                 ) -> i32 {
+                    $(
+                        let _span = $crate::__command_attribute!($attribute, msg);
+                    )?
+
                     super::$fn_name.adapt(msg, arg1, arg2, arg3, arg4, arg5, arg6, arg7, arg8)
                 }
             )*
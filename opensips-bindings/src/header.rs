@@ -0,0 +1,227 @@
+use core::fmt::Write;
+use core::str::FromStr;
+
+// An encoded word may be at most 75 characters long (RFC 2047
+// section 2), and `=?utf-8?B?` and `?=` take 12 of them. 45 bytes
+// of text become 60 characters of base64.
+const ENCODED_WORD_OVERHEAD: usize = 12;
+const ENCODED_WORD_TEXT: usize = 45;
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// How text outside of ASCII is written in a header value.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum Encoding {
+    /// As it is, which SIP allows.
+    #[default]
+    Utf8,
+    /// As RFC 2047 encoded words, when there is any.
+    Rfc2047,
+    /// Each byte as `%XX`, as is `%` itself.
+    Percent,
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "utf-8" => Ok(Self::Utf8),
+            "rfc2047" => Ok(Self::Rfc2047),
+            "percent" => Ok(Self::Percent),
+            _ => Err(format!(
+                "Unknown header encoding `{s}`, expected `utf-8`, `rfc2047` or `percent`"
+            )),
+        }
+    }
+}
+
+/// Makes `value` safe to use as the value of a header. Line breaks,
+/// other control characters and runs of whitespace become a single
+/// space, so the value can't end the header or fold it. The result
+/// is cut to at most `max_len` bytes, never in the middle of a
+/// character or an encoding.
+pub fn encode_value(value: &str, encoding: Encoding, max_len: Option<usize>) -> String {
+    let value = unfold(value);
+    let max_len = max_len.unwrap_or(usize::MAX);
+    let mut encoded = String::new();
+
+    match encoding {
+        Encoding::Rfc2047 if !value.is_ascii() => {
+            for word in chunks(&value, ENCODED_WORD_TEXT) {
+                let separator = usize::from(!encoded.is_empty());
+                let available = max_len.saturating_sub(encoded.len() + separator);
+                let text_len = available.saturating_sub(ENCODED_WORD_OVERHEAD) / 4 * 3;
+
+                let text = prefix(word, text_len);
+                if text.is_empty() {
+                    break;
+                }
+                if separator > 0 {
+                    encoded.push(' ');
+                }
+                encoded.push_str("=?utf-8?B?");
+                base64(text.as_bytes(), &mut encoded);
+                encoded.push_str("?=");
+
+                // Words after a cut one would leave a gap.
+                if text.len() < word.len() {
+                    break;
+                }
+            }
+        }
+        Encoding::Percent => {
+            let mut buf = [0; 4];
+            for c in value.chars() {
+                let len = if c == '%' || !c.is_ascii() {
+                    c.len_utf8() * 3
+                } else {
+                    1
+                };
+                if encoded.len() + len > max_len {
+                    break;
+                }
+
+                if len == 1 {
+                    encoded.push(c);
+                } else {
+                    for b in c.encode_utf8(&mut buf).bytes() {
+                        let _ = write!(encoded, "%{b:02X}");
+                    }
+                }
+            }
+        }
+        Encoding::Utf8 | Encoding::Rfc2047 => {
+            encoded.push_str(prefix(&value, max_len));
+        }
+    }
+
+    encoded
+}
+
+fn unfold(value: &str) -> String {
+    let mut unfolded = String::with_capacity(value.len());
+    let mut space = false;
+
+    for c in value.chars() {
+        if c.is_whitespace() || c.is_control() {
+            // Leading and trailing whitespace is dropped.
+            space = !unfolded.is_empty();
+            continue;
+        }
+        if space {
+            unfolded.push(' ');
+            space = false;
+        }
+        unfolded.push(c);
+    }
+
+    unfolded
+}
+
+/// The longest prefix of `s` of at most `max_len` bytes.
+fn prefix(s: &str, max_len: usize) -> &str {
+    let mut end = max_len.min(s.len());
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+/// Pieces of `s` of at most `max_len` bytes each. `max_len` has to
+/// fit any character.
+fn chunks(s: &str, max_len: usize) -> impl Iterator<Item = &str> {
+    let mut rest = s;
+    core::iter::from_fn(move || {
+        let chunk = prefix(rest, max_len);
+        rest = &rest[chunk.len()..];
+        (!chunk.is_empty()).then_some(chunk)
+    })
+}
+
+fn base64(bytes: &[u8], out: &mut String) {
+    for chunk in bytes.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = u32::from(b[0]) << 16 | u32::from(b[1]) << 8 | u32::from(b[2]);
+
+        for i in 0..4 {
+            if i <= chunk.len() {
+                let index = (n >> (18 - 6 * i)) & 0x3f;
+                out.push(char::from(BASE64[index as usize]));
+            } else {
+                out.push('=');
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{base64, encode_value, Encoding};
+
+    fn base64_of(bytes: &[u8]) -> String {
+        let mut out = String::new();
+        base64(bytes, &mut out);
+        out
+    }
+
+    #[test]
+    fn strips_line_breaks_and_folding() {
+        let value = encode_value(" Hello\r\n world\r\n\tagain \n", Encoding::Utf8, None);
+        assert_eq!(value, "Hello world again");
+
+        let value = encode_value("Hi\r\nVia: evil", Encoding::Utf8, None);
+        assert_eq!(value, "Hi Via: evil");
+    }
+
+    #[test]
+    fn cuts_at_a_char_boundary() {
+        assert_eq!(encode_value("héllo", Encoding::Utf8, Some(2)), "h");
+        assert_eq!(encode_value("héllo", Encoding::Utf8, Some(3)), "hé");
+
+        assert_eq!(encode_value("aé", Encoding::Percent, Some(6)), "a");
+        assert_eq!(encode_value("aé", Encoding::Percent, Some(7)), "a%C3%A9");
+    }
+
+    #[test]
+    fn leaves_out_words_without_room_for_text() {
+        for max_len in 0..=12 {
+            assert_eq!(encode_value("héllo", Encoding::Rfc2047, Some(max_len)), "");
+        }
+
+        let value = encode_value("héllo", Encoding::Rfc2047, Some(16));
+        assert_eq!(value, "=?utf-8?B?aMOp?=");
+    }
+
+    #[test]
+    fn splits_long_text_into_short_words() {
+        let text = "é".repeat(100);
+        let value = encode_value(&text, Encoding::Rfc2047, None);
+        let words: Vec<_> = value.split(' ').collect();
+
+        assert_eq!(words.len(), 5);
+        for word in words {
+            assert!(word.len() <= 75, "{word}");
+            assert!(
+                word.starts_with("=?utf-8?B?") && word.ends_with("?="),
+                "{word}"
+            );
+        }
+    }
+
+    #[test]
+    fn pads_base64() {
+        assert_eq!(base64_of(b""), "");
+        assert_eq!(base64_of(b"a"), "YQ==");
+        assert_eq!(base64_of(b"ab"), "YWI=");
+        assert_eq!(base64_of(b"abc"), "YWJj");
+        assert_eq!(
+            encode_value("é", Encoding::Rfc2047, None),
+            "=?utf-8?B?w6k=?="
+        );
+    }
+}
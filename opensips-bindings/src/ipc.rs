@@ -0,0 +1,220 @@
+use core::marker::PhantomData;
+use core::{fmt, mem};
+use std::ffi::CString;
+use std::os::raw::{c_char, c_int, c_void};
+use std::panic::{self, AssertUnwindSafe};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::generated as opensips;
+use crate::memory::ShmString;
+
+/// A message that can be sent to other OpenSIPS processes. It is
+/// serialized into shared memory and handled by the IPC reactor of
+/// the destination process.
+pub trait Job: Serialize + DeserializeOwned {
+    /// Runs in the destination process. `sender` is the process
+    /// number of the sending process.
+    fn run(self, sender: c_int);
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Serialize(serde_json::Error),
+    OutOfMemory,
+    Send,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Serialize(e) => write!(f, "unable to serialize IPC job: {e}"),
+            Self::OutOfMemory => f.write_str("out of shared memory for IPC job"),
+            Self::Send => f.write_str("unable to send IPC job"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// A registered IPC handler for jobs of type `J`.
+pub struct Handler<J> {
+    type_: opensips::ipc_handler_type,
+    _job: PhantomData<fn(J)>,
+}
+
+impl<J> Clone for Handler<J> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<J> Copy for Handler<J> {}
+
+impl<J> fmt::Debug for Handler<J> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Handler").field(&self.type_).finish()
+    }
+}
+
+/// Registers the handler for jobs of type `J`. This must be called
+/// during module initialization, before the processes fork.
+pub fn register_handler<J: Job>(name: &str) -> Option<Handler<J>> {
+    // OpenSIPS keeps the pointer for the lifetime of the process.
+    let name = CString::new(name).ok()?.into_raw();
+
+    // SAFETY: The trampoline matches the expected signature and the
+    // name lives forever.
+    let type_ = unsafe { opensips::ipc_register_handler(Some(job_trampoline::<J>), name) };
+
+    if type_ < 0 {
+        return None;
+    }
+
+    Some(Handler {
+        type_,
+        _job: PhantomData,
+    })
+}
+
+extern "C" fn job_trampoline<J: Job>(sender: c_int, payload: *mut c_void) {
+    // SAFETY: The payload was created by `Handler::payload` and each
+    // payload is only sent to one process.
+    let payload = unsafe { ShmString::from_raw(payload.cast()) };
+
+    // The job was serialized by the same code, so this can only fail
+    // if something is very wrong. Panicking would unwind into C.
+    let job = match serde_json::from_str::<J>(&payload) {
+        Ok(job) => job,
+        Err(e) => {
+            crate::log::error(
+                "job_trampoline",
+                &format!("Dropping an invalid IPC job: {e}"),
+            );
+            return;
+        }
+    };
+    drop(payload);
+
+    // Unwinding into C would abort OpenSIPS.
+    if panic::catch_unwind(AssertUnwindSafe(|| job.run(sender))).is_err() {
+        crate::log::error("job_trampoline", "An IPC job panicked");
+    }
+}
+
+impl<J: Job> Handler<J> {
+    /// Sends the job to the process with number `dst_proc`.
+    pub fn send(&self, dst_proc: c_int, job: &J) -> Result<(), Error> {
+        let payload = Self::payload(job)?;
+
+        // SAFETY: The handler was registered and ownership of the
+        // payload is passed to the receiver on success.
+        let rc = unsafe { opensips::ipc_send_job(dst_proc, self.type_, payload.cast()) };
+        Self::check_sent(rc, payload)
+    }
+
+    /// Sends the job to any one of the SIP workers.
+    pub fn dispatch(&self, job: &J) -> Result<(), Error> {
+        let payload = Self::payload(job)?;
+
+        // SAFETY: The handler was registered and ownership of the
+        // payload is passed to the receiver on success.
+        let rc = unsafe { opensips::ipc_dispatch_job(self.type_, payload.cast()) };
+        Self::check_sent(rc, payload)
+    }
+
+    /// Sends a copy of the job to every other running process that
+    /// accepts IPC jobs. Returns the number of processes the job was
+    /// sent to; failing to send to one process doesn't stop the
+    /// others.
+    pub fn broadcast(&self, job: &J) -> Result<usize, Error> {
+        let mut sent = 0;
+
+        for dst_proc in ipc_processes() {
+            match self.send(dst_proc, job) {
+                Ok(()) => sent += 1,
+                Err(Error::Send) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(sent)
+    }
+
+    fn payload(job: &J) -> Result<*mut c_char, Error> {
+        let json = serde_json::to_string(job).map_err(Error::Serialize)?;
+        let payload = ShmString::new(&json).ok_or(Error::OutOfMemory)?;
+        Ok(payload.into_raw())
+    }
+
+    fn check_sent(rc: c_int, payload: *mut c_char) -> Result<(), Error> {
+        if rc < 0 {
+            // SAFETY: The job wasn't sent so we still own the payload.
+            drop(unsafe { ShmString::from_raw(payload) });
+            return Err(Error::Send);
+        }
+        Ok(())
+    }
+}
+
+/// Runs `f` in the process with number `dst_proc`. As every process
+/// is forked from the same image, the function pointer is valid
+/// everywhere.
+pub fn send_rpc(dst_proc: c_int, f: fn(sender: c_int)) -> Result<(), Error> {
+    // SAFETY: The trampoline matches the expected signature and the
+    // parameter is a plain function pointer.
+    let rc = unsafe { opensips::ipc_send_rpc(dst_proc, Some(rpc_trampoline), f as *mut c_void) };
+
+    if rc < 0 {
+        return Err(Error::Send);
+    }
+    Ok(())
+}
+
+/// Runs `f` in any one of the SIP workers.
+pub fn dispatch_rpc(f: fn(sender: c_int)) -> Result<(), Error> {
+    // SAFETY: See `send_rpc`
+    let rc = unsafe { opensips::ipc_dispatch_rpc(Some(rpc_trampoline), f as *mut c_void) };
+
+    if rc < 0 {
+        return Err(Error::Send);
+    }
+    Ok(())
+}
+
+extern "C" fn rpc_trampoline(sender: c_int, param: *mut c_void) {
+    // SAFETY: `param` was created from a `fn(c_int)` in `send_rpc` or
+    // `dispatch_rpc`.
+    let f = unsafe { mem::transmute::<*mut c_void, fn(c_int)>(param) };
+
+    // See `job_trampoline`
+    if panic::catch_unwind(|| f(sender)).is_err() {
+        crate::log::error("rpc_trampoline", "An IPC function panicked");
+    }
+}
+
+/// The process numbers of all other running processes with an IPC
+/// reactor.
+fn ipc_processes() -> impl Iterator<Item = c_int> {
+    // SAFETY: [OpenSIPS::valid] The process table is allocated in
+    // shared memory before any process is forked.
+    let (pt, count, me) = unsafe {
+        (
+            opensips::pt,
+            opensips::counted_max_processes,
+            opensips::process_no,
+        )
+    };
+
+    (0..count).filter(move |&proc_no| {
+        if proc_no == me {
+            return false;
+        }
+
+        // SAFETY: `proc_no` is within the process table.
+        let flags = unsafe { (*pt.offset(proc_no as isize)).flags };
+
+        flags & opensips::OSS_PROC_IS_RUNNING as c_int != 0
+            && flags & opensips::OSS_PROC_NO_IPC as c_int == 0
+    })
+}
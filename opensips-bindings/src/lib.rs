@@ -1,4 +1,5 @@
 use core::{mem, ptr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::os::raw::{c_char, c_int};
 
 // This is the bindgen-created output...
@@ -17,7 +18,20 @@ mod generated {
 pub use generated::*;
 
 pub mod command;
+pub mod header;
+pub mod ipc;
+pub mod lock;
+pub mod log;
+pub mod map;
+pub mod memory;
+pub mod mi;
 pub mod module_parameter;
+pub mod pv;
+pub mod shared;
+pub mod statistic;
+pub mod timer;
+pub mod tm;
+pub mod usrloc;
 
 // ... and what follows are additions we've made
 
@@ -104,6 +118,16 @@ impl mi_export_t {
     };
 }
 
+unsafe impl Sync for stat_export_t {}
+
+impl stat_export_t {
+    pub const NULL: Self = Self {
+        name: ptr::null_mut(),
+        flags: 0,
+        stat_pointer: ptr::null_mut(),
+    };
+}
+
 impl mi_recipe_t {
     pub const NULL: Self = Self {
         cmd: None,
@@ -185,6 +209,60 @@ pub fn is_worker_proc(rank: c_int) -> bool {
     rank >= 1
 }
 
+/// The index of this process in the process table.
+#[inline]
+pub fn current_process_no() -> c_int {
+    // SAFETY: Each process sets this once, right after forking.
+    unsafe { process_no }
+}
+
+// These are macros in `lock_ops.h`, whose definition depends on the
+// locking method OpenSIPS was built with. `shim.c` wraps them, so
+// they follow the `-D` flags given to the build.
+extern "C" {
+    fn rust_lock_init(lock: *mut gen_lock_t) -> *mut gen_lock_t;
+    fn rust_lock_destroy(lock: *mut gen_lock_t);
+    fn rust_lock_get(lock: *mut gen_lock_t);
+    fn rust_lock_release(lock: *mut gen_lock_t);
+}
+
+/// Returns NULL when the lock can't be initialized.
+///
+/// # Safety
+///
+/// `lock` must point to memory for a lock, usually in shared memory,
+/// that will not move.
+#[inline]
+pub unsafe fn lock_init(lock: *mut gen_lock_t) -> *mut gen_lock_t {
+    rust_lock_init(lock)
+}
+
+/// # Safety
+///
+/// `lock` must point to an initialized lock that nobody is holding.
+#[inline]
+pub unsafe fn lock_destroy(lock: *mut gen_lock_t) {
+    rust_lock_destroy(lock);
+}
+
+/// # Safety
+///
+/// `lock` must point to an initialized lock, usually in shared
+/// memory.
+#[inline]
+pub unsafe fn lock_get(lock: *mut gen_lock_t) {
+    rust_lock_get(lock);
+}
+
+/// # Safety
+///
+/// `lock` must point to an initialized lock that is held by this
+/// process.
+#[inline]
+pub unsafe fn lock_release(lock: *mut gen_lock_t) {
+    rust_lock_release(lock);
+}
+
 impl str_ {
     pub fn try_as_str(&self) -> Result<&str, core::str::Utf8Error> {
         let len = self.len.try_into().expect("TODO: report error");
@@ -233,4 +311,207 @@ impl sip_msg {
             }
         })
     }
+
+    /// Parses the headers selected by `flags` (e.g. [`HDR_CALLID_F`]),
+    /// filling in the shortcut fields like `callid`. Returns `false`
+    /// if the message couldn't be parsed.
+    pub fn parse_headers(&mut self, flags: hdr_flags_t) -> bool {
+        // SAFETY: [OpenSIPS::valid] The message is a valid message
+        // and OpenSIPS only parses what hasn't been parsed yet.
+        unsafe { parse_headers(self, flags, 0) >= 0 }
+    }
+
+    /// The method of a request, or `None` for a reply.
+    pub fn method(&self) -> Option<&str> {
+        if self.first_line.type_ != SIP_REQUEST as c_int {
+            return None;
+        }
+
+        // SAFETY: We checked that this is a request.
+        let request = unsafe { &self.first_line.u.request };
+        request.method.try_as_str().ok()
+    }
+
+    /// The body of the Call-ID header. Only available once
+    /// [`HDR_CALLID_F`] has been parsed.
+    pub fn call_id(&self) -> Option<&str> {
+        Self::shortcut_body(self.callid)
+    }
+
+    /// The body of the CSeq header. Only available once
+    /// [`HDR_CSEQ_F`] has been parsed.
+    pub fn cseq(&self) -> Option<&str> {
+        Self::shortcut_body(self.cseq)
+    }
+
+    /// The tag of the From header, parsing it if needed.
+    pub fn from_tag(&mut self) -> Option<&str> {
+        // SAFETY: [OpenSIPS::valid] The message is a valid message.
+        if unsafe { parse_from_header(self) } < 0 {
+            return None;
+        }
+        Self::tag(self.from)
+    }
+
+    /// The user part of the From URI, parsing it if needed.
+    pub fn from_user(&mut self) -> Option<&str> {
+        // SAFETY: [OpenSIPS::valid] The message is a valid message,
+        // and the parsed URI lives as long as the message.
+        let uri = unsafe { parse_from_uri(self).as_ref()? };
+        let user = uri.user.try_as_str().ok()?;
+        (!user.is_empty()).then_some(user)
+    }
+
+    /// The URI of the From header, parsing it if needed.
+    pub fn from_uri(&mut self) -> Option<&str> {
+        // SAFETY: [OpenSIPS::valid] The message is a valid message.
+        if unsafe { parse_from_header(self) } < 0 {
+            return None;
+        }
+        Self::uri(self.from)
+    }
+
+    /// The URI of the To header, parsing it if needed.
+    pub fn to_uri(&mut self) -> Option<&str> {
+        if !self.parse_headers(HDR_TO_F) {
+            return None;
+        }
+        Self::uri(self.to)
+    }
+
+    /// The body of the Content-Type header, parsing it if needed.
+    pub fn content_type(&mut self) -> Option<&str> {
+        if !self.parse_headers(HDR_CONTENTTYPE_F) {
+            return None;
+        }
+        Self::shortcut_body(self.content_type)
+    }
+
+    /// The display name of the From header, without quotes, parsing
+    /// it if needed.
+    pub fn from_display(&mut self) -> Option<&str> {
+        // SAFETY: [OpenSIPS::valid] The message is a valid message.
+        if unsafe { parse_from_header(self) } < 0 {
+            return None;
+        }
+
+        // SAFETY: [OpenSIPS::valid] The header has been parsed into
+        // a `to_body`.
+        let body = unsafe { self.from.as_ref()?.parsed.cast::<to_body>().as_ref()? };
+        let display = body.display.try_as_str().ok()?.trim();
+        let display = display
+            .strip_prefix('"')
+            .and_then(|d| d.strip_suffix('"'))
+            .unwrap_or(display);
+        (!display.is_empty()).then_some(display)
+    }
+
+    /// The user part of the Request-URI, parsing it if needed.
+    pub fn ruri_user(&mut self) -> Option<&str> {
+        // SAFETY: [OpenSIPS::valid] The message is a valid message.
+        if unsafe { parse_sip_msg_uri(self) } < 0 {
+            return None;
+        }
+        let user = self.parsed_uri.user.try_as_str().ok()?;
+        (!user.is_empty()).then_some(user)
+    }
+
+    /// The body of the User-Agent header, parsing it if needed.
+    pub fn user_agent(&mut self) -> Option<&str> {
+        if !self.parse_headers(HDR_USERAGENT_F) {
+            return None;
+        }
+        Self::shortcut_body(self.user_agent)
+    }
+
+    /// The message body, parsing all headers if needed. Returns
+    /// `None` when there is no body or it isn't valid UTF-8.
+    ///
+    /// This is `get_body` in `parser/msg_parser.h`, a `static inline`
+    /// function which bindgen doesn't generate.
+    pub fn body(&mut self) -> Option<&str> {
+        if !self.parse_headers(HDR_EOH_F) || self.unparsed.is_null() {
+            return None;
+        }
+
+        // SAFETY: [OpenSIPS::valid] `buf` holds `len` bytes and, once
+        // all headers are parsed, `unparsed` points into it at the
+        // empty line before the body.
+        let (message, headers_len) = unsafe {
+            let message =
+                core::slice::from_raw_parts(self.buf.cast::<u8>(), self.len.try_into().ok()?);
+            (message, self.unparsed.offset_from(self.buf))
+        };
+        let rest = message.get(usize::try_from(headers_len).ok()?..)?;
+        let body = rest
+            .strip_prefix(b"\r\n")
+            .or_else(|| rest.strip_prefix(b"\n"))
+            .unwrap_or(rest);
+
+        let body = core::str::from_utf8(body).ok()?;
+        (!body.is_empty()).then_some(body)
+    }
+
+    /// The tag of the To header, parsing it if needed. Requests
+    /// outside of a dialog don't have one.
+    pub fn to_tag(&mut self) -> Option<&str> {
+        if !self.parse_headers(HDR_TO_F) {
+            return None;
+        }
+        Self::tag(self.to)
+    }
+
+    fn tag<'a>(header: *const hdr_field) -> Option<&'a str> {
+        // SAFETY: [OpenSIPS::valid] The header is either NULL or has
+        // been parsed into a `to_body`, which From and To share.
+        let body = unsafe { header.as_ref()?.parsed.cast::<to_body>().as_ref()? };
+        let tag = body.tag_value.try_as_str().ok()?;
+        (!tag.is_empty()).then_some(tag)
+    }
+
+    fn uri<'a>(header: *const hdr_field) -> Option<&'a str> {
+        // SAFETY: [OpenSIPS::valid] The header is either NULL or has
+        // been parsed into a `to_body`, which From and To share.
+        let body = unsafe { header.as_ref()?.parsed.cast::<to_body>().as_ref()? };
+        body.uri.try_as_str().ok()
+    }
+
+    fn shortcut_body<'a>(header: *const hdr_field) -> Option<&'a str> {
+        // SAFETY: [OpenSIPS::valid] The shortcut is either NULL or
+        // points to one of the headers of the message.
+        let header = unsafe { header.as_ref()? };
+        header.body.try_as_str().ok().map(str::trim)
+    }
+
+    /// Where the message was received from.
+    pub fn source(&self) -> Option<SocketAddr> {
+        let ip = self.rcv.src_ip.to_ip_addr()?;
+        Some(SocketAddr::new(ip, self.rcv.src_port))
+    }
 }
+
+impl ip_addr {
+    pub fn to_ip_addr(&self) -> Option<IpAddr> {
+        // SAFETY: Every variant of the union is plain bytes.
+        let addr = unsafe { self.u.addr };
+
+        match self.af {
+            AF_INET => {
+                let octets: [u8; 4] = addr[..4].try_into().ok()?;
+                Some(Ipv4Addr::from(octets).into())
+            }
+            AF_INET6 => Some(Ipv6Addr::from(addr).into()),
+            _ => None,
+        }
+    }
+}
+
+// These are macros built from `hdr_types_t` which bindgen doesn't
+// generate. Define them ourselves.
+pub const HDR_CALLID_F: hdr_flags_t = 1 << hdr_types_t::HDR_CALLID_T;
+pub const HDR_CSEQ_F: hdr_flags_t = 1 << hdr_types_t::HDR_CSEQ_T;
+pub const HDR_FROM_F: hdr_flags_t = 1 << hdr_types_t::HDR_FROM_T;
+pub const HDR_TO_F: hdr_flags_t = 1 << hdr_types_t::HDR_TO_T;
+pub const HDR_CONTENTTYPE_F: hdr_flags_t = 1 << hdr_types_t::HDR_CONTENTTYPE_T;
+pub const HDR_USERAGENT_F: hdr_flags_t = 1 << hdr_types_t::HDR_USERAGENT_T;
+pub const HDR_EOH_F: hdr_flags_t = 1 << hdr_types_t::HDR_EOH_T;
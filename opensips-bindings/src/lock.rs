@@ -0,0 +1,323 @@
+use core::ptr::{self, NonNull};
+use core::{fmt, mem};
+use std::{thread, time::Duration};
+
+use crate::generated as opensips;
+use crate::memory;
+use crate::shared::ShmSafe;
+
+// Matches `LOCK_WAIT` in `rw_locking.h`
+const LOCK_WAIT: Duration = Duration::from_micros(10);
+
+/// A `gen_lock_t` in shared memory, usable across OpenSIPS
+/// processes.
+///
+/// Dropping the lock frees it for *every* process, so it should
+/// usually live inside a [`Shared`][crate::shared::Shared] value.
+pub struct Lock(NonNull<opensips::gen_lock_t>);
+
+unsafe impl Send for Lock {}
+unsafe impl Sync for Lock {}
+unsafe impl ShmSafe for Lock {}
+
+impl Lock {
+    /// Returns `None` when shared memory is exhausted.
+    pub fn new() -> Option<Self> {
+        let lock = memory::shm_malloc(mem::size_of::<opensips::gen_lock_t>());
+        let lock = NonNull::new(lock)?.cast();
+
+        // SAFETY: We just allocated the lock.
+        unsafe {
+            if crate::lock_init(lock.as_ptr()).is_null() {
+                memory::shm_free(lock.as_ptr().cast());
+                return None;
+            }
+        }
+
+        Some(Self(lock))
+    }
+
+    pub fn lock(&self) -> LockGuard<'_> {
+        // SAFETY: The lock was initialized in `new`.
+        unsafe { crate::lock_get(self.as_ptr()) };
+        LockGuard(self)
+    }
+
+    /// For passing the lock to C APIs.
+    pub fn as_ptr(&self) -> *mut opensips::gen_lock_t {
+        self.0.as_ptr()
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        // SAFETY: The lock came from `shm_malloc` and nobody can be
+        // holding it as we have `&mut self`.
+        unsafe {
+            crate::lock_destroy(self.as_ptr());
+            memory::shm_free(self.as_ptr().cast());
+        }
+    }
+}
+
+impl fmt::Debug for Lock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Lock").field(&self.0).finish()
+    }
+}
+
+#[must_use = "the lock is released when the guard is dropped"]
+pub struct LockGuard<'a>(&'a Lock);
+
+impl Drop for LockGuard<'_> {
+    fn drop(&mut self) {
+        // SAFETY: We hold the lock.
+        unsafe { crate::lock_release(self.0.as_ptr()) };
+    }
+}
+
+/// A `gen_lock_set_t` in shared memory; a fixed number of locks,
+/// typically indexed by a hash to protect the buckets of a table.
+pub struct LockSet(NonNull<opensips::gen_lock_set_t>);
+
+unsafe impl Send for LockSet {}
+unsafe impl Sync for LockSet {}
+unsafe impl ShmSafe for LockSet {}
+
+impl LockSet {
+    /// Returns `None` when shared memory is exhausted.
+    pub fn new(n: usize) -> Option<Self> {
+        assert!(n > 0, "A lock set needs at least one lock");
+
+        // Mirrors `lock_set_alloc` / `lock_set_init`: the locks
+        // directly follow the header in the same allocation.
+        let header_size = mem::size_of::<opensips::gen_lock_set_t>();
+        let size = header_size + n * mem::size_of::<opensips::gen_lock_t>();
+        let set = NonNull::new(memory::shm_malloc(size))?.cast::<opensips::gen_lock_set_t>();
+
+        // SAFETY: We just allocated enough room for the header and
+        // `n` locks.
+        unsafe {
+            let locks = set.as_ptr().cast::<u8>().add(header_size).cast();
+            set.as_ptr().write(opensips::gen_lock_set_t {
+                size: n as _,
+                locks,
+            });
+
+            for i in 0..n {
+                if crate::lock_init(locks.add(i)).is_null() {
+                    memory::shm_free(set.as_ptr().cast());
+                    return None;
+                }
+            }
+        }
+
+        Some(Self(set))
+    }
+
+    pub fn len(&self) -> usize {
+        // SAFETY: The header was initialized in `new`.
+        unsafe { self.0.as_ref().size as usize }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Panics if `i` is out of bounds.
+    pub fn lock(&self, i: usize) -> LockSetGuard<'_> {
+        let lock = self.lock_ptr(i);
+
+        // SAFETY: The lock was initialized in `new`.
+        unsafe { crate::lock_get(lock) };
+        LockSetGuard { set: self, i }
+    }
+
+    /// Locks the entry that `hash` falls into.
+    pub fn lock_for(&self, hash: u64) -> LockSetGuard<'_> {
+        self.lock((hash % self.len() as u64) as usize)
+    }
+
+    /// For passing the lock set to C APIs.
+    pub fn as_ptr(&self) -> *mut opensips::gen_lock_set_t {
+        self.0.as_ptr()
+    }
+
+    fn lock_ptr(&self, i: usize) -> *mut opensips::gen_lock_t {
+        assert!(i < self.len(), "Lock index out of bounds");
+
+        // SAFETY: We checked the index against the size.
+        unsafe { self.0.as_ref().locks.add(i) }
+    }
+}
+
+impl Drop for LockSet {
+    fn drop(&mut self) {
+        // SAFETY: The set came from `shm_malloc` and nobody can be
+        // holding any of the locks as we have `&mut self`.
+        unsafe {
+            for i in 0..self.len() {
+                crate::lock_destroy(self.lock_ptr(i));
+            }
+            memory::shm_free(self.as_ptr().cast());
+        }
+    }
+}
+
+impl fmt::Debug for LockSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LockSet")
+            .field("len", &self.len())
+            .finish_non_exhaustive()
+    }
+}
+
+#[must_use = "the lock is released when the guard is dropped"]
+pub struct LockSetGuard<'a> {
+    set: &'a LockSet,
+    i: usize,
+}
+
+impl LockSetGuard<'_> {
+    pub fn index(&self) -> usize {
+        self.i
+    }
+}
+
+impl Drop for LockSetGuard<'_> {
+    fn drop(&mut self) {
+        // SAFETY: We hold the lock.
+        unsafe { crate::lock_release(self.set.lock_ptr(self.i)) };
+    }
+}
+
+/// A `rw_lock_t` in shared memory; any number of readers or a
+/// single writer.
+///
+/// Like the C version, waiting is done by polling, so hold it
+/// briefly.
+pub struct RwLock(NonNull<opensips::rw_lock_t>);
+
+unsafe impl Send for RwLock {}
+unsafe impl Sync for RwLock {}
+unsafe impl ShmSafe for RwLock {}
+
+// These are macros / `static inline` functions in `rw_locking.h`
+// which bindgen doesn't generate. Define them ourselves.
+impl RwLock {
+    /// Returns `None` when shared memory is exhausted.
+    pub fn new() -> Option<Self> {
+        let lock = Lock::new()?;
+        let rw = memory::shm_malloc(mem::size_of::<opensips::rw_lock_t>());
+        let rw = NonNull::new(rw)?.cast::<opensips::rw_lock_t>();
+
+        // SAFETY: We just allocated the memory.
+        unsafe {
+            rw.as_ptr().write(opensips::rw_lock_t {
+                lock: lock.as_ptr(),
+                w_flag: 0,
+                r_count: 0,
+            });
+        }
+
+        // Now owned by the `rw_lock_t`
+        mem::forget(lock);
+
+        Some(Self(rw))
+    }
+
+    pub fn read(&self) -> ReadGuard<'_> {
+        let rw = self.as_ptr();
+
+        // SAFETY: The fields are only modified while holding the
+        // inner lock.
+        unsafe {
+            loop {
+                crate::lock_get((*rw).lock);
+                if (*rw).w_flag == 0 {
+                    break;
+                }
+                crate::lock_release((*rw).lock);
+                thread::sleep(LOCK_WAIT);
+            }
+            (*rw).r_count += 1;
+            crate::lock_release((*rw).lock);
+        }
+
+        ReadGuard(self)
+    }
+
+    pub fn write(&self) -> WriteGuard<'_> {
+        let rw = self.as_ptr();
+
+        // SAFETY: The fields are only modified while holding the
+        // inner lock. Readers only decrement `r_count`, so polling it
+        // is fine.
+        unsafe {
+            loop {
+                crate::lock_get((*rw).lock);
+                if (*rw).w_flag == 0 {
+                    break;
+                }
+                crate::lock_release((*rw).lock);
+                thread::sleep(LOCK_WAIT);
+            }
+            (*rw).w_flag = 1;
+            crate::lock_release((*rw).lock);
+
+            while ptr::read_volatile(ptr::addr_of!((*rw).r_count)) != 0 {
+                thread::sleep(LOCK_WAIT);
+            }
+        }
+
+        WriteGuard(self)
+    }
+
+    /// For passing the lock to C APIs.
+    pub fn as_ptr(&self) -> *mut opensips::rw_lock_t {
+        self.0.as_ptr()
+    }
+}
+
+impl Drop for RwLock {
+    fn drop(&mut self) {
+        // SAFETY: Both allocations came from `shm_malloc` and nobody
+        // can be holding the lock as we have `&mut self`.
+        unsafe {
+            drop(Lock(NonNull::new_unchecked((*self.as_ptr()).lock)));
+            memory::shm_free(self.as_ptr().cast());
+        }
+    }
+}
+
+impl fmt::Debug for RwLock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("RwLock").field(&self.0).finish()
+    }
+}
+
+#[must_use = "the lock is released when the guard is dropped"]
+pub struct ReadGuard<'a>(&'a RwLock);
+
+impl Drop for ReadGuard<'_> {
+    fn drop(&mut self) {
+        let rw = self.0.as_ptr();
+
+        // SAFETY: We are a reader.
+        unsafe {
+            crate::lock_get((*rw).lock);
+            (*rw).r_count -= 1;
+            crate::lock_release((*rw).lock);
+        }
+    }
+}
+
+#[must_use = "the lock is released when the guard is dropped"]
+pub struct WriteGuard<'a>(&'a RwLock);
+
+impl Drop for WriteGuard<'_> {
+    fn drop(&mut self) {
+        // SAFETY: We are the only writer and there are no readers.
+        unsafe { ptr::write_volatile(ptr::addr_of_mut!((*self.0.as_ptr()).w_flag), 0) };
+    }
+}
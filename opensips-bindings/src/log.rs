@@ -0,0 +1,77 @@
+use core::ffi::CStr;
+use std::ffi::CString;
+use std::os::raw::{c_char, c_int};
+
+use crate::generated as opensips;
+
+/// The OpenSIPS log levels, from most to least severe.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(i32)]
+pub enum Level {
+    Alert = opensips::L_ALERT,
+    Critical = opensips::L_CRIT,
+    Error = opensips::L_ERR,
+    Warning = opensips::L_WARN,
+    Notice = opensips::L_NOTICE,
+    Info = opensips::L_INFO,
+    Debug = opensips::L_DBG,
+}
+
+// `LM_GEN1` in `shim.c`, as the prefix formats it passes to `dprint`
+// differ between OpenSIPS versions.
+extern "C" {
+    fn rust_log(
+        level: c_int,
+        module: *const c_char,
+        func: *const c_char,
+        len: c_int,
+        message: *const c_char,
+    );
+}
+
+/// If a message at `level` would be logged by this process. This
+/// follows the current level of the process, which may be changed at
+/// runtime (e.g. by the `log_level` MI command).
+///
+/// This is `is_printable` in `dprint.h`.
+#[inline]
+pub fn is_printable(level: Level) -> bool {
+    // SAFETY: [OpenSIPS::valid] `log_level` points into the process
+    // table, which is set up before any module is loaded.
+    unsafe {
+        let current = opensips::log_level;
+        !current.is_null() && *current >= level as c_int
+    }
+}
+
+/// Logs `message` like `LM_GEN1` would, honoring the `log_stderror`
+/// and `log_facility` settings. The caller should check
+/// [`is_printable`] first.
+pub fn print(level: Level, module: &CStr, func: &CStr, message: &str) {
+    let len = message.len().try_into().unwrap_or(c_int::MAX);
+
+    // SAFETY: [OpenSIPS::valid] The message is only read up to its
+    // length, and the names are NUL-terminated.
+    unsafe {
+        rust_log(
+            level as c_int,
+            module.as_ptr(),
+            func.as_ptr(),
+            len,
+            message.as_ptr().cast(),
+        );
+    }
+}
+
+/// Logs an error of the bindings themselves, e.g. in a callback which
+/// has nobody to return it to.
+pub(crate) fn error(func: &str, message: &str) {
+    if !is_printable(Level::Error) {
+        return;
+    }
+
+    // SAFETY: `cstr_lit!` adds the NUL byte.
+    let module = unsafe { CStr::from_ptr(crate::cstr_lit!("opensips-bindings").cast()) };
+    let func = CString::new(func).unwrap_or_default();
+    print(Level::Error, module, &func, message);
+}
@@ -0,0 +1,207 @@
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::hash::{Hash, Hasher};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::hash_map::DefaultHasher;
+use std::time::SystemTime;
+
+use crate::lock::LockSet;
+use crate::memory::{ShmBox, ShmString};
+use crate::shared::ShmSafe;
+
+/// The number of buckets of a [`Map`] unless it says otherwise,
+/// plenty for a few thousand entries.
+pub const BUCKETS: usize = 64;
+
+/// A hash map with string keys in shared memory, usable across
+/// OpenSIPS processes. Each of the `N` buckets has its own lock.
+///
+/// Like any other shared value, it must be created during module
+/// initialization and is usually placed in a
+/// [`Shared`][crate::shared::Shared].
+pub struct Map<V, const N: usize = BUCKETS> {
+    locks: LockSet,
+    buckets: [UnsafeCell<Link<V>>; N],
+    len: AtomicUsize,
+}
+
+type Link<V> = Option<ShmBox<Entry<V>>>;
+
+struct Entry<V> {
+    hash: u64,
+    key: ShmString,
+    // Only `None` while the caller of `update` decides what to do.
+    value: Option<V>,
+    next: Link<V>,
+}
+
+unsafe impl<V: Send, const N: usize> Send for Map<V, N> {}
+unsafe impl<V: Send, const N: usize> Sync for Map<V, N> {}
+unsafe impl<V: ShmSafe + Send, const N: usize> ShmSafe for Map<V, N> {}
+
+#[derive(Debug)]
+pub struct OutOfMemory;
+
+impl fmt::Display for OutOfMemory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("out of shared memory for the map entry")
+    }
+}
+
+impl std::error::Error for OutOfMemory {}
+
+impl<V: ShmSafe, const N: usize> Map<V, N> {
+    /// This must be called during module initialization. Returns
+    /// `None` when shared memory is exhausted.
+    pub fn new() -> Option<Self> {
+        Some(Self {
+            locks: LockSet::new(N)?,
+            buckets: core::array::from_fn(|_| UnsafeCell::new(None)),
+            len: AtomicUsize::new(0),
+        })
+    }
+
+    /// The number of entries. Other processes may change it at any
+    /// time.
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Runs `f` with the value for `key`, or `None` when there is
+    /// none, while holding the lock of its bucket. Setting the value
+    /// inserts or replaces it; setting `None` removes it.
+    ///
+    /// `f` must not use the map, as it may need the same lock.
+    pub fn update<R>(
+        &self,
+        key: &str,
+        f: impl FnOnce(&mut Option<V>) -> R,
+    ) -> Result<R, OutOfMemory> {
+        let hash = hash(key);
+        let i = (hash % N as u64) as usize;
+        let _guard = self.locks.lock(i);
+
+        // SAFETY: We hold the lock of this bucket.
+        let bucket = unsafe { &mut *self.buckets[i].get() };
+
+        let mut slot = bucket;
+        while slot
+            .as_ref()
+            .is_some_and(|e| e.hash != hash || e.key.as_str() != key)
+        {
+            slot = &mut slot.as_mut().expect("checked by the loop").next;
+        }
+
+        let Some(entry) = slot.as_mut() else {
+            let mut value = None;
+            let result = f(&mut value);
+
+            if let Some(value) = value {
+                let key = ShmString::new(key).ok_or(OutOfMemory)?;
+                let entry = Entry {
+                    hash,
+                    key,
+                    value: Some(value),
+                    next: None,
+                };
+                *slot = Some(ShmBox::new(entry).ok_or(OutOfMemory)?);
+                self.len.fetch_add(1, Ordering::Relaxed);
+            }
+
+            return Ok(result);
+        };
+
+        let result = f(&mut entry.value);
+
+        if entry.value.is_none() {
+            let next = entry.next.take();
+            *slot = next;
+            self.len.fetch_sub(1, Ordering::Relaxed);
+        }
+
+        Ok(result)
+    }
+
+    /// Calls `f` with the value for `key`, if any.
+    pub fn get<R>(&self, key: &str, f: impl FnOnce(&V) -> R) -> Option<R> {
+        self.update(key, |value| value.as_ref().map(f))
+            .ok()
+            .flatten()
+    }
+
+    /// Removes the value for `key`, returning if there was one.
+    pub fn remove(&self, key: &str) -> bool {
+        self.update(key, |value| value.take().is_some())
+            .unwrap_or(false)
+    }
+
+    /// Keeps only the entries for which `f` returns `true`. The
+    /// buckets are locked one at a time, so this is not a snapshot.
+    pub fn retain(&self, mut f: impl FnMut(&str, &mut V) -> bool) {
+        for i in 0..N {
+            self.retain_in(i, &mut f);
+        }
+    }
+
+    /// Like [`retain`][Self::retain], but only for the entries in the
+    /// bucket of `key`, whether or not there is one for `key` itself.
+    /// Only that bucket is locked, so this is cheap enough to do on
+    /// every request.
+    pub fn retain_bucket(&self, key: &str, mut f: impl FnMut(&str, &mut V) -> bool) {
+        self.retain_in((hash(key) % N as u64) as usize, &mut f);
+    }
+
+    fn retain_in(&self, i: usize, f: &mut impl FnMut(&str, &mut V) -> bool) {
+        let _guard = self.locks.lock(i);
+
+        // SAFETY: We hold the lock of this bucket.
+        let mut slot = unsafe { &mut *self.buckets[i].get() };
+
+        while let Some(entry) = slot.as_mut() {
+            let Entry { key, value, .. } = &mut **entry;
+            let value = value.as_mut().expect("only unset during update");
+
+            if f(key.as_str(), value) {
+                slot = &mut slot.as_mut().expect("checked by the loop").next;
+            } else {
+                let next = entry.next.take();
+                *slot = next;
+                self.len.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Removes every entry.
+    pub fn clear(&self) {
+        self.retain(|_, _| false);
+    }
+}
+
+impl<V, const N: usize> fmt::Debug for Map<V, N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Map")
+            .field("len", &self.len.load(Ordering::Relaxed))
+            .finish_non_exhaustive()
+    }
+}
+
+/// Seconds since the Unix epoch. Times kept in a [`Map`] should be
+/// these, as `Instant`s don't mean the same thing in every process.
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+// `DefaultHasher::new` uses fixed keys, so every process agrees on
+// the bucket of a key.
+fn hash(key: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
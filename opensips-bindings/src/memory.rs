@@ -0,0 +1,427 @@
+use core::ffi::CStr;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::ptr::{self, NonNull};
+use core::{fmt, mem, slice, str};
+use std::os::raw::{c_char, c_ulong, c_void};
+
+use crate::generated as opensips;
+
+// OpenSIPS is built with `DBG_MALLOC`, so every allocation records
+// where it came from. We don't have a meaningful C location, so
+// everything from Rust is attributed to this file.
+const DBG_FILE: *const c_char = crate::cstr_lit!("rust");
+const DBG_FUNC: *const c_char = crate::cstr_lit!("memory");
+const DBG_LINE: u32 = 0;
+
+// Both the F_MALLOC and Q_MALLOC allocators round to at least this
+// many bytes.
+const MIN_ALIGN: usize = mem::size_of::<c_ulong>();
+
+// These are macros / `static inline` functions in `mem/mem.h` and
+// `mem/shm_mem.h` which bindgen doesn't generate. Define them
+// ourselves.
+
+/// Allocates `size` bytes of private (per-process) memory. Returns
+/// NULL when OpenSIPS is out of pkg memory.
+#[inline]
+pub fn pkg_malloc(size: usize) -> *mut c_void {
+    // SAFETY: [OpenSIPS::valid] The allocator function and memory
+    // block are set up before any module is loaded.
+    unsafe {
+        let f = opensips::gen_pkg_malloc.expect("pkg allocator missing");
+        f(
+            opensips::mem_block,
+            size as c_ulong,
+            DBG_FILE,
+            DBG_FUNC,
+            DBG_LINE,
+        )
+    }
+}
+
+/// # Safety
+///
+/// `p` must have come from [`pkg_malloc`] or [`pkg_realloc`] in this
+/// process and not already be freed.
+#[inline]
+pub unsafe fn pkg_realloc(p: *mut c_void, size: usize) -> *mut c_void {
+    let f = opensips::gen_pkg_realloc.expect("pkg allocator missing");
+    f(
+        opensips::mem_block,
+        p,
+        size as c_ulong,
+        DBG_FILE,
+        DBG_FUNC,
+        DBG_LINE,
+    )
+}
+
+/// # Safety
+///
+/// `p` must have come from [`pkg_malloc`] or [`pkg_realloc`] in this
+/// process and not already be freed.
+#[inline]
+pub unsafe fn pkg_free(p: *mut c_void) {
+    let f = opensips::gen_pkg_free.expect("pkg allocator missing");
+    f(opensips::mem_block, p, DBG_FILE, DBG_FUNC, DBG_LINE)
+}
+
+// The HP_MALLOC allocator takes its own (per-bucket) locks and
+// leaves `mem_lock` as NULL.
+unsafe fn shm_lock() {
+    if !opensips::mem_lock.is_null() {
+        crate::lock_get(opensips::mem_lock);
+    }
+}
+
+unsafe fn shm_unlock() {
+    if !opensips::mem_lock.is_null() {
+        crate::lock_release(opensips::mem_lock);
+    }
+}
+
+/// Allocates `size` bytes of memory shared between all OpenSIPS
+/// processes. Returns NULL when OpenSIPS is out of shm memory.
+#[inline]
+pub fn shm_malloc(size: usize) -> *mut c_void {
+    // SAFETY: [OpenSIPS::valid] Shared memory is set up before any
+    // module is loaded.
+    unsafe {
+        let f = opensips::gen_shm_malloc.expect("shm allocator missing");
+        shm_lock();
+        let p = f(
+            opensips::shm_block,
+            size as c_ulong,
+            DBG_FILE,
+            DBG_FUNC,
+            DBG_LINE,
+        );
+        shm_unlock();
+        p
+    }
+}
+
+/// # Safety
+///
+/// `p` must have come from [`shm_malloc`] or [`shm_realloc`] and not
+/// already be freed (by any process).
+#[inline]
+pub unsafe fn shm_realloc(p: *mut c_void, size: usize) -> *mut c_void {
+    let f = opensips::gen_shm_realloc.expect("shm allocator missing");
+    shm_lock();
+    let p = f(
+        opensips::shm_block,
+        p,
+        size as c_ulong,
+        DBG_FILE,
+        DBG_FUNC,
+        DBG_LINE,
+    );
+    shm_unlock();
+    p
+}
+
+/// # Safety
+///
+/// `p` must have come from [`shm_malloc`] or [`shm_realloc`] and not
+/// already be freed (by any process).
+#[inline]
+pub unsafe fn shm_free(p: *mut c_void) {
+    let f = opensips::gen_shm_free.expect("shm allocator missing");
+    shm_lock();
+    f(opensips::shm_block, p, DBG_FILE, DBG_FUNC, DBG_LINE);
+    shm_unlock();
+}
+
+/// One of the OpenSIPS memory pools.
+pub trait Allocator {
+    fn malloc(size: usize) -> *mut c_void;
+
+    /// # Safety
+    ///
+    /// `p` must have come from `malloc` of the same allocator.
+    unsafe fn free(p: *mut c_void);
+}
+
+/// Private memory, only valid within the process that allocated it
+/// (and any processes forked from it afterwards).
+#[derive(Debug)]
+pub enum Pkg {}
+
+/// Memory shared between all OpenSIPS processes.
+#[derive(Debug)]
+pub enum Shm {}
+
+impl Allocator for Pkg {
+    fn malloc(size: usize) -> *mut c_void {
+        pkg_malloc(size)
+    }
+
+    unsafe fn free(p: *mut c_void) {
+        pkg_free(p)
+    }
+}
+
+impl Allocator for Shm {
+    fn malloc(size: usize) -> *mut c_void {
+        shm_malloc(size)
+    }
+
+    unsafe fn free(p: *mut c_void) {
+        shm_free(p)
+    }
+}
+
+/// Like [`Box`], but the value lives in one of the OpenSIPS memory
+/// pools.
+pub struct AllocBox<T, A: Allocator> {
+    ptr: NonNull<T>,
+    _allocator: PhantomData<(T, A)>,
+}
+
+pub type PkgBox<T> = AllocBox<T, Pkg>;
+pub type ShmBox<T> = AllocBox<T, Shm>;
+
+// The shm allocator is locked, so the box may be freed from any
+// thread or process.
+unsafe impl<T: Send> Send for AllocBox<T, Shm> {}
+unsafe impl<T: Sync> Sync for AllocBox<T, Shm> {}
+
+impl<T, A: Allocator> AllocBox<T, A> {
+    /// Returns `None` when the pool is exhausted.
+    pub fn new(value: T) -> Option<Self> {
+        assert!(
+            mem::align_of::<T>() <= MIN_ALIGN,
+            "OpenSIPS allocators do not support this alignment",
+        );
+
+        let ptr = NonNull::new(A::malloc(mem::size_of::<T>().max(1)))?.cast::<T>();
+
+        // SAFETY: We just allocated enough suitably-aligned memory.
+        unsafe { ptr.as_ptr().write(value) };
+
+        Some(Self {
+            ptr,
+            _allocator: PhantomData,
+        })
+    }
+
+    pub fn as_ptr(this: &Self) -> *mut T {
+        this.ptr.as_ptr()
+    }
+
+    /// Gives up ownership; the memory will never be freed unless it
+    /// is passed to [`AllocBox::from_raw`].
+    pub fn into_raw(this: Self) -> *mut T {
+        let ptr = this.ptr.as_ptr();
+        mem::forget(this);
+        ptr
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must have come from [`AllocBox::into_raw`] with the same
+    /// allocator.
+    pub unsafe fn from_raw(ptr: *mut T) -> Self {
+        Self {
+            ptr: NonNull::new_unchecked(ptr),
+            _allocator: PhantomData,
+        }
+    }
+}
+
+impl<T, A: Allocator> Deref for AllocBox<T, A> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: We own a valid, initialized value.
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T, A: Allocator> DerefMut for AllocBox<T, A> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: We own a valid, initialized value.
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T, A: Allocator> Drop for AllocBox<T, A> {
+    fn drop(&mut self) {
+        // SAFETY: We own a valid, initialized value that came from
+        // this allocator.
+        unsafe {
+            ptr::drop_in_place(self.ptr.as_ptr());
+            A::free(self.ptr.as_ptr().cast());
+        }
+    }
+}
+
+impl<T: fmt::Debug, A: Allocator> fmt::Debug for AllocBox<T, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+/// An immutable UTF-8 string living in one of the OpenSIPS memory
+/// pools. The data is followed by a NUL byte so it can also be
+/// handed to C as-is.
+pub struct AllocString<A: Allocator> {
+    ptr: NonNull<u8>,
+    len: usize,
+    _allocator: PhantomData<A>,
+}
+
+pub type PkgString = AllocString<Pkg>;
+pub type ShmString = AllocString<Shm>;
+
+unsafe impl Send for AllocString<Shm> {}
+unsafe impl Sync for AllocString<Shm> {}
+
+impl<A: Allocator> AllocString<A> {
+    /// Returns `None` when the pool is exhausted.
+    pub fn new(s: &str) -> Option<Self> {
+        let len = s.len();
+        let ptr = NonNull::new(A::malloc(len + 1))?.cast::<u8>();
+
+        // SAFETY: We just allocated `len + 1` bytes.
+        unsafe {
+            ptr::copy_nonoverlapping(s.as_ptr(), ptr.as_ptr(), len);
+            ptr.as_ptr().add(len).write(0);
+        }
+
+        Some(Self {
+            ptr,
+            len,
+            _allocator: PhantomData,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        // SAFETY: We copied the bytes from a `&str` and never modify
+        // them.
+        unsafe { str::from_utf8_unchecked(slice::from_raw_parts(self.ptr.as_ptr(), self.len)) }
+    }
+
+    pub fn as_c_str(&self) -> *const c_char {
+        self.ptr.as_ptr()
+    }
+
+    /// The returned value borrows from `self`; OpenSIPS must not
+    /// modify or free it.
+    pub fn as_opensips_str(&self) -> opensips::str_ {
+        opensips::str_ {
+            s: self.ptr.as_ptr(),
+            len: self.len.try_into().unwrap_or(0),
+        }
+    }
+
+    /// Gives up ownership of the NUL-terminated data; the memory will
+    /// never be freed unless it is passed to
+    /// [`AllocString::from_raw`].
+    pub fn into_raw(self) -> *mut c_char {
+        let ptr = self.ptr.as_ptr();
+        mem::forget(self);
+        ptr
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must have come from [`AllocString::into_raw`] with the
+    /// same allocator, and the string must not contain NUL bytes.
+    pub unsafe fn from_raw(ptr: *mut c_char) -> Self {
+        Self {
+            ptr: NonNull::new_unchecked(ptr),
+            len: CStr::from_ptr(ptr).to_bytes().len(),
+            _allocator: PhantomData,
+        }
+    }
+}
+
+impl<A: Allocator> Deref for AllocString<A> {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl<A: Allocator> Drop for AllocString<A> {
+    fn drop(&mut self) {
+        // SAFETY: We own the memory and it came from this allocator.
+        unsafe { A::free(self.ptr.as_ptr().cast()) }
+    }
+}
+
+impl<A: Allocator> fmt::Debug for AllocString<A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.as_str().fmt(f)
+    }
+}
+
+impl<A: Allocator> fmt::Display for AllocString<A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.as_str().fmt(f)
+    }
+}
+
+/// A [`GlobalAlloc`] using pkg memory, so that Rust allocations show
+/// up in the OpenSIPS memory statistics.
+///
+/// The pkg allocator is not thread-safe. This must only be used when
+/// Rust code never allocates outside of the OpenSIPS process' own
+/// thread, including in threads started by dependencies.
+#[cfg(feature = "pkg-allocator")]
+#[derive(Debug)]
+pub struct PkgAllocator;
+
+#[cfg(feature = "pkg-allocator")]
+unsafe impl core::alloc::GlobalAlloc for PkgAllocator {
+    unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
+        if layout.align() <= MIN_ALIGN {
+            return pkg_malloc(layout.size()).cast();
+        }
+
+        // Over-allocate and stash the original pointer just before the
+        // aligned one. The alignment is larger than `MIN_ALIGN`, so
+        // there's always room for it.
+        let raw = pkg_malloc(layout.size() + layout.align()).cast::<u8>();
+        if raw.is_null() {
+            return raw;
+        }
+
+        let offset = layout.align() - (raw as usize % layout.align());
+        let aligned = raw.add(offset);
+        aligned.cast::<*mut u8>().sub(1).write(raw);
+        aligned
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
+        if layout.align() <= MIN_ALIGN {
+            pkg_free(ptr.cast());
+        } else {
+            let raw = ptr.cast::<*mut u8>().sub(1).read();
+            pkg_free(raw.cast());
+        }
+    }
+
+    unsafe fn realloc(
+        &self,
+        ptr: *mut u8,
+        layout: core::alloc::Layout,
+        new_size: usize,
+    ) -> *mut u8 {
+        if layout.align() <= MIN_ALIGN {
+            return pkg_realloc(ptr.cast(), new_size).cast();
+        }
+
+        let new_layout = core::alloc::Layout::from_size_align_unchecked(new_size, layout.align());
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
+}
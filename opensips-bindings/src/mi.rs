@@ -0,0 +1,143 @@
+use core::marker::PhantomData;
+use core::{ptr, slice, str};
+use std::os::raw::{c_char, c_int};
+
+use crate::generated as opensips;
+
+/// Reads the string parameter `name` of an MI command. Returns `None`
+/// when it is missing, not a string, or not UTF-8.
+///
+/// # Safety
+///
+/// `params` must be the parameters OpenSIPS passed to the MI command.
+/// The returned string borrows from them. `name` must be a C string,
+/// e.g. from [`cstr_lit!`][crate::cstr_lit].
+pub unsafe fn string_param<'a>(
+    params: *const opensips::mi_params_t,
+    name: *const c_char,
+) -> Option<&'a str> {
+    let mut value: *mut c_char = core::ptr::null_mut();
+    let mut len: c_int = 0;
+
+    // OpenSIPS doesn't modify the name, it just isn't `const`.
+    let rc = opensips::get_mi_string_param(params, name.cast_mut(), &mut value, &mut len);
+
+    if rc < 0 || value.is_null() {
+        return None;
+    }
+
+    let bytes = slice::from_raw_parts(value.cast::<u8>(), len.try_into().ok()?);
+    str::from_utf8(bytes).ok()
+}
+
+/// A successful response with a string result.
+pub fn result_string(value: &str) -> *mut opensips::mi_response_t {
+    let len = value.len().try_into().unwrap_or(c_int::MAX);
+
+    // SAFETY: The value is copied into the response.
+    unsafe { opensips::init_mi_result_string(value.as_ptr(), len) }
+}
+
+/// A successful response with an object result, which `fill` adds
+/// to. When it returns `None`, e.g. because memory is exhausted, an
+/// error is returned instead.
+pub fn result_object(
+    fill: impl for<'a> FnOnce(&Item<'a>) -> Option<()>,
+) -> *mut opensips::mi_response_t {
+    let mut object = ptr::null_mut();
+
+    // SAFETY: OpenSIPS sets `object` when it returns a response.
+    let response = unsafe { opensips::init_mi_result_object(&mut object) };
+    if response.is_null() {
+        return ptr::null_mut();
+    }
+
+    let item = Item {
+        item: object,
+        _response: PhantomData,
+    };
+    if fill(&item).is_none() {
+        // SAFETY: The response was created above and isn't used
+        // anywhere else.
+        unsafe { opensips::free_mi_response(response) };
+        return error(opensips::JSONRPC_SERVER_ERR_CODE, "Out of memory");
+    }
+
+    response
+}
+
+/// An object or array inside of a response. Names are ignored when
+/// adding to an array.
+pub struct Item<'a> {
+    item: *mut opensips::mi_item_t,
+    _response: PhantomData<&'a opensips::mi_response_t>,
+}
+
+impl<'a> Item<'a> {
+    pub fn add_string(&self, name: &str, value: &str) -> Option<()> {
+        let (name, name_len) = Self::name(name);
+        let len = value.len().try_into().unwrap_or(c_int::MAX);
+
+        // SAFETY: The item belongs to a live response, and the name
+        // and value are copied.
+        let rc = unsafe {
+            opensips::add_mi_string(self.item, name, name_len, value.as_ptr().cast(), len)
+        };
+        (rc >= 0).then_some(())
+    }
+
+    pub fn add_number(&self, name: &str, value: f64) -> Option<()> {
+        let (name, name_len) = Self::name(name);
+
+        // SAFETY: The item belongs to a live response, and the name
+        // is copied.
+        let rc = unsafe { opensips::add_mi_number(self.item, name, name_len, value) };
+        (rc >= 0).then_some(())
+    }
+
+    pub fn add_array(&self, name: &str) -> Option<Item<'a>> {
+        let (name, name_len) = Self::name(name);
+
+        // SAFETY: The item belongs to a live response, and the name
+        // is copied.
+        let item = unsafe { opensips::add_mi_array(self.item, name, name_len) };
+        Self::child(item)
+    }
+
+    pub fn add_object(&self, name: &str) -> Option<Item<'a>> {
+        let (name, name_len) = Self::name(name);
+
+        // SAFETY: The item belongs to a live response, and the name
+        // is copied.
+        let item = unsafe { opensips::add_mi_object(self.item, name, name_len) };
+        Self::child(item)
+    }
+
+    // OpenSIPS doesn't modify the name, it just isn't `const`.
+    fn name(name: &str) -> (*mut c_char, c_int) {
+        let len = name.len().try_into().unwrap_or(c_int::MAX);
+        (name.as_ptr().cast_mut().cast(), len)
+    }
+
+    fn child(item: *mut opensips::mi_item_t) -> Option<Item<'a>> {
+        (!item.is_null()).then_some(Item {
+            item,
+            _response: PhantomData,
+        })
+    }
+}
+
+/// An error response. Use the `JSONRPC_*_CODE` constants for `code`.
+pub fn error(code: c_int, message: &str) -> *mut opensips::mi_response_t {
+    let len = message.len().try_into().unwrap_or(c_int::MAX);
+
+    // SAFETY: The message is copied into the response.
+    unsafe { opensips::init_mi_error_extra(code, message.as_ptr(), len, core::ptr::null(), 0) }
+}
+
+/// The error response for missing or invalid parameters.
+///
+/// This is `init_mi_param_error` in `mi/item.h`.
+pub fn param_error() -> *mut opensips::mi_response_t {
+    error(opensips::JSONRPC_INVAL_PARAMS_CODE, "Invalid params")
+}
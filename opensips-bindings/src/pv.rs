@@ -0,0 +1,133 @@
+use core::{fmt, mem, ops::Deref};
+
+use crate::generated as opensips;
+use crate::StrExt;
+
+/// A parsed script variable, such as `$var(name)`, `$avp(name)` or
+/// `$ru`.
+pub struct Spec(Box<Variable>);
+
+// SAFETY: The specification is parsed during module initialization
+// and only read afterwards. This *requires* that the plugin is only
+// used in a single-threaded fashion.
+unsafe impl Send for Spec {}
+unsafe impl Sync for Spec {}
+
+impl fmt::Debug for Spec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Spec").finish_non_exhaustive()
+    }
+}
+
+impl Deref for Spec {
+    type Target = Variable;
+
+    fn deref(&self) -> &Variable {
+        &self.0
+    }
+}
+
+/// A script variable parsed by OpenSIPS, e.g. a parameter of a
+/// command. [`Spec`] owns one.
+#[repr(transparent)]
+pub struct Variable(opensips::pv_spec_t);
+
+impl fmt::Debug for Variable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Variable").finish_non_exhaustive()
+    }
+}
+
+impl Spec {
+    /// Parses the variable at the start of `s`, returning it and the
+    /// number of bytes it spans. Variables may allocate private
+    /// memory, so this should be done during module initialization.
+    pub fn parse_prefix(s: &str) -> Option<(Self, usize)> {
+        // SAFETY: The specification is plain data which
+        // `pv_parse_spec` fills in.
+        let mut spec = Box::new(Variable(unsafe { mem::zeroed() }));
+        let input = s.as_opensips_str();
+
+        // SAFETY: `input` points into `s`, which outlives the call.
+        // OpenSIPS copies whatever names it needs to keep.
+        let end = unsafe { opensips::pv_parse_spec(&input, &mut spec.0) };
+        if end.is_null() {
+            return None;
+        }
+
+        // SAFETY: On success, `end` points into `s`, just past the
+        // variable.
+        let len = unsafe { end.cast_const().offset_from(s.as_ptr().cast()) };
+        let len = usize::try_from(len)
+            .ok()
+            .filter(|&l| l > 0 && l <= s.len())?;
+        Some((Self(spec), len))
+    }
+}
+
+impl Variable {
+    /// # Safety
+    ///
+    /// `spec` needs to be non-NULL and valid for `'a`.
+    pub unsafe fn from_ptr<'a>(spec: *mut opensips::pv_spec_t) -> &'a Self {
+        &*spec.cast::<Self>()
+    }
+
+    fn as_ptr(&self) -> *mut opensips::pv_spec_t {
+        &self.0 as *const _ as *mut _
+    }
+
+    /// The value of the variable for `msg`, formatted as text.
+    /// Returns `None` when the variable is unset.
+    pub fn get(&self, msg: &mut opensips::sip_msg) -> Option<String> {
+        // SAFETY: The value is plain data which OpenSIPS fills in.
+        let mut value: opensips::pv_value_t = unsafe { mem::zeroed() };
+
+        // SAFETY: [OpenSIPS::valid] The message is a valid message
+        // and getters don't modify the specification.
+        if unsafe { opensips::pv_get_spec_value(msg, self.as_ptr(), &mut value) } != 0 {
+            return None;
+        }
+
+        let flags = value.flags as u32;
+        if flags & opensips::PV_VAL_NULL != 0 {
+            None
+        } else if flags & opensips::PV_VAL_STR != 0 {
+            // The string lives in a buffer which the next lookup may
+            // reuse, so copy it right away.
+            value.rs.try_as_str().ok().map(String::from)
+        } else if flags & opensips::PV_VAL_INT != 0 {
+            Some(value.ri.to_string())
+        } else {
+            None
+        }
+    }
+
+    /// Assigns `value` to the variable for `msg`, as `=` does in the
+    /// script. Returns `false` for read-only variables like `$ci`.
+    pub fn set(&self, msg: &mut opensips::sip_msg, value: &str) -> bool {
+        let value = opensips::pv_value_t {
+            rs: value.as_opensips_str(),
+            ri: 0,
+            flags: opensips::PV_VAL_STR as _,
+        };
+        self.assign(msg, value)
+    }
+
+    /// Like [`set`][Self::set], but the variable holds a number the
+    /// script can compare.
+    pub fn set_int(&self, msg: &mut opensips::sip_msg, value: i32) -> bool {
+        let value = opensips::pv_value_t {
+            rs: "".as_opensips_str(),
+            ri: value,
+            flags: (opensips::PV_VAL_INT | opensips::PV_TYPE_INT) as _,
+        };
+        self.assign(msg, value)
+    }
+
+    fn assign(&self, msg: &mut opensips::sip_msg, mut value: opensips::pv_value_t) -> bool {
+        // SAFETY: [OpenSIPS::valid] The message is a valid message,
+        // and setters copy the value.
+        unsafe { opensips::pv_set_value(msg, self.as_ptr(), opensips::EQ_T as _, &mut value) >= 0 }
+    }
+}
@@ -0,0 +1,142 @@
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, AtomicI32, AtomicI64, AtomicU32, AtomicU64, AtomicUsize};
+
+use crate::lock::{Lock, LockGuard};
+use crate::memory::{ShmBox, ShmString};
+
+/// Types that are meaningful when viewed from a process other than
+/// the one that created them. They must not point into the heap or
+/// pkg memory.
+///
+/// # Safety
+///
+/// Any pointers inside the type must point into shared memory.
+pub unsafe trait ShmSafe: Sync {}
+
+macro_rules! impl_shm_safe {
+    ($($ty:ty),* $(,)?) => {
+        $(unsafe impl ShmSafe for $ty {})*
+    };
+}
+
+impl_shm_safe!(
+    bool,
+    u8,
+    u16,
+    u32,
+    u64,
+    usize,
+    i8,
+    i16,
+    i32,
+    i64,
+    isize,
+    AtomicBool,
+    AtomicI32,
+    AtomicI64,
+    AtomicU32,
+    AtomicU64,
+    AtomicUsize,
+    ShmString,
+);
+
+unsafe impl<T: ShmSafe> ShmSafe for Option<T> {}
+unsafe impl<T: ShmSafe, const N: usize> ShmSafe for [T; N] {}
+unsafe impl<T: ShmSafe> ShmSafe for ShmBox<T> {}
+
+/// A value in shared memory.
+///
+/// This must be created during module initialization so that every
+/// worker process forked afterwards sees the same value. The memory
+/// is never freed as we can't know when the last process is done
+/// with it.
+pub struct Shared<T: ShmSafe>(NonNull<T>);
+
+unsafe impl<T: ShmSafe> Send for Shared<T> {}
+unsafe impl<T: ShmSafe> Sync for Shared<T> {}
+
+impl<T: ShmSafe> Shared<T> {
+    /// Returns `None` when shared memory is exhausted.
+    pub fn new(value: T) -> Option<Self> {
+        let value = ShmBox::new(value)?;
+        NonNull::new(ShmBox::into_raw(value)).map(Self)
+    }
+}
+
+impl<T: ShmSafe> Clone for Shared<T> {
+    fn clone(&self) -> Self {
+        Self(self.0)
+    }
+}
+
+impl<T: ShmSafe> Deref for Shared<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: The value is initialized and never freed.
+        unsafe { self.0.as_ref() }
+    }
+}
+
+impl<T: ShmSafe + fmt::Debug> fmt::Debug for Shared<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+/// A mutual exclusion lock that works across OpenSIPS processes.
+pub struct Mutex<T> {
+    lock: Lock,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+unsafe impl<T: ShmSafe + Send> ShmSafe for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    /// Returns `None` when shared memory is exhausted.
+    pub fn new(value: T) -> Option<Self> {
+        Some(Self {
+            lock: Lock::new()?,
+            value: UnsafeCell::new(value),
+        })
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        MutexGuard {
+            mutex: self,
+            _guard: self.lock.lock(),
+        }
+    }
+}
+
+impl<T> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mutex").finish_non_exhaustive()
+    }
+}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+    _guard: LockGuard<'a>,
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: We hold the lock.
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: We hold the lock.
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
@@ -0,0 +1,127 @@
+use core::cell::UnsafeCell;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::generated as opensips;
+use crate::StrExt;
+
+/// A counter exported to the OpenSIPS statistics, e.g. for
+/// `opensips-cli -x mi get_statistics`.
+///
+/// OpenSIPS allocates the counter in shared memory when the module
+/// is loaded, so every process updates the same value.
+#[repr(C)]
+pub struct Statistic(UnsafeCell<*mut opensips::stat_var>);
+
+// OpenSIPS only writes the pointer while loading the module, before
+// anything else can use it. The counter itself is atomic.
+unsafe impl Sync for Statistic {}
+
+impl Statistic {
+    pub const fn new() -> Self {
+        Self(UnsafeCell::new(ptr::null_mut()))
+    }
+
+    fn counter(&self) -> Option<&AtomicUsize> {
+        // SAFETY: The pointer is either NULL or set by OpenSIPS to a
+        // statistic which lives as long as the process.
+        let var = unsafe { (*self.0.get()).as_ref()? };
+        counter(var)
+    }
+
+    /// This is `update_stat` in `statistics.h`.
+    pub fn add(&self, n: usize) {
+        if let Some(counter) = self.counter() {
+            counter.fetch_add(n, Ordering::Relaxed);
+        }
+    }
+
+    pub fn increment(&self) {
+        self.add(1);
+    }
+
+    /// This is `get_stat_val` in `statistics.h`.
+    pub fn get(&self) -> usize {
+        self.counter().map_or(0, |c| c.load(Ordering::Relaxed))
+    }
+
+    /// This is `reset_stat` in `statistics.h`.
+    pub fn reset(&self) {
+        if let Some(counter) = self.counter() {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+
+    #[doc(hidden)]
+    pub const fn as_stat_pointer(&self) -> *mut *mut opensips::stat_var {
+        self.0.get()
+    }
+}
+
+impl Default for Statistic {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The value of any statistic, e.g. `rcv_requests`, whichever module
+/// it belongs to. This is `get_stat` and `get_stat_val` in
+/// `statistics.h`.
+pub fn value(name: &str) -> Option<usize> {
+    let mut name = name.as_opensips_str();
+
+    // SAFETY: OpenSIPS only reads the name, and statistics live as
+    // long as the process.
+    let var = unsafe { opensips::get_stat(&mut name).as_ref()? };
+
+    if var.flags & opensips::STAT_IS_FUNC as u16 == 0 {
+        return counter(var).map(|c| c.load(Ordering::Relaxed));
+    }
+
+    // SAFETY: Statistics computed on demand have a function, which
+    // is called with their context.
+    let value = unsafe { var.u.f?(var.context) };
+    value.try_into().ok()
+}
+
+fn counter(var: &opensips::stat_var) -> Option<&AtomicUsize> {
+    if var.flags & opensips::STAT_IS_FUNC as u16 != 0 {
+        return None;
+    }
+
+    // SAFETY: `stat_val` is an `atomic_t`, which wraps a single
+    // `unsigned long`, as `AtomicUsize` does on the platforms
+    // OpenSIPS runs on.
+    unsafe { var.u.val.cast::<AtomicUsize>().as_ref() }
+}
+
+/// Generates a `static STATS` with the specified names.
+///
+/// ```rust,norun
+/// opensips::statistics! {
+///     #[name = "requests"]
+///     static REQUESTS: Statistic;
+/// }
+/// ```
+#[macro_export]
+macro_rules! statistics {
+    ($(
+        #[name = $name:literal]
+        static $var_name:ident: Statistic;
+    )*) => {
+        $(
+            static $var_name: $crate::statistic::Statistic = $crate::statistic::Statistic::new();
+        )*
+
+        static STATS: &[$crate::stat_export_t] = &[
+            $(
+                $crate::stat_export_t {
+                    name: $crate::cstr_lit!(mut $name),
+                    flags: 0,
+                    stat_pointer: $var_name.as_stat_pointer(),
+                },
+            )*
+            $crate::stat_export_t::NULL,
+        ];
+    };
+}
@@ -0,0 +1,157 @@
+use std::ffi::CString;
+use std::os::raw::{c_char, c_uint, c_ulonglong, c_ushort, c_void};
+use std::panic::{self, AssertUnwindSafe};
+use std::time::Duration;
+
+use crate::generated as opensips;
+
+/// What to do when the previous run of a timer hasn't finished by
+/// the time it should run again.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum OnDelay {
+    /// Run anyway, possibly in parallel in another process.
+    #[default]
+    Run,
+    /// Skip this run.
+    Skip,
+    /// Run once the previous run finishes.
+    Delay,
+}
+
+impl OnDelay {
+    fn flags(self) -> c_ushort {
+        let flags = match self {
+            Self::Run => 0,
+            Self::Skip => opensips::TIMER_FLAG_SKIP_ON_DELAY,
+            Self::Delay => opensips::TIMER_FLAG_DELAY_ON_DELAY,
+        };
+        flags as c_ushort
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    InvalidName,
+    InvalidInterval,
+    Register,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidName => f.write_str("timer name contains a NUL byte"),
+            Self::InvalidInterval => f.write_str("timer interval is out of range"),
+            Self::Register => f.write_str("unable to register timer"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Runs `f` every `interval` (with a resolution of one second) in
+/// the OpenSIPS timer processes. This must be called during module
+/// initialization, before the processes fork.
+///
+/// A panic in `f` is contained to that run of that timer.
+pub fn register_timer<F>(
+    name: &str,
+    interval: Duration,
+    on_delay: OnDelay,
+    f: F,
+) -> Result<(), Error>
+where
+    F: Fn() + Send + Sync + 'static,
+{
+    let interval = interval
+        .as_secs()
+        .try_into()
+        .map_err(|_| Error::InvalidInterval)?;
+    if interval == 0 {
+        return Err(Error::InvalidInterval);
+    }
+
+    let (label, param) = leak(name, f)?;
+
+    // SAFETY: The trampoline matches the expected signature and the
+    // label and parameter live forever.
+    let rc = unsafe {
+        opensips::register_timer(
+            label,
+            Some(timer_trampoline::<F>),
+            param,
+            interval,
+            on_delay.flags(),
+        )
+    };
+
+    if rc < 0 {
+        return Err(Error::Register);
+    }
+    Ok(())
+}
+
+/// Like [`register_timer`], but with a resolution of one
+/// microsecond.
+pub fn register_utimer<F>(
+    name: &str,
+    interval: Duration,
+    on_delay: OnDelay,
+    f: F,
+) -> Result<(), Error>
+where
+    F: Fn() + Send + Sync + 'static,
+{
+    let interval = interval
+        .as_micros()
+        .try_into()
+        .map_err(|_| Error::InvalidInterval)?;
+    if interval == 0 {
+        return Err(Error::InvalidInterval);
+    }
+
+    let (label, param) = leak(name, f)?;
+
+    // SAFETY: The trampoline matches the expected signature and the
+    // label and parameter live forever.
+    let rc = unsafe {
+        opensips::register_utimer(
+            label,
+            Some(utimer_trampoline::<F>),
+            param,
+            interval,
+            on_delay.flags(),
+        )
+    };
+
+    if rc < 0 {
+        return Err(Error::Register);
+    }
+    Ok(())
+}
+
+// OpenSIPS keeps both pointers for the lifetime of the process.
+fn leak<F>(name: &str, f: F) -> Result<(*mut c_char, *mut c_void), Error> {
+    let label = CString::new(name)
+        .map_err(|_| Error::InvalidName)?
+        .into_raw();
+    let param = Box::into_raw(Box::new(f)).cast();
+    Ok((label, param))
+}
+
+extern "C" fn timer_trampoline<F: Fn()>(_ticks: c_uint, param: *mut c_void) {
+    run::<F>(param)
+}
+
+extern "C" fn utimer_trampoline<F: Fn()>(_uticks: c_ulonglong, param: *mut c_void) {
+    run::<F>(param)
+}
+
+fn run<F: Fn()>(param: *mut c_void) {
+    // SAFETY: `param` was created from a `Box<F>` in `leak` and is
+    // never freed.
+    let f = unsafe { &*param.cast::<F>() };
+
+    // The panic hook has already reported the problem; all that's
+    // left is to keep it from unwinding into C.
+    let _ = panic::catch_unwind(AssertUnwindSafe(f));
+}
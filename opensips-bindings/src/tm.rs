@@ -0,0 +1,237 @@
+use core::ptr::{self, NonNull};
+use core::{fmt, mem};
+use std::os::raw::c_int;
+
+use crate::generated as opensips;
+use crate::memory::ShmString;
+use crate::StrExt;
+
+/// The API of the `tm` module.
+pub struct Tm(opensips::tm_binds);
+
+impl fmt::Debug for Tm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tm").finish_non_exhaustive()
+    }
+}
+
+/// This is `load_tm_api` in `modules/tm/tm_load.h`, a `static
+/// inline` function which bindgen doesn't generate. Returns `None`
+/// when the `tm` module isn't loaded.
+pub fn load_tm_api() -> Option<Tm> {
+    // SAFETY: `find_export` is called with a static string, and
+    // `transmute` is the same function pointer cast as in the
+    // original C code.
+    let load_tm: opensips::load_tm_f = unsafe {
+        let load_tm_raw = opensips::find_export(crate::cstr_lit!("load_tm"), 0);
+        mem::transmute(load_tm_raw)
+    };
+    let load_tm = load_tm?;
+
+    // SAFETY: The structure only holds function pointers, which are
+    // all `None` when zeroed.
+    let mut tmb: opensips::tm_binds = unsafe { mem::zeroed() };
+
+    // SAFETY: `tmb` is initialized and `tm` fills in every field.
+    if unsafe { load_tm(&mut tmb) } == -1 {
+        return None;
+    }
+
+    Some(Tm(tmb))
+}
+
+impl Tm {
+    /// Sends a request outside of any dialog, without waiting for its
+    /// reply. OpenSIPS picks the Call-ID and the From tag.
+    pub fn request(&self, request: &Request<'_>) -> bool {
+        let Some(t_request) = self.0.t_request else {
+            return false;
+        };
+
+        let mut method = request.method.as_opensips_str();
+        let mut uri = request.uri.as_opensips_str();
+        let mut to = request.to.as_opensips_str();
+        let mut from = request.from.as_opensips_str();
+        let mut headers = request.headers.as_opensips_str();
+        let mut body = request.body.as_opensips_str();
+        let mut next_hop = request.next_hop.map(str::as_opensips_str);
+        let next_hop = next_hop.as_mut().map_or(ptr::null_mut(), |n| n as *mut _);
+
+        // SAFETY: The strings are copied into the transaction, and a
+        // NULL outbound proxy routes by the request URI.
+        let rc: c_int = unsafe {
+            t_request(
+                &mut method,
+                &mut uri,
+                &mut to,
+                &mut from,
+                &mut headers,
+                &mut body,
+                next_hop,
+                None,
+                ptr::null_mut(),
+                None,
+            )
+        };
+
+        rc >= 0
+    }
+}
+
+/// A request sent with [`Tm::request`].
+#[derive(Debug, Copy, Clone)]
+pub struct Request<'a> {
+    pub method: &'a str,
+    pub uri: &'a str,
+    pub to: &'a str,
+    pub from: &'a str,
+    /// Complete header lines, each ending with CRLF.
+    pub headers: &'a str,
+    pub body: &'a str,
+    /// Where to send the request instead of the request URI.
+    pub next_hop: Option<&'a str>,
+}
+
+/// A URI which reaches where `msg` came from, over the same
+/// transport.
+pub fn next_hop(msg: &opensips::sip_msg) -> Option<String> {
+    let transport = match msg.rcv.proto as u32 {
+        opensips::sip_protos::PROTO_TCP => ";transport=tcp",
+        opensips::sip_protos::PROTO_TLS => ";transport=tls",
+        _ => "",
+    };
+
+    let next_hop = match msg.source()? {
+        std::net::SocketAddr::V4(a) => format!("sip:{a}{transport}"),
+        std::net::SocketAddr::V6(a) => format!("sip:[{}]:{}{transport}", a.ip(), a.port()),
+    };
+    Some(next_hop)
+}
+
+/// The UAS side of the dialog of a request, used to send requests
+/// back to whoever sent it.
+pub struct Dialog<'a> {
+    tm: &'a Tm,
+    dlg: NonNull<opensips::dlg_t>,
+    // `hooks.next_hop` points here, and that into `next_hop_uri`.
+    next_hop: Box<opensips::str_>,
+    next_hop_uri: String,
+}
+
+impl fmt::Debug for Dialog<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Dialog")
+            .field("next_hop", &self.next_hop_uri)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'a> Dialog<'a> {
+    /// The dialog `msg` belongs to, as seen by its recipient. Requests
+    /// are sent straight back to where `msg` came from, on the socket
+    /// it was received on.
+    ///
+    /// Requests outside of a dialog don't have a To tag yet, so
+    /// `local_tag` is used instead.
+    pub fn from_request(tm: &'a Tm, msg: &mut opensips::sip_msg, local_tag: &str) -> Option<Self> {
+        let new_dlg_uac = tm.0.new_dlg_uac?;
+
+        let remote_tag = msg.from_tag()?.to_owned();
+        let local_tag = msg.to_tag().unwrap_or(local_tag).to_owned();
+        msg.parse_headers(crate::HDR_CALLID_F);
+
+        let call_id = msg.call_id()?.as_opensips_str();
+        let local_uri = to_body(msg.to)?.uri;
+        let remote_uri = to_body(msg.from)?.uri;
+
+        let mut dlg = ptr::null_mut();
+
+        // SAFETY: [OpenSIPS::valid] Everything is copied into the new
+        // dialog. OpenSIPS has no use for the local CSeq of a dialog
+        // it didn't start, so we start at 1.
+        let rc = unsafe {
+            new_dlg_uac(
+                &call_id as *const _ as *mut _,
+                &local_tag.as_opensips_str() as *const _ as *mut _,
+                1,
+                &local_uri as *const _ as *mut _,
+                &remote_uri as *const _ as *mut _,
+                &mut dlg,
+            )
+        };
+        if rc < 0 {
+            return None;
+        }
+        let dlg = NonNull::new(dlg)?;
+
+        let next_hop = next_hop(msg)?;
+
+        let mut dialog = Self {
+            tm,
+            dlg,
+            next_hop: Box::new(next_hop.as_opensips_str()),
+            next_hop_uri: next_hop,
+        };
+
+        // `free_dlg` releases the remote tag with `shm_free`.
+        let remote_tag = ShmString::new(&remote_tag)?;
+        let len = remote_tag.len().try_into().ok()?;
+
+        // SAFETY: The dialog was just created and nobody else uses it.
+        let d = unsafe { dialog.dlg.as_mut() };
+        d.id.rem_tag = opensips::str_ {
+            s: remote_tag.into_raw(),
+            len,
+        };
+        d.state = opensips::dlg_state::DLG_CONFIRMED;
+        d.send_sock = msg.rcv.bind_address;
+        d.hooks.next_hop = &mut *dialog.next_hop;
+
+        Some(dialog)
+    }
+
+    /// Sends a request with `method` within the dialog, without
+    /// waiting for its reply. `headers` are complete header lines,
+    /// each ending with CRLF.
+    pub fn request(&mut self, method: &str, headers: &str, body: &str) -> bool {
+        let Some(t_request_within) = self.tm.0.t_request_within else {
+            return false;
+        };
+
+        let mut method = method.as_opensips_str();
+        let mut headers = headers.as_opensips_str();
+        let mut body = body.as_opensips_str();
+
+        // SAFETY: The strings are copied into the transaction, and
+        // the dialog is valid until dropped.
+        let rc: c_int = unsafe {
+            t_request_within(
+                &mut method,
+                &mut headers,
+                &mut body,
+                self.dlg.as_ptr(),
+                None,
+                ptr::null_mut(),
+                None,
+            )
+        };
+
+        rc >= 0
+    }
+}
+
+impl Drop for Dialog<'_> {
+    fn drop(&mut self) {
+        if let Some(free_dlg) = self.tm.0.free_dlg {
+            // SAFETY: The dialog was created by `new_dlg_uac` and is
+            // not used afterwards.
+            unsafe { free_dlg(self.dlg.as_ptr()) };
+        }
+    }
+}
+
+fn to_body<'a>(header: *const opensips::hdr_field) -> Option<&'a opensips::to_body> {
+    // SAFETY: [OpenSIPS::valid] The header is either NULL or has been
+    // parsed into a `to_body`.
+    unsafe { header.as_ref()?.parsed.cast::<opensips::to_body>().as_ref() }
+}
@@ -0,0 +1,112 @@
+use core::{fmt, mem, ptr};
+use std::ffi::CString;
+
+use crate::generated as opensips;
+use crate::StrExt;
+
+/// The API of the `usrloc` module.
+pub struct Usrloc(opensips::usrloc_api_t);
+
+impl fmt::Debug for Usrloc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Usrloc").finish_non_exhaustive()
+    }
+}
+
+/// A registered contact of an address of record.
+#[derive(Debug, Clone)]
+pub struct Contact {
+    pub uri: String,
+    /// Seconds since the Unix epoch, `None` for permanent contacts.
+    pub expires: Option<i64>,
+    pub user_agent: Option<String>,
+}
+
+/// This is `load_ul_api` in `modules/usrloc/usrloc.h`, a `static
+/// inline` function which bindgen doesn't generate. Returns `None`
+/// when the `usrloc` module isn't loaded.
+pub fn load_ul_api() -> Option<Usrloc> {
+    // SAFETY: `find_export` is called with a static string, and
+    // `transmute` is the same function pointer cast as in the
+    // original C code.
+    let bind_usrloc: opensips::bind_usrloc_t = unsafe {
+        let bind_usrloc_raw = opensips::find_export(crate::cstr_lit!("ul_bind_usrloc"), 0);
+        mem::transmute(bind_usrloc_raw)
+    };
+    let bind_usrloc = bind_usrloc?;
+
+    // SAFETY: The structure only holds flags and function pointers,
+    // which are all `None` when zeroed.
+    let mut api: opensips::usrloc_api_t = unsafe { mem::zeroed() };
+
+    // SAFETY: `api` is initialized and `usrloc` fills in every field.
+    if unsafe { bind_usrloc(&mut api) } < 0 {
+        return None;
+    }
+
+    Some(Usrloc(api))
+}
+
+impl Usrloc {
+    /// Whether addresses of record include the domain.
+    pub fn use_domain(&self) -> bool {
+        self.0.use_domain != 0
+    }
+
+    /// The contacts registered for `aor` in the table `domain`, e.g.
+    /// `location`. Returns `None` when there is no such table.
+    pub fn contacts(&self, domain: &str, aor: &str) -> Option<Vec<Contact>> {
+        let get_udomain = self.0.get_udomain?;
+        let lock_udomain = self.0.lock_udomain?;
+        let unlock_udomain = self.0.unlock_udomain?;
+        let get_urecord = self.0.get_urecord?;
+        let release_urecord = self.0.release_urecord?;
+
+        let domain = CString::new(domain).ok()?;
+        let mut udomain = ptr::null_mut();
+
+        // SAFETY: The name is a valid C string, only read to find
+        // one of the tables registered while starting up.
+        if unsafe { get_udomain(domain.as_ptr(), &mut udomain) } < 0 || udomain.is_null() {
+            return None;
+        }
+
+        let mut aor = aor.as_opensips_str();
+        let mut record = ptr::null_mut();
+        let mut contacts = Vec::new();
+
+        // SAFETY: The record and its contacts are only read while
+        // the entry of the AOR is locked, and are copied before it is
+        // unlocked again. `get_urecord` returns 0 when the AOR is
+        // registered, and the record is released like `registrar`'s
+        // `lookup` does, which frees it when usrloc doesn't keep it
+        // in memory.
+        unsafe {
+            lock_udomain(udomain, &mut aor);
+
+            if get_urecord(udomain, &mut aor, &mut record) == 0 {
+                if let Some(record) = record.as_ref() {
+                    let mut contact = record.contacts;
+                    while let Some(c) = contact.as_ref() {
+                        contacts.push(Contact {
+                            uri: c.c.try_as_str().unwrap_or_default().into(),
+                            expires: (c.expires != 0).then_some(c.expires.into()),
+                            user_agent: c
+                                .user_agent
+                                .try_as_str()
+                                .ok()
+                                .filter(|ua| !ua.is_empty())
+                                .map(Into::into),
+                        });
+                        contact = c.next;
+                    }
+                }
+                release_urecord(record, 0);
+            }
+
+            unlock_udomain(udomain, &mut aor);
+        }
+
+        Some(contacts)
+    }
+}
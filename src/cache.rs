@@ -0,0 +1,308 @@
+use opensips::{
+    map::{unix_time, Map},
+    memory::ShmString,
+    shared::{Shared, ShmSafe},
+};
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use tracing::{debug, warn};
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Answers are asked for again once they are this old.
+    pub ttl: Duration,
+    /// How many answers to keep, roughly. To make room, only the
+    /// answers sharing a bucket of the map with the new one are looked
+    /// at, so the cache grows past this when that bucket is empty.
+    pub soft_capacity: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            ttl: Duration::from_secs(3600),
+            soft_capacity: 1000,
+        }
+    }
+}
+
+struct Answer {
+    answer: ShmString,
+    /// When the answer expires, as a [`unix_time`].
+    expires: u64,
+    /// When the answer was last used, according to `Inner::clock`.
+    used: u64,
+}
+
+// SAFETY: The only pointer is inside the `ShmString`.
+unsafe impl ShmSafe for Answer {}
+
+struct Inner {
+    answers: Map<Answer>,
+    // Counts lookups, so the least recently used answer has the
+    // lowest `used`.
+    clock: AtomicU64,
+}
+
+// SAFETY: Both fields are `ShmSafe` themselves.
+unsafe impl ShmSafe for Inner {}
+
+/// Answers of the LLM to questions without any earlier messages,
+/// shared by all processes.
+#[derive(Clone)]
+pub struct Cache {
+    config: Config,
+    inner: Shared<Inner>,
+}
+
+impl std::fmt::Debug for Cache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Cache")
+            .field("config", &self.config)
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+/// An answer as shown by [`Cache::entries`].
+#[derive(Debug)]
+pub struct Entry {
+    pub model: String,
+    pub question: String,
+    pub answer: String,
+    pub expires_in: Duration,
+}
+
+impl Cache {
+    /// Allocates the cache in shared memory, see [`Map::new`].
+    pub fn new(config: Config) -> Option<Self> {
+        let inner = Shared::new(Inner {
+            answers: Map::new()?,
+            clock: AtomicU64::new(0),
+        })?;
+        Some(Self { config, inner })
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.answers.len()
+    }
+
+    /// The key of a question; the same question has a different
+    /// answer with a different model or system prompt.
+    pub fn key(model: &str, system_prompt: &str, question: &str) -> String {
+        let mut hasher = DefaultHasher::new();
+        system_prompt.hash(&mut hasher);
+        let prompt = hasher.finish();
+
+        // Neither the model nor a header value contains a newline.
+        format!("{model}\n{prompt:016x}\n{question}")
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        let now = unix_time();
+        let tick = self.tick();
+
+        self.inner
+            .answers
+            .update(key, |answer| {
+                let a = answer.as_mut()?;
+                if is_expired(a.expires, now) {
+                    *answer = None;
+                    return None;
+                }
+
+                a.used = tick;
+                Some(a.answer.as_str().to_owned())
+            })
+            .ok()
+            .flatten()
+    }
+
+    pub fn insert(&self, key: &str, answer: &str) {
+        let now = unix_time();
+
+        // Other processes may be inserting at the same time, so this
+        // is only approximate.
+        if self.len() >= self.config.soft_capacity {
+            self.evict(key, now);
+        }
+
+        let Some(answer) = ShmString::new(answer) else {
+            warn!("Not caching the answer: out of shared memory");
+            return;
+        };
+        let answer = Answer {
+            answer,
+            expires: expiry(now, self.config.ttl),
+            used: self.tick(),
+        };
+
+        if let Err(e) = self.inner.answers.update(key, |a| *a = Some(answer)) {
+            warn!("Not caching the answer: {e}");
+        }
+    }
+
+    /// Forgets every answer.
+    pub fn clear(&self) {
+        self.inner.answers.clear();
+    }
+
+    pub fn entries(&self) -> Vec<Entry> {
+        let now = unix_time();
+        let mut entries = Vec::new();
+
+        self.inner.answers.retain(|key, a| {
+            let (model, question) = split_key(key);
+            entries.push(Entry {
+                model: model.into(),
+                question: question.into(),
+                answer: a.answer.as_str().into(),
+                expires_in: Duration::from_secs(a.expires.saturating_sub(now)),
+            });
+            true
+        });
+
+        entries
+    }
+
+    // Drops the expired answers in the bucket `key` goes to, or the
+    // least recently used one there when none have expired. Looking at
+    // a single bucket keeps inserts cheap; an empty bucket lets the
+    // cache grow a little past its capacity instead.
+    fn evict(&self, key: &str, now: u64) {
+        let mut oldest = Oldest::default();
+
+        self.inner.answers.retain_bucket(key, |other, a| {
+            if is_expired(a.expires, now) {
+                return false;
+            }
+            oldest.see(other, a.used);
+            true
+        });
+
+        if self.len() < self.config.soft_capacity {
+            return;
+        }
+        if let Some(key) = oldest.key {
+            debug!("Evicting the least recently used answer");
+            self.inner.answers.remove(&key);
+        }
+    }
+
+    fn tick(&self) -> u64 {
+        self.inner.clock.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+/// When an answer cached at `now` expires, as a [`unix_time`].
+fn expiry(now: u64, ttl: Duration) -> u64 {
+    now.saturating_add(ttl.as_secs())
+}
+
+fn is_expired(expires: u64, now: u64) -> bool {
+    expires <= now
+}
+
+/// The model and the question a [`Cache::key`] was made of.
+fn split_key(key: &str) -> (&str, &str) {
+    let mut parts = key.splitn(3, '\n');
+    let model = parts.next().unwrap_or_default();
+    let question = parts.nth(1).unwrap_or_default();
+    (model, question)
+}
+
+/// The least recently used of the answers seen.
+#[derive(Debug, Default)]
+struct Oldest {
+    used: u64,
+    key: Option<String>,
+}
+
+impl Oldest {
+    fn see(&mut self, key: &str, used: u64) {
+        if self.key.is_none() || used < self.used {
+            self.used = used;
+            self.key = Some(key.into());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{expiry, is_expired, split_key, Cache, Oldest};
+    use std::time::Duration;
+
+    #[test]
+    fn expires_after_the_ttl() {
+        let expires = expiry(1000, Duration::from_secs(60));
+        assert!(!is_expired(expires, 1000));
+        assert!(!is_expired(expires, 1059));
+        assert!(is_expired(expires, 1060));
+        assert!(is_expired(expires, 2000));
+    }
+
+    #[test]
+    fn expires_immediately_without_a_ttl() {
+        assert!(is_expired(expiry(1000, Duration::ZERO), 1000));
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used() {
+        let mut oldest = Oldest::default();
+        assert_eq!(oldest.key, None);
+
+        oldest.see("b", 7);
+        oldest.see("a", 3);
+        oldest.see("c", 5);
+        assert_eq!(oldest.key.as_deref(), Some("a"));
+
+        oldest.see("d", 3);
+        assert_eq!(oldest.key.as_deref(), Some("a"));
+    }
+
+    #[test]
+    fn evicts_the_only_answer() {
+        let mut oldest = Oldest::default();
+        oldest.see("a", u64::MAX);
+        assert_eq!(oldest.key.as_deref(), Some("a"));
+    }
+
+    #[test]
+    fn keys_depend_on_the_model_prompt_and_question() {
+        let key = Cache::key("gpt-4o", "Be brief.", "Who is there?");
+        assert_eq!(key, Cache::key("gpt-4o", "Be brief.", "Who is there?"));
+        assert_ne!(key, Cache::key("gpt-4o-mini", "Be brief.", "Who is there?"));
+        assert_ne!(key, Cache::key("gpt-4o", "Be verbose.", "Who is there?"));
+        assert_ne!(key, Cache::key("gpt-4o", "Be brief.", "Who is here?"));
+    }
+
+    #[test]
+    fn keys_hide_the_prompt() {
+        let key = Cache::key("gpt-4o", "Be brief.", "Who is there?");
+        let mut lines = key.lines();
+        assert_eq!(lines.next(), Some("gpt-4o"));
+        let prompt = lines.next().expect("The prompt hash is the second line");
+        assert_eq!(prompt.len(), 16);
+        assert!(prompt.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(lines.next(), Some("Who is there?"));
+    }
+
+    #[test]
+    fn splits_keys() {
+        let key = Cache::key("gpt-4o", "Be brief.", "Who is there?");
+        assert_eq!(split_key(&key), ("gpt-4o", "Who is there?"));
+
+        let key = Cache::key("gpt-4o", "", "Two\nlines");
+        assert_eq!(split_key(&key), ("gpt-4o", "Two\nlines"));
+
+        assert_eq!(split_key("garbage"), ("garbage", ""));
+    }
+}
@@ -0,0 +1,290 @@
+use crate::llm::{Message, Role};
+use opensips::{
+    map::{unix_time, Map},
+    memory::ShmString,
+    shared::{Shared, ShmSafe},
+};
+use std::{str::FromStr, time::Duration};
+use tracing::{debug, warn};
+
+/// What makes two requests part of the same conversation.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum Key {
+    #[default]
+    CallId,
+    /// The Call-ID and the tag of the caller, in either direction,
+    /// so forks of a call are told apart. The request starting the
+    /// dialog has the caller's tag in From; later requests in either
+    /// direction find the conversation by their From or To tag.
+    Tags,
+}
+
+impl FromStr for Key {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "call-id" => Ok(Self::CallId),
+            "tags" => Ok(Self::Tags),
+            _ => Err(format!(
+                "Unknown conversation key `{s}`, expected `call-id` or `tags`"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub key: Key,
+    /// Conversations are forgotten this long after their last message.
+    pub ttl: Duration,
+    /// New conversations aren't remembered once there are this many.
+    pub max_conversations: usize,
+    /// The oldest messages are forgotten to stay under this.
+    pub max_tokens: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            key: Key::default(),
+            ttl: Duration::from_secs(600),
+            max_conversations: 1000,
+            max_tokens: 1000,
+        }
+    }
+}
+
+struct Conversation {
+    /// The messages as JSON, as they have to live in shared memory.
+    messages: ShmString,
+    /// When the conversation is forgotten, as a [`unix_time`].
+    expires: u64,
+}
+
+// SAFETY: The only pointer is inside the `ShmString`.
+unsafe impl ShmSafe for Conversation {}
+
+/// Earlier messages of conversations with the LLM, shared by all
+/// processes.
+#[derive(Debug, Clone)]
+pub struct Store {
+    config: Config,
+    conversations: Shared<Map<Conversation>>,
+}
+
+impl Store {
+    /// Allocates the store in shared memory, see [`Map::new`].
+    pub fn new(config: Config) -> Option<Self> {
+        let conversations = Shared::new(Map::new()?)?;
+        Some(Self {
+            config,
+            conversations,
+        })
+    }
+
+    /// The key of the conversation `msg` belongs to.
+    pub fn key(&self, msg: &mut opensips::sip_msg) -> Option<String> {
+        match self.config.key {
+            Key::CallId => {
+                msg.parse_headers(opensips::HDR_CALLID_F);
+                msg.call_id().map(Into::into)
+            }
+
+            Key::Tags => {
+                msg.parse_headers(opensips::HDR_CALLID_F);
+                let call_id = msg.call_id()?.to_owned();
+                let from = msg.from_tag()?.to_owned();
+                let to = msg.to_tag().map(ToOwned::to_owned);
+                Some(tags_key(&call_id, &from, to.as_deref(), |key| {
+                    self.conversations.get(key, |_| ()).is_some()
+                }))
+            }
+        }
+    }
+
+    /// The messages of the conversation so far, oldest first.
+    pub fn history(&self, key: &str) -> Vec<Message> {
+        let now = unix_time();
+
+        self.conversations
+            .get(key, |c| {
+                if c.expires <= now {
+                    return Vec::new();
+                }
+                serde_json::from_str(&c.messages).unwrap_or_default()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Remembers a question and its answer.
+    pub fn append(&self, key: &str, question: &str, answer: &str) {
+        let now = unix_time();
+        let full = self.conversations.len() >= self.config.max_conversations;
+
+        let updated = self.conversations.update(key, |conversation| {
+            let mut messages = match conversation {
+                Some(c) if c.expires > now => {
+                    serde_json::from_str::<Vec<Message>>(&c.messages).unwrap_or_default()
+                }
+                Some(_) => Vec::new(),
+                None if full => return Err("too many conversations"),
+                None => Vec::new(),
+            };
+
+            messages.push(Message::new(Role::User, question));
+            messages.push(Message::new(Role::Assistant, answer));
+            trim(&mut messages, self.config.max_tokens);
+
+            let json = serde_json::to_string(&messages).map_err(|_| "unable to serialize")?;
+            let messages = ShmString::new(&json).ok_or("out of shared memory")?;
+
+            *conversation = Some(Conversation {
+                messages,
+                expires: now + self.config.ttl.as_secs(),
+            });
+            Ok(())
+        });
+
+        match updated {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => debug!("Not remembering the conversation: {e}"),
+            Err(e) => warn!("Not remembering the conversation: {e}"),
+        }
+    }
+
+    /// Forgets expired conversations.
+    pub fn expire(&self) {
+        let now = unix_time();
+        self.conversations.retain(|_, c| c.expires > now);
+    }
+}
+
+/// The key of a conversation by its tags: whichever tag the
+/// conversation started with. If it only starts now, both sides pick
+/// the same one.
+fn tags_key(call_id: &str, from: &str, to: Option<&str>, exists: impl Fn(&str) -> bool) -> String {
+    let from = format!("{call_id};{from}");
+    let Some(to) = to else {
+        return from;
+    };
+    let to = format!("{call_id};{to}");
+
+    match (exists(&from), exists(&to)) {
+        (true, _) => from,
+        (false, true) => to,
+        (false, false) => from.min(to),
+    }
+}
+
+/// Drops the oldest question and answer until the conversation fits
+/// `max_tokens`. The latest ones are always kept.
+fn trim(messages: &mut Vec<Message>, max_tokens: usize) {
+    let mut total: usize = messages.iter().map(estimate_tokens).sum();
+
+    while total > max_tokens && messages.len() > 2 {
+        total -= messages
+            .drain(..2)
+            .map(|m| estimate_tokens(&m))
+            .sum::<usize>();
+    }
+}
+
+// Without the model's tokenizer, this uses the common rule of thumb
+// of four characters per token, plus a little for the role.
+fn estimate_tokens(message: &Message) -> usize {
+    message.content.len() / 4 + 4
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{estimate_tokens, tags_key, trim, Key};
+    use crate::llm::{Message, Role};
+
+    fn conversation(pairs: usize, len: usize) -> Vec<Message> {
+        (0..pairs)
+            .flat_map(|i| {
+                let text = format!("{i}").repeat(len);
+                [
+                    Message::new(Role::User, &text),
+                    Message::new(Role::Assistant, &text),
+                ]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parses_keys() {
+        assert_eq!("call-id".parse(), Ok(Key::CallId));
+        assert_eq!("tags".parse(), Ok(Key::Tags));
+        assert!("from-tag".parse::<Key>().is_err());
+    }
+
+    #[test]
+    fn estimates_four_characters_per_token() {
+        assert_eq!(estimate_tokens(&Message::new(Role::User, "")), 4);
+        assert_eq!(estimate_tokens(&Message::new(Role::User, "abcdefg")), 5);
+        assert_eq!(estimate_tokens(&Message::new(Role::User, "abcdefgh")), 6);
+    }
+
+    #[test]
+    fn keeps_conversations_within_the_budget() {
+        // Every message is 40 / 4 + 4 = 14 tokens.
+        let mut messages = conversation(3, 40);
+        trim(&mut messages, 84);
+        assert_eq!(messages.len(), 6);
+    }
+
+    #[test]
+    fn drops_the_oldest_pairs() {
+        let mut messages = conversation(3, 40);
+        trim(&mut messages, 83);
+        assert_eq!(messages.len(), 4);
+        assert!(messages[0].content.starts_with('1'));
+        assert_eq!(messages[0].role, Role::User);
+
+        let mut messages = conversation(3, 40);
+        trim(&mut messages, 28);
+        assert_eq!(messages.len(), 2);
+        assert!(messages[0].content.starts_with('2'));
+    }
+
+    #[test]
+    fn keeps_the_latest_pair_over_the_budget() {
+        let mut messages = conversation(2, 400);
+        trim(&mut messages, 10);
+        assert_eq!(messages.len(), 2);
+        assert!(messages[1].content.starts_with('1'));
+    }
+
+    #[test]
+    fn uses_the_from_tag_without_a_to_tag() {
+        let key = tags_key("abc", "1", None, |_| panic!("Nothing to look up"));
+        assert_eq!(key, "abc;1");
+    }
+
+    #[test]
+    fn uses_the_tag_the_conversation_started_with() {
+        assert_eq!(
+            tags_key("abc", "1", Some("2"), |key| key == "abc;1"),
+            "abc;1"
+        );
+        assert_eq!(
+            tags_key("abc", "1", Some("2"), |key| key == "abc;2"),
+            "abc;2"
+        );
+        assert_eq!(
+            tags_key("abc", "2", Some("1"), |key| key == "abc;2"),
+            "abc;2"
+        );
+        assert_eq!(tags_key("abc", "1", Some("2"), |_| true), "abc;1");
+    }
+
+    #[test]
+    fn both_directions_start_with_the_same_key() {
+        let forward = tags_key("abc", "x", Some("y"), |_| false);
+        let backward = tags_key("abc", "y", Some("x"), |_| false);
+        assert_eq!(forward, "abc;x");
+        assert_eq!(forward, backward);
+    }
+}
@@ -1,29 +1,135 @@
-use std::fmt;
-use time::macros::format_description;
-use tracing_core::{Event, Subscriber};
+use crate::{
+    opensips_log::OpenSipsLayer,
+    writer::{Facility, FileConfig, NonBlocking, RotatingFile, Sink, Syslog},
+};
+use serde_json::{Map, Value};
+use std::{fmt, str::FromStr, sync::OnceLock};
+use time::{format_description::FormatItem, macros::format_description, UtcOffset};
+use tracing_core::{field::Visit, Event, Field, Subscriber};
 use tracing_subscriber::{
     fmt::{
-        format::{self, FormatEvent, FormatFields},
-        time::FormatTime,
-        time::UtcTime,
+        format::{self, FormatEvent, FormatFields, JsonFields},
+        time::{FormatTime, OffsetTime, Uptime},
+        writer::BoxMakeWriter,
         FmtContext, FormattedFields,
     },
+    layer::SubscriberExt,
     registry::LookupSpan,
+    reload,
+    util::SubscriberInitExt,
+    EnvFilter, Registry,
 };
 
+/// How the time of each log line is written.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum Timestamp {
+    /// MON DD HH:MM:SS, like OpenSIPS
+    #[default]
+    Syslog,
+    /// YYYY-MM-DDTHH:MM:SS.sss+HH:MM
+    Rfc3339Millis,
+    /// YYYY-MM-DDTHH:MM:SS.ssssss+HH:MM
+    Rfc3339Micros,
+    /// Seconds since the module was initialized
+    Uptime,
+}
+
+impl FromStr for Timestamp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "syslog" => Ok(Self::Syslog),
+            "rfc3339-ms" => Ok(Self::Rfc3339Millis),
+            "rfc3339-us" => Ok(Self::Rfc3339Micros),
+            "uptime" => Ok(Self::Uptime),
+            _ => Err(format!(
+                "Unknown log timestamp `{s}`, expected `syslog`, `rfc3339-ms`, `rfc3339-us` or `uptime`"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum Timezone {
+    #[default]
+    Utc,
+    Local,
+}
+
+impl FromStr for Timezone {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "utc" => Ok(Self::Utc),
+            "local" => Ok(Self::Local),
+            _ => Err(format!(
+                "Unknown log timezone `{s}`, expected `utc` or `local`"
+            )),
+        }
+    }
+}
+
+impl Timezone {
+    // The local offset can only be determined soundly while the
+    // process has a single thread, so it is looked up once at
+    // startup. Changes to daylight saving time need a restart.
+    fn offset(self) -> Result<UtcOffset, String> {
+        match self {
+            Self::Utc => Ok(UtcOffset::UTC),
+            Self::Local => UtcOffset::current_local_offset()
+                .map_err(|e| format!("Unable to determine the local timezone: {e}")),
+        }
+    }
+}
+
+type Formats = &'static [FormatItem<'static>];
+
+enum Timer {
+    Clock(OffsetTime<Formats>),
+    Uptime(Uptime),
+}
+
+impl Timer {
+    fn new(timestamp: Timestamp, timezone: Timezone) -> Self {
+        let offset = timezone.offset().unwrap_or(UtcOffset::UTC);
+
+        let format: Formats = match timestamp {
+            Timestamp::Syslog => format_description!(
+                "[month repr:short] [day] [hour repr:24]:[minute]:[second]"
+            ),
+            Timestamp::Rfc3339Millis => format_description!(
+                "[year]-[month]-[day]T[hour]:[minute]:[second].[subsecond digits:3][offset_hour sign:mandatory]:[offset_minute]"
+            ),
+            Timestamp::Rfc3339Micros => format_description!(
+                "[year]-[month]-[day]T[hour]:[minute]:[second].[subsecond digits:6][offset_hour sign:mandatory]:[offset_minute]"
+            ),
+            Timestamp::Uptime => return Self::Uptime(Uptime::default()),
+        };
+
+        Self::Clock(OffsetTime::new(offset, format))
+    }
+}
+
+impl FormatTime for Timer {
+    fn format_time(&self, w: &mut format::Writer<'_>) -> fmt::Result {
+        match self {
+            Self::Clock(t) => t.format_time(w),
+            Self::Uptime(t) => t.format_time(w),
+        }
+    }
+}
+
 /// Mirrors the OpenSips log format with small tweaks for Rust
 ///
 /// MON DD HH:MM:SS [PID] LEVEL:TARGET: <message>
 struct OpenSipsFormat {
-    timer: UtcTime<&'static [time::format_description::FormatItem<'static>]>,
+    timer: Timer,
 }
 
 impl OpenSipsFormat {
-    fn new() -> Self {
-        let timer = UtcTime::new(format_description!(
-            "[month repr:short] [day] [hour repr:24]:[minute]:[second]"
-        ));
-
+    fn new(timer: Timer) -> Self {
         Self { timer }
     }
 }
@@ -74,9 +180,282 @@ where
     }
 }
 
-pub fn install() {
-    tracing_subscriber::fmt()
-        .event_format(OpenSipsFormat::new())
-        .with_writer(std::io::stderr)
-        .init()
+/// One JSON object per line, for log pipelines.
+///
+/// {"timestamp":…,"level":…,"target":…,"pid":…,"rank":…,"call_id":…,
+///  "spans":[{"name":…,<fields>},…],"fields":{"message":…,<fields>}}
+///
+/// `call_id` is taken from the innermost span with a `call_id` field.
+struct OpenSipsJsonFormat {
+    timer: Timer,
+}
+
+impl OpenSipsJsonFormat {
+    fn new(timer: Timer) -> Self {
+        Self { timer }
+    }
+}
+
+impl<S> FormatEvent<S, JsonFields> for OpenSipsJsonFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, JsonFields>,
+        mut writer: format::Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut timestamp = String::new();
+        self.timer
+            .format_time(&mut format::Writer::new(&mut timestamp))?;
+
+        let metadata = event.metadata();
+        let mut call_id = Value::Null;
+        let mut spans = Vec::new();
+
+        if let Some(scope) = ctx.event_scope() {
+            for span in scope.from_root() {
+                // `JsonFields` stores the fields as a serialized object.
+                // A span without them is still worth its name, while a
+                // panic here would take down the SIP worker.
+                let ext = span.extensions();
+                let mut object = ext
+                    .get::<FormattedFields<JsonFields>>()
+                    .and_then(|fields| serde_json::from_str::<Map<String, Value>>(fields).ok())
+                    .unwrap_or_default();
+
+                if let Some(id) = object.get("call_id") {
+                    call_id = id.clone();
+                }
+
+                object.insert("name".into(), span.name().into());
+                spans.push(Value::Object(object));
+            }
+        }
+
+        let mut fields = JsonVisitor::default();
+        event.record(&mut fields);
+
+        let line = serde_json::json!({
+            "timestamp": timestamp,
+            "level": metadata.level().as_str(),
+            "target": metadata.target(),
+            "pid": std::process::id(),
+            "rank": opensips::current_process_no(),
+            "call_id": call_id,
+            "spans": spans,
+            "fields": fields.0,
+        });
+
+        writeln!(writer, "{line}")
+    }
+}
+
+#[derive(Default)]
+struct JsonVisitor(Map<String, Value>);
+
+impl Visit for JsonVisitor {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().into(), format!("{value:?}").into());
+    }
+}
+
+/// How log lines are formatted when we write them ourselves.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum Format {
+    #[default]
+    Text,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!(
+                "Unknown log format `{s}`, expected `text` or `json`"
+            )),
+        }
+    }
+}
+
+/// Where log events are sent.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum Target {
+    /// Through the OpenSIPS logging, following its log settings.
+    #[default]
+    OpenSips,
+    /// Directly to stderr, in the OpenSIPS log format.
+    Stderr,
+    /// To the local syslog daemon.
+    Syslog,
+    /// To a file, with rotation.
+    File,
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "opensips" => Ok(Self::OpenSips),
+            "stderr" => Ok(Self::Stderr),
+            "syslog" => Ok(Self::Syslog),
+            "file" => Ok(Self::File),
+            _ => Err(format!(
+                "Unknown log target `{s}`, expected `opensips`, `stderr`, `syslog` or `file`"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    pub target: Target,
+    pub format: Format,
+    /// `EnvFilter` directives, e.g. `rust_experiment::llm=debug`.
+    pub filter: Option<String>,
+    /// Only used with [`Target::Syslog`]
+    pub syslog_facility: Facility,
+    /// Required with [`Target::File`]
+    pub file: Option<FileConfig>,
+    pub timestamp: Timestamp,
+    pub timezone: Timezone,
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.target == Target::OpenSips && self.format != Format::Text {
+            return Err("The `opensips` log target formats its own lines".into());
+        }
+        if let Some(filter) = &self.filter {
+            parse_filter(filter)?;
+        }
+        self.timezone.offset()?;
+        if self.target == Target::File {
+            let file = self
+                .file
+                .as_ref()
+                .ok_or("The `file` log target needs a file")?;
+
+            // Report problems now rather than from the log thread.
+            RotatingFile::check(&file.path)
+                .map_err(|e| format!("Unable to log to `{}`: {e}", file.path.display()))?;
+        }
+        Ok(())
+    }
+
+    fn writer(&self) -> BoxMakeWriter {
+        match self.target {
+            Target::OpenSips | Target::Stderr => BoxMakeWriter::new(std::io::stderr),
+
+            Target::Syslog => {
+                let facility = self.syslog_facility;
+                BoxMakeWriter::new(NonBlocking::new(move || {
+                    Ok(Box::new(Syslog::open(facility)?) as Box<dyn Sink>)
+                }))
+            }
+
+            Target::File => {
+                let file = self.file.clone().unwrap_or_default();
+                BoxMakeWriter::new(NonBlocking::new(move || {
+                    Ok(Box::new(RotatingFile::open(file.clone())?) as Box<dyn Sink>)
+                }))
+            }
+        }
+    }
+
+    fn timer(&self) -> Timer {
+        Timer::new(self.timestamp, self.timezone)
+    }
+
+    fn filter(&self) -> EnvFilter {
+        // The OpenSIPS logging applies its own log level on top, so
+        // let everything through to it by default.
+        let default = match self.target {
+            Target::OpenSips => "trace",
+            Target::Stderr | Target::Syslog | Target::File => "info",
+        };
+
+        self.filter
+            .as_deref()
+            .and_then(|f| parse_filter(f).ok())
+            .unwrap_or_else(|| EnvFilter::new(default))
+    }
+}
+
+type FilterHandle = reload::Handle<EnvFilter, Registry>;
+
+// Each process gets its own copy when OpenSIPS forks, so changes
+// have to be made in every process.
+static FILTER: OnceLock<FilterHandle> = OnceLock::new();
+
+fn parse_filter(directives: &str) -> Result<EnvFilter, String> {
+    EnvFilter::builder()
+        .parse(directives)
+        .map_err(|e| format!("Invalid log filter `{directives}`: {e}"))
+}
+
+pub fn install(config: Config) {
+    let (filter, handle) = reload::Layer::new(config.filter());
+    let registry = tracing_subscriber::registry().with(filter);
+
+    match (config.target, config.format) {
+        (Target::OpenSips, _) => registry.with(OpenSipsLayer::new()).init(),
+
+        (_, Format::Text) => registry
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .event_format(OpenSipsFormat::new(config.timer()))
+                    .with_writer(config.writer()),
+            )
+            .init(),
+
+        (_, Format::Json) => registry
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .fmt_fields(JsonFields::new())
+                    .event_format(OpenSipsJsonFormat::new(config.timer()))
+                    .with_writer(config.writer()),
+            )
+            .init(),
+    }
+
+    let _ = FILTER.set(handle);
+}
+
+/// The filter directives currently in use by this process.
+pub fn filter() -> Option<String> {
+    let handle = FILTER.get()?;
+    handle.with_current(|f| f.to_string()).ok()
+}
+
+/// Replaces the filter of this process.
+pub fn set_filter(directives: &str) -> Result<(), String> {
+    let filter = parse_filter(directives)?;
+    let handle = FILTER.get().ok_or("The formatter is not installed")?;
+    handle
+        .reload(filter)
+        .map_err(|e| format!("Unable to replace the log filter: {e}"))
 }
@@ -0,0 +1,123 @@
+/// The headers of MESSAGE requests carrying text.
+pub const HEADERS: &str = "Content-Type: text/plain;charset=utf-8\r\n";
+
+/// The text of a MESSAGE request, which is either `text/plain` or
+/// wrapped in `message/cpim` (RFC 3862). Requests without a
+/// Content-Type are taken as text. Returns `Ok(None)` without text
+/// and the unsupported content type otherwise.
+pub fn text(msg: &mut opensips::sip_msg) -> Result<Option<&str>, String> {
+    let content_type = msg.content_type().map(media_type);
+
+    match content_type.as_deref() {
+        None | Some("text/plain") => Ok(msg.body().filter(|b| !b.trim().is_empty())),
+        Some("message/cpim") => {
+            let Some(body) = msg.body() else {
+                return Ok(None);
+            };
+            cpim_text(body)
+        }
+        Some(other) => Err(other.into()),
+    }
+}
+
+/// `text/plain;charset=utf-8` becomes `text/plain`.
+fn media_type(content_type: &str) -> String {
+    let media_type = content_type.split(';').next().unwrap_or_default();
+    media_type.trim().to_ascii_lowercase()
+}
+
+// A CPIM message has its own headers, then the MIME headers of what
+// it wraps, each followed by an empty line.
+fn cpim_text(body: &str) -> Result<Option<&str>, String> {
+    let Some((_, content)) = split_headers(body) else {
+        return Ok(None);
+    };
+    let Some((headers, text)) = split_headers(content) else {
+        return Ok(None);
+    };
+
+    let content_type = headers
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("Content-Type"))
+        .map(|(_, value)| media_type(value));
+
+    match content_type.as_deref() {
+        None | Some("text/plain") => Ok(Some(text).filter(|t| !t.trim().is_empty())),
+        Some(other) => Err(format!("message/cpim with {other}")),
+    }
+}
+
+fn split_headers(s: &str) -> Option<(&str, &str)> {
+    s.split_once("\r\n\r\n").or_else(|| s.split_once("\n\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cpim_text, media_type};
+
+    const CPIM: &str = "From: <sip:alice@example.com>\r\n\
+                        To: <sip:bob@example.com>\r\n\
+                        DateTime: 2000-12-13T13:40:00-08:00\r\n\
+                        \r\n\
+                        Content-Type: text/plain; charset=utf-8\r\n\
+                        \r\n\
+                        Hello, Bob!";
+
+    #[test]
+    fn drops_parameters_of_the_media_type() {
+        assert_eq!(media_type("text/plain"), "text/plain");
+        assert_eq!(media_type("text/plain;charset=utf-8"), "text/plain");
+        assert_eq!(media_type(" Text/Plain ; charset=\"UTF-8\""), "text/plain");
+        assert_eq!(media_type(""), "");
+    }
+
+    #[test]
+    fn unwraps_cpim() {
+        assert_eq!(cpim_text(CPIM), Ok(Some("Hello, Bob!")));
+    }
+
+    #[test]
+    fn unwraps_cpim_with_bare_newlines() {
+        let cpim = CPIM.replace("\r\n", "\n");
+        assert_eq!(cpim_text(&cpim), Ok(Some("Hello, Bob!")));
+    }
+
+    #[test]
+    fn takes_cpim_without_a_content_type_as_text() {
+        let cpim = "From: <sip:alice@example.com>\r\n\r\nSubject: Hi\r\n\r\nHello";
+        assert_eq!(cpim_text(cpim), Ok(Some("Hello")));
+    }
+
+    #[test]
+    fn finds_the_content_type_in_any_case() {
+        let cpim = CPIM.replace("Content-Type: text/plain", "content-type:TEXT/PLAIN");
+        assert_eq!(cpim_text(&cpim), Ok(Some("Hello, Bob!")));
+    }
+
+    #[test]
+    fn has_no_text_in_empty_or_truncated_cpim() {
+        let empty = CPIM.replace("Hello, Bob!", " \r\n");
+        assert_eq!(cpim_text(&empty), Ok(None));
+        assert_eq!(cpim_text("From: <sip:alice@example.com>"), Ok(None));
+        assert_eq!(
+            cpim_text("From: <sip:alice@example.com>\r\n\r\nContent-Type: text/plain"),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn refuses_other_types_in_cpim() {
+        let html = CPIM.replace("text/plain; charset=utf-8", "text/html");
+        assert_eq!(
+            cpim_text(&html),
+            Err("message/cpim with text/html".to_owned())
+        );
+
+        let image = CPIM.replace("text/plain; charset=utf-8", "image/png");
+        assert_eq!(
+            cpim_text(&image),
+            Err("message/cpim with image/png".to_owned())
+        );
+    }
+}
@@ -0,0 +1,231 @@
+use crate::llm;
+use std::sync::OnceLock;
+
+/// Tells which of a set of labeled intents a text is closest to, by
+/// comparing embeddings.
+#[derive(Debug)]
+pub struct Classifier {
+    examples: Vec<Example>,
+    // Embedded by the first classification in each process, as the
+    // LLM client only runs after the fork.
+    embeddings: OnceLock<Vec<llm::Embedding>>,
+}
+
+/// Something a caller with this intent might say.
+#[derive(Debug)]
+struct Example {
+    label: String,
+    text: String,
+}
+
+/// The intent closest to a text.
+#[derive(Debug, Copy, Clone)]
+pub struct Intent<'a> {
+    pub label: &'a str,
+    /// The cosine similarity, from -1 to 1.
+    pub score: f32,
+}
+
+impl Classifier {
+    /// Reads the intents from a file with one `label: example` per
+    /// line. A label may have several examples. Blank lines and
+    /// lines starting with `#` are skipped.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read the intents `{path}`: {e}"))?;
+        Self::parse(&contents).map_err(|e| format!("Invalid intents `{path}`: {e}"))
+    }
+
+    fn parse(contents: &str) -> Result<Self, String> {
+        let mut examples = Vec::new();
+
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let example = line
+                .split_once(':')
+                .map(|(label, text)| (label.trim(), text.trim()))
+                .filter(|(label, text)| !label.is_empty() && !text.is_empty());
+            let Some((label, text)) = example else {
+                return Err(format!("expected `label: example` on line {}", i + 1));
+            };
+
+            examples.push(Example {
+                label: label.into(),
+                text: text.into(),
+            });
+        }
+
+        if examples.is_empty() {
+            return Err("there are no intents".into());
+        }
+
+        Ok(Self {
+            examples,
+            embeddings: OnceLock::new(),
+        })
+    }
+
+    /// Returns the intent whose example is most similar to `text`.
+    /// Blocks until the embeddings have arrived; the examples are
+    /// embedded again until that succeeds once.
+    pub fn classify(&self, client: &llm::Client, text: &str) -> Result<Intent<'_>, llm::Error> {
+        let embeddings = match self.embeddings.get() {
+            Some(embeddings) => embeddings,
+            None => {
+                let texts = self.examples.iter().map(|e| e.text.clone()).collect();
+                let embeddings = client.embed(texts)?;
+                self.embeddings.get_or_init(|| embeddings)
+            }
+        };
+
+        // `embed` returns exactly one embedding per input.
+        let query = client.embed(vec![text.into()])?.swap_remove(0);
+
+        // `parse` refuses files without intents, so this only fails
+        // if that ever changes.
+        closest(&self.examples, embeddings, &query)
+            .ok_or_else(|| llm::Error::Decode("there are no intents to compare with".into()))
+    }
+}
+
+/// The intent of the example whose embedding is most similar to
+/// `query`, if there are any.
+fn closest<'a>(
+    examples: &'a [Example],
+    embeddings: &[llm::Embedding],
+    query: &[f32],
+) -> Option<Intent<'a>> {
+    examples
+        .iter()
+        .zip(embeddings)
+        .map(|(example, embedding)| Intent {
+            label: &example.label,
+            score: cosine_similarity(query, embedding),
+        })
+        .max_by(|a, b| a.score.total_cmp(&b.score))
+}
+
+// Embeddings of different lengths, or without any direction, aren't
+// similar at all.
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norms = norm(a) * norm(b);
+
+    if norms == 0.0 {
+        0.0
+    } else {
+        dot / norms
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{closest, cosine_similarity, Classifier};
+
+    fn examples(contents: &str) -> Vec<(String, String)> {
+        let classifier = Classifier::parse(contents).expect("The intents are valid");
+        classifier
+            .examples
+            .into_iter()
+            .map(|e| (e.label, e.text))
+            .collect()
+    }
+
+    #[test]
+    fn parses_examples() {
+        let parsed =
+            examples("billing: My invoice is wrong\nsupport:Nothing works \nbilling : Refund");
+        assert_eq!(
+            parsed,
+            [
+                ("billing".into(), "My invoice is wrong".into()),
+                ("support".into(), "Nothing works".into()),
+                ("billing".into(), "Refund".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_colons_in_examples() {
+        let parsed = examples("time: What time is it: now?");
+        assert_eq!(parsed, [("time".into(), "What time is it: now?".into())]);
+    }
+
+    #[test]
+    fn skips_blank_lines_and_comments() {
+        let parsed = examples("# Intents\n\n   \nsales: Buy\n  # indented\r\n");
+        assert_eq!(parsed, [("sales".into(), "Buy".into())]);
+    }
+
+    #[test]
+    fn refuses_lines_without_a_colon() {
+        let e = Classifier::parse("sales: Buy\n\nsupport").expect_err("Line 3 has no colon");
+        assert_eq!(e, "expected `label: example` on line 3");
+    }
+
+    #[test]
+    fn refuses_empty_labels_and_examples() {
+        assert!(Classifier::parse(": Buy").is_err());
+        assert!(Classifier::parse("sales:").is_err());
+        assert!(Classifier::parse("sales:   ").is_err());
+    }
+
+    #[test]
+    fn refuses_files_without_intents() {
+        assert_eq!(
+            Classifier::parse("").expect_err("There are no intents"),
+            "there are no intents"
+        );
+        assert!(Classifier::parse("# Nothing yet\n\n").is_err());
+    }
+
+    #[test]
+    fn compares_directions() {
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[2.0, 0.0]), 1.0);
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[0.0, 3.0]), 0.0);
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[-3.0, 0.0]), -1.0);
+    }
+
+    #[test]
+    fn mismatched_lengths_are_not_similar() {
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[1.0, 0.0, 0.0]), 0.0);
+        assert_eq!(cosine_similarity(&[], &[1.0]), 0.0);
+    }
+
+    #[test]
+    fn zero_vectors_are_not_similar() {
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 0.0]), 0.0);
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[0.0, 0.0]), 0.0);
+        assert_eq!(cosine_similarity(&[], &[]), 0.0);
+    }
+
+    #[test]
+    fn finds_the_closest_example() {
+        let classifier = Classifier::parse("north: Up\neast: Right\nsouth: Down")
+            .expect("The intents are valid");
+        let embeddings = [vec![0.0, 1.0], vec![1.0, 0.0], vec![0.0, -1.0]];
+
+        let intent =
+            closest(&classifier.examples, &embeddings, &[0.9, 0.1]).expect("There are intents");
+        assert_eq!(intent.label, "east");
+        assert!(intent.score > 0.9);
+
+        let intent =
+            closest(&classifier.examples, &embeddings, &[0.1, -0.9]).expect("There are intents");
+        assert_eq!(intent.label, "south");
+    }
+
+    #[test]
+    fn finds_nothing_without_examples() {
+        assert!(closest(&[], &[], &[1.0]).is_none());
+    }
+}
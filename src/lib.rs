@@ -1,25 +1,46 @@
-use opensips::{cstr_lit, module_parameter, StrExt};
+use opensips::{
+    cstr_lit,
+    header::{self, encode_value},
+    ipc, mi, module_parameter, pv,
+    shared::Shared,
+    timer, tm, StrExt,
+};
 use std::{
-    fs::Permissions,
     num::NonZeroI32,
     os::raw::{c_char, c_int},
-    os::unix::fs::PermissionsExt,
     ptr,
-    sync::RwLock,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        OnceLock, RwLock,
+    },
     thread,
     time::Duration,
 };
-use tokio::{
-    fs,
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
-    net::{UnixListener, UnixStream},
-    select,
-    sync::{broadcast, mpsc},
-};
-use tracing::{error, info, instrument};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::{error, info, instrument, warn};
 
-mod chatgpt;
+mod cache;
+mod conversation;
 mod formatter;
+mod im;
+mod intent;
+mod llm;
+mod opensips_log;
+mod quota;
+mod stream;
+mod template;
+mod tools;
+mod writer;
+
+// The pkg allocator isn't thread-safe, and this module allocates from
+// threads of its own: the LLM client, the dog fetcher and the log
+// writers. Only modules without any threads can use it.
+#[cfg(feature = "pkg-allocator")]
+compile_error!(
+    "The `pkg-allocator` feature can't be used, as the LLM client, the \
+     dog fetcher and the log writers allocate from their own threads"
+);
 
 // With a lot of FFI interaction, these safety comments are applicable
 // in multiple locations.
@@ -40,7 +61,7 @@ pub static exports: opensips::module_exports = opensips::module_exports {
     cmds: CMDS.as_ptr(),
     acmds: ptr::null(),
     params: PARAMS.as_ptr(),
-    stats: ptr::null(),
+    stats: STATS.as_ptr(),
     mi_cmds: MI_EXPORTS.as_ptr(),
     items: ptr::null(),
     trans: ptr::null(),
@@ -61,6 +82,18 @@ static DEPS: opensips::dep_export_concrete<1> = opensips::dep_export_concrete {
             mod_name: cstr_lit!(mut "signaling"),
             type_: opensips::DEP_ABORT,
         };
+        // Only needed for streaming
+        md[1] = opensips::module_dependency {
+            mod_type: opensips::module_type::MOD_TYPE_DEFAULT,
+            mod_name: cstr_lit!(mut "tm"),
+            type_: opensips::DEP_SILENT,
+        };
+        // Only needed for the `lookup_contacts` tool
+        md[2] = opensips::module_dependency {
+            mod_type: opensips::module_type::MOD_TYPE_DEFAULT,
+            mod_name: cstr_lit!(mut "usrloc"),
+            type_: opensips::DEP_SILENT,
+        };
         md
     },
     mpd: [opensips::modparam_dependency::NULL],
@@ -68,8 +101,21 @@ static DEPS: opensips::dep_export_concrete<1> = opensips::dep_export_concrete {
 
 opensips::commands! {
     #[name = "rust_experiment_reply"]
+    #[span]
     fn reply;
 
+    #[name = "rust_experiment_stream"]
+    #[span]
+    fn stream;
+
+    #[name = "rust_experiment_chat"]
+    #[span]
+    fn chat;
+
+    #[name = "rust_experiment_intent"]
+    #[span]
+    fn intent;
+
     #[name = "rust_experiment_test_str"]
     fn test_str;
 }
@@ -81,11 +127,290 @@ opensips::module_parameters! {
     #[name = "name"]
     static NAME: module_parameter::String;
 
+    // `openai` (the default), `ollama` or `mock`
+    #[name = "chatgpt-backend"]
+    static CHATGPT_BACKEND: module_parameter::String;
+
     #[name = "chatgpt-key"]
     static CHATGPT_KEY: module_parameter::String;
+
+    // The base URL of the server, e.g. `http://127.0.0.1:8080/v1`.
+    // ChatGPT is used when this, the key or the backend is set.
+    #[name = "chatgpt-url"]
+    static CHATGPT_URL: module_parameter::String;
+
+    #[name = "chatgpt-model"]
+    static CHATGPT_MODEL: module_parameter::String;
+
+    // A decimal, e.g. `0.7`
+    #[name = "chatgpt-temperature"]
+    static CHATGPT_TEMPERATURE: module_parameter::String;
+
+    #[name = "chatgpt-max-tokens"]
+    static CHATGPT_MAX_TOKENS: module_parameter::Integer;
+
+    // The model `rust_experiment_intent` embeds texts with; each
+    // backend has its own default
+    #[name = "chatgpt-embedding-model"]
+    static CHATGPT_EMBEDDING_MODEL: module_parameter::String;
+
+    // The intents `rust_experiment_intent` chooses from, one
+    // `label: example` per line, e.g. `billing: I have a question
+    // about my invoice`
+    #[name = "chatgpt-intents-file"]
+    static CHATGPT_INTENTS_FILE: module_parameter::String;
+
+    // The lowest score, in percent, which counts as a match. Unset,
+    // the closest intent always does.
+    #[name = "chatgpt-intent-threshold"]
+    static CHATGPT_INTENT_THRESHOLD: module_parameter::Integer;
+
+    // At most one of these two may be set
+    #[name = "chatgpt-system-prompt"]
+    static CHATGPT_SYSTEM_PROMPT: module_parameter::String;
+
+    #[name = "chatgpt-system-prompt-file"]
+    static CHATGPT_SYSTEM_PROMPT_FILE: module_parameter::String;
+
+    // Builds the question from each request instead of taking the
+    // X-ChatGPT header, e.g. `{from_display} writes: {body}`. The
+    // fields are `{query}`, `{from_display}`, `{from_user}`,
+    // `{ruri_user}`, `{user_agent}`, `{body}` and `{text}` (the body
+    // of a MESSAGE, unwrapped from CPIM); script variables like
+    // `$var(topic)` work too. `{{`, `}}` and `$$` stand for
+    // themselves, as does a `$` not followed by a letter or `(`.
+    #[name = "chatgpt-prompt-template"]
+    static CHATGPT_PROMPT_TEMPLATE: module_parameter::String;
+
+    // Like `chatgpt-prompt-template`, for the system prompt. Can't
+    // be combined with the system prompt or its file.
+    #[name = "chatgpt-system-template"]
+    static CHATGPT_SYSTEM_TEMPLATE: module_parameter::String;
+
+    // Milliseconds
+    #[name = "chatgpt-connect-timeout"]
+    static CHATGPT_CONNECT_TIMEOUT: module_parameter::Integer;
+
+    // Milliseconds, for each try; streamed answers may take as long
+    // between two pieces
+    #[name = "chatgpt-timeout"]
+    static CHATGPT_TIMEOUT: module_parameter::Integer;
+
+    // Retries after a 429 or 5xx response; -1 disables them
+    #[name = "chatgpt-retries"]
+    static CHATGPT_RETRIES: module_parameter::Integer;
+
+    // Milliseconds for a whole request, including its retries and
+    // the time it waited for its turn; defaults to 30 seconds
+    #[name = "chatgpt-deadline"]
+    static CHATGPT_DEADLINE: module_parameter::Integer;
+
+    // Consecutive failures before ChatGPT isn't asked for a while
+    #[name = "chatgpt-breaker-threshold"]
+    static CHATGPT_BREAKER_THRESHOLD: module_parameter::Integer;
+
+    // Seconds to not ask ChatGPT after too many failures
+    #[name = "chatgpt-breaker-cooldown"]
+    static CHATGPT_BREAKER_COOLDOWN: module_parameter::Integer;
+
+    // The X-ChatGPT value used when ChatGPT can't answer. Without
+    // it, the header is left out.
+    #[name = "chatgpt-fallback"]
+    static CHATGPT_FALLBACK: module_parameter::String;
+
+    // The X-ChatGPT header is cut to this many bytes; defaults to
+    // 1024, -1 for no limit
+    #[name = "chatgpt-header-max-length"]
+    static CHATGPT_HEADER_MAX_LENGTH: module_parameter::Integer;
+
+    // How text outside of ASCII is written in the headers we add:
+    // `utf-8` (the default), `rfc2047` or `percent`
+    #[name = "header-encoding"]
+    static HEADER_ENCODING: module_parameter::String;
+
+    // Streamed answers are sent in MESSAGEs of at least this many
+    // bytes, ending a sentence where possible; defaults to 80 when
+    // unset or not positive
+    #[name = "chatgpt-stream-chunk"]
+    static CHATGPT_STREAM_CHUNK: module_parameter::Integer;
+
+    // Roughly how many answers to questions without earlier messages
+    // to keep: the least recently used answer is only looked for among
+    // a few, so the cache may grow a little past this. Unset, every
+    // question is sent to ChatGPT.
+    #[name = "chatgpt-cache-soft-size"]
+    static CHATGPT_CACHE_SOFT_SIZE: module_parameter::Integer;
+
+    // Seconds to keep a cached answer; defaults to an hour
+    #[name = "chatgpt-cache-ttl"]
+    static CHATGPT_CACHE_TTL: module_parameter::Integer;
+
+    // Tokens each caller may use per day. Unset, usage is only
+    // counted.
+    #[name = "chatgpt-quota"]
+    static CHATGPT_QUOTA: module_parameter::Integer;
+
+    // `from-user` (the default) or `source`. Requests without a user
+    // in the From URI are charged to their `source` either way.
+    #[name = "chatgpt-quota-key"]
+    static CHATGPT_QUOTA_KEY: module_parameter::String;
+
+    // At most this many callers are counted; defaults to 10000
+    #[name = "chatgpt-quota-callers"]
+    static CHATGPT_QUOTA_CALLERS: module_parameter::Integer;
+
+    // The reply to callers over their quota; defaults to 403
+    #[name = "chatgpt-quota-code"]
+    static CHATGPT_QUOTA_CODE: module_parameter::Integer;
+
+    // Remembers earlier questions of the same `call-id` or dialog
+    // (`tags`). Unset, every question stands alone.
+    #[name = "chatgpt-memory"]
+    static CHATGPT_MEMORY: module_parameter::String;
+
+    // Seconds after the last question to forget a conversation
+    #[name = "chatgpt-memory-ttl"]
+    static CHATGPT_MEMORY_TTL: module_parameter::Integer;
+
+    // At most this many conversations are remembered
+    #[name = "chatgpt-memory-size"]
+    static CHATGPT_MEMORY_SIZE: module_parameter::Integer;
+
+    // Older messages are forgotten to stay under this many tokens
+    #[name = "chatgpt-memory-tokens"]
+    static CHATGPT_MEMORY_TOKENS: module_parameter::Integer;
+
+    // The tools ChatGPT may call, e.g. `lookup_contacts,get_statistic`
+    // or `route_to_extension`. Unset, it can't call any. Streamed
+    // answers never call tools.
+    #[name = "chatgpt-tools"]
+    static CHATGPT_TOOLS: module_parameter::String;
+
+    // The usrloc table `lookup_contacts` reads; defaults to `location`
+    #[name = "chatgpt-tools-domain"]
+    static CHATGPT_TOOLS_DOMAIN: module_parameter::String;
+
+    // Holds the address of record, or the domain of the addresses,
+    // `lookup_contacts` may read. Unset, callers may only look up
+    // their own From URI. A domain is refused when usrloc doesn't
+    // use domains, as it couldn't tell the users of others apart.
+    #[name = "chatgpt-tools-aor-variable"]
+    static CHATGPT_TOOLS_AOR_VARIABLE: module_parameter::String;
+
+    // Where `route_to_extension` leaves the extension for the script;
+    // defaults to `$avp(chatgpt_route)`
+    #[name = "chatgpt-route-variable"]
+    static CHATGPT_ROUTE_VARIABLE: module_parameter::String;
+
+    // Seconds between dogs. When set, the OpenSIPS timer decides
+    // when to fetch them instead of our own thread.
+    #[name = "dog-timer"]
+    static DOG_TIMER: module_parameter::Integer;
+
+    // `opensips` (the default), `stderr`, `syslog` or `file`
+    #[name = "log-target"]
+    static LOG_TARGET: module_parameter::String;
+
+    // `text` (the default) or `json`
+    #[name = "log-format"]
+    static LOG_FORMAT: module_parameter::String;
+
+    // `EnvFilter` directives, e.g. `info,rust_experiment::llm=debug`.
+    // Can be changed at runtime with `rust_experiment_log_filter`.
+    #[name = "log-filter"]
+    static LOG_FILTER: module_parameter::String;
+
+    // e.g. `local0`; defaults to `daemon`
+    #[name = "log-syslog-facility"]
+    static LOG_SYSLOG_FACILITY: module_parameter::String;
+
+    #[name = "log-file"]
+    static LOG_FILE: module_parameter::String;
+
+    // Rotate the log file once it reaches this many bytes
+    #[name = "log-file-size"]
+    static LOG_FILE_SIZE: module_parameter::Integer;
+
+    // Rotate the log file every this many seconds
+    #[name = "log-file-interval"]
+    static LOG_FILE_INTERVAL: module_parameter::Integer;
+
+    // How many rotated log files to keep; defaults to 5
+    #[name = "log-file-keep"]
+    static LOG_FILE_KEEP: module_parameter::Integer;
+
+    // `syslog` (the default), `rfc3339-ms`, `rfc3339-us` or `uptime`
+    #[name = "log-timestamp"]
+    static LOG_TIMESTAMP: module_parameter::String;
+
+    // `utc` (the default) or `local`
+    #[name = "log-timezone"]
+    static LOG_TIMEZONE: module_parameter::String;
+}
+
+opensips::statistics! {
+    #[name = "chatgpt_cache_hits"]
+    static CACHE_HITS: Statistic;
+
+    #[name = "chatgpt_cache_misses"]
+    static CACHE_MISSES: Statistic;
+
+    #[name = "chatgpt_prompt_tokens"]
+    static PROMPT_TOKENS: Statistic;
+
+    #[name = "chatgpt_completion_tokens"]
+    static COMPLETION_TOKENS: Statistic;
+
+    #[name = "chatgpt_total_tokens"]
+    static TOTAL_TOKENS: Statistic;
+
+    #[name = "chatgpt_quota_rejected"]
+    static QUOTA_REJECTED: Statistic;
+
+    #[name = "chatgpt_errors"]
+    static ERRORS: Statistic;
+
+    // The queue is full or ChatGPT has been failing
+    #[name = "chatgpt_errors_unavailable"]
+    static ERRORS_UNAVAILABLE: Statistic;
+
+    #[name = "chatgpt_errors_rate_limit"]
+    static ERRORS_RATE_LIMIT: Statistic;
+
+    #[name = "chatgpt_errors_quota"]
+    static ERRORS_QUOTA: Statistic;
+
+    #[name = "chatgpt_errors_auth"]
+    static ERRORS_AUTH: Statistic;
+
+    #[name = "chatgpt_errors_context_length"]
+    static ERRORS_CONTEXT_LENGTH: Statistic;
+
+    #[name = "chatgpt_errors_timeout"]
+    static ERRORS_TIMEOUT: Statistic;
+
+    // Network, HTTP, invalid responses and other API errors
+    #[name = "chatgpt_errors_backend"]
+    static ERRORS_BACKEND: Statistic;
+
+    #[name = "chatgpt_tool_calls"]
+    static TOOL_CALLS: Statistic;
 }
 
 const DEFAULT_NAME: &str = "This is the default name";
+const DEFAULT_LOG_FILE_KEEP: usize = 5;
+const DEFAULT_QUOTA_CODE: c_int = 403;
+const DEFAULT_STREAM_CHUNK: usize = 80;
+// Answers for which the model calls tools more often fail.
+const MAX_TOOL_ROUNDS: usize = 4;
+const DEFAULT_CHATGPT_HEADER_MAX_LEN: usize = 1024;
+
+// A slow random.dog shouldn't keep the next dog from being fetched.
+const DOG_CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+const DOG_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How often expired conversations are forgotten, at most.
+const CONVERSATION_EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
 
 static MI_EXPORTS: &[opensips::mi_export_t] = &[
     opensips::mi_export_t {
@@ -102,6 +427,92 @@ static MI_EXPORTS: &[opensips::mi_export_t] = &[
             recipes
         },
     },
+    opensips::mi_export_t {
+        name: cstr_lit!(mut "rust_experiment_log_filter"),
+        help: cstr_lit!(mut "Shows or replaces the log filter of the Rust code"),
+        flags: 0,
+        init_f: None,
+        recipes: {
+            let mut recipes = [opensips::mi_recipe_t::NULL; 48];
+            recipes[0] = opensips::mi_recipe_t {
+                cmd: Some(log_filter),
+                params: [ptr::null_mut(); 10],
+            };
+            recipes[1] = opensips::mi_recipe_t {
+                cmd: Some(log_filter),
+                params: {
+                    let mut params = [ptr::null_mut(); 10];
+                    params[0] = cstr_lit!(mut "filter");
+                    params
+                },
+            };
+            recipes
+        },
+    },
+    opensips::mi_export_t {
+        name: cstr_lit!(mut "rust_experiment_cache"),
+        help: cstr_lit!(mut "Shows the cached ChatGPT answers"),
+        flags: 0,
+        init_f: None,
+        recipes: {
+            let mut recipes = [opensips::mi_recipe_t::NULL; 48];
+            recipes[0] = opensips::mi_recipe_t {
+                cmd: Some(cache_list),
+                params: [ptr::null_mut(); 10],
+            };
+            recipes
+        },
+    },
+    opensips::mi_export_t {
+        name: cstr_lit!(mut "rust_experiment_cache_flush"),
+        help: cstr_lit!(mut "Forgets the cached ChatGPT answers"),
+        flags: 0,
+        init_f: None,
+        recipes: {
+            let mut recipes = [opensips::mi_recipe_t::NULL; 48];
+            recipes[0] = opensips::mi_recipe_t {
+                cmd: Some(cache_flush),
+                params: [ptr::null_mut(); 10],
+            };
+            recipes
+        },
+    },
+    opensips::mi_export_t {
+        name: cstr_lit!(mut "rust_experiment_usage"),
+        help: cstr_lit!(mut "Shows today's ChatGPT token usage per caller"),
+        flags: 0,
+        init_f: None,
+        recipes: {
+            let mut recipes = [opensips::mi_recipe_t::NULL; 48];
+            recipes[0] = opensips::mi_recipe_t {
+                cmd: Some(usage_list),
+                params: [ptr::null_mut(); 10],
+            };
+            recipes
+        },
+    },
+    opensips::mi_export_t {
+        name: cstr_lit!(mut "rust_experiment_usage_reset"),
+        help: cstr_lit!(mut "Forgets the ChatGPT token usage of a caller, or of everyone"),
+        flags: 0,
+        init_f: None,
+        recipes: {
+            let mut recipes = [opensips::mi_recipe_t::NULL; 48];
+            recipes[0] = opensips::mi_recipe_t {
+                cmd: Some(usage_reset),
+                params: [ptr::null_mut(); 10],
+            };
+            recipes[1] = opensips::mi_recipe_t {
+                cmd: Some(usage_reset),
+                params: {
+                    let mut params = [ptr::null_mut(); 10];
+                    params[0] = cstr_lit!(mut "caller");
+                    params
+                },
+            };
+            recipes
+        },
+    },
     opensips::mi_export_t::NULL,
 ];
 
@@ -109,308 +520,1273 @@ static MI_EXPORTS: &[opensips::mi_export_t] = &[
 struct GlobalState {
     count: u32,
     name: String,
-    counter: u32,
+    counter: Shared<AtomicU32>,
     dog_url: String,
     sigb: opensips::sig_binds,
-    parent_tx: Option<mpsc::Sender<Message>>,
-    chatgpt_key: Option<String>,
+    // Only loaded when the `tm` module is.
+    tm: Option<tm::Tm>,
+    stream_chunk: usize,
+    header_encoding: header::Encoding,
+    // `None` for no limit
+    chatgpt_header_max_len: Option<usize>,
+    chatgpt: Option<llm::Config>,
+    chatgpt_fallback: Option<String>,
+    cache: Option<cache::Cache>,
+    quotas: Option<quota::Quotas>,
+    quota_code: c_int,
+    conversations: Option<conversation::Store>,
+    // `None` when no tools are allowed
+    tools: Option<tools::Tools>,
+    prompt_template: Option<template::Template>,
+    system_template: Option<template::Template>,
+    intents: Option<intent::Classifier>,
+    // In percent, `None` when the closest intent always matches
+    intent_threshold: Option<i32>,
+    messages: ipc::Handler<Message>,
 }
 
 static STATE: RwLock<Option<GlobalState>> = RwLock::new(None);
 
-extern "C" fn init() -> c_int {
-    formatter::install();
-
-    // `#[instrument]` doesn't work until the formatter is installed.
-    let _span = tracing::info_span!("init").entered();
-
-    info!("called");
-
-    let count = COUNT.get_value().map_or(0, NonZeroI32::get);
-    let count = count.try_into().unwrap_or(0);
+// Started in each SIP worker, as threads don't survive the fork.
+static LLM: OnceLock<llm::Client> = OnceLock::new();
+
+// Started by the first tick of the dog timer, in the timer process.
+static DOG_FETCHER: OnceLock<Option<mpsc::Sender<()>>> = OnceLock::new();
+
+/// Parses an optional string parameter, using the default when it
+/// is not set.
+fn parse_param<T>(param: &module_parameter::String) -> Result<T, String>
+where
+    T: FromStr<Err = String> + Default,
+{
+    // SAFETY: It is the responsibility of OpenSips to set this value
+    // to a valid C string.
+    let value = unsafe { param.get_value() };
+    value.map_or(Ok(T::default()), str::parse)
+}
 
-    let name;
-    let chatgpt_key;
+fn log_config() -> Result<formatter::Config, String> {
+    let filter;
+    let file;
 
     // SAFETY: It is the responsibility of OpenSips to set these
     // values to valid C strings.
     unsafe {
-        name = NAME.get_value().unwrap_or(DEFAULT_NAME).into();
-        chatgpt_key = CHATGPT_KEY.get_value().map(Into::into);
+        filter = LOG_FILTER.get_value();
+        file = LOG_FILE.get_value();
     }
 
-    let Some(sigb) = opensips::load_sig_api() else { return -1 };
-
-    let mut state = STATE.write().expect("Lock poisoned");
-    assert!(state.is_none(), "Double-initializing the module");
+    let positive = |p: &module_parameter::Integer| {
+        p.get_value()
+            .and_then(|v| u64::try_from(v.get()).ok())
+            .filter(|&v| v > 0)
+    };
 
-    *state = Some(GlobalState {
-        count,
-        name,
-        counter: 0,
-        dog_url: "Dog URL not set yet".into(),
-        sigb,
-        parent_tx: None,
-        chatgpt_key,
+    let file = file.map(|path| writer::FileConfig {
+        path: path.into(),
+        max_size: positive(&LOG_FILE_SIZE),
+        interval: positive(&LOG_FILE_INTERVAL).map(Duration::from_secs),
+        keep: LOG_FILE_KEEP
+            .get_value()
+            .and_then(|v| v.get().try_into().ok())
+            .unwrap_or(DEFAULT_LOG_FILE_KEEP),
     });
 
-    // TODO: track the spawned thread
-    thread::spawn(run_server_loop);
-
-    0
+    let config = formatter::Config {
+        target: parse_param(&LOG_TARGET)?,
+        format: parse_param(&LOG_FORMAT)?,
+        filter: filter.map(Into::into),
+        syslog_facility: parse_param(&LOG_SYSLOG_FACILITY)?,
+        file,
+        timestamp: parse_param(&LOG_TIMESTAMP)?,
+        timezone: parse_param(&LOG_TIMEZONE)?,
+    };
+    config.validate()?;
+    Ok(config)
 }
 
-#[instrument]
-extern "C" fn init_child(rank: c_int) -> c_int {
-    info!("called");
-
-    let (tx, rx) = mpsc::channel(3);
+/// Unset values keep the defaults. Returns `None` when ChatGPT
+/// isn't configured.
+fn chatgpt_config() -> Result<Option<llm::Config>, String> {
+    let mut config = llm::Config::default();
 
-    // TODO: track the spawned thread
-    thread::spawn(|| run_worker_loop(rx));
+    let backend;
+    let api_key;
+    let url;
+    let model;
+    let embedding_model;
+    let temperature;
+    let system_prompt;
+    let system_prompt_file;
 
-    let mut state = STATE.write().expect("Lock poisoned");
-    let mut state = state.as_mut().expect("State uninitialized");
-    state.parent_tx = Some(tx);
-
-    0
-}
+    // SAFETY: It is the responsibility of OpenSips to set these
+    // values to valid C strings.
+    unsafe {
+        backend = CHATGPT_BACKEND.get_value();
+        api_key = CHATGPT_KEY.get_value();
+        url = CHATGPT_URL.get_value();
+        model = CHATGPT_MODEL.get_value();
+        embedding_model = CHATGPT_EMBEDDING_MODEL.get_value();
+        temperature = CHATGPT_TEMPERATURE.get_value();
+        system_prompt = CHATGPT_SYSTEM_PROMPT.get_value();
+        system_prompt_file = CHATGPT_SYSTEM_PROMPT_FILE.get_value();
+    }
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
-enum Message {
-    IncrementCounter,
-    NewDog(String),
-}
+    if backend.is_none() && api_key.is_none() && url.is_none() {
+        return Ok(None);
+    }
 
-const CONTROL_SOCKET: &str = "/usr/local/etc/opensips/rust_experiment";
+    if let Some(backend) = backend {
+        config.backend = backend.parse()?;
+    }
+    config.api_key = api_key.map(Into::into);
+    config.url = url.map(Into::into);
+    config.model = model.map(Into::into);
+    config.embedding_model = embedding_model.map(Into::into);
+    if let Some(temperature) = temperature {
+        let temperature = temperature
+            .parse()
+            .map_err(|e| format!("Invalid ChatGPT temperature `{temperature}`: {e}"))?;
+        config.temperature = Some(temperature);
+    }
+    config.max_tokens = CHATGPT_MAX_TOKENS
+        .get_value()
+        .and_then(|v| v.get().try_into().ok());
 
-#[tokio::main(flavor = "current_thread")]
-#[instrument]
-async fn run_server_loop() {
-    info!("called");
+    match (system_prompt, system_prompt_file) {
+        (Some(_), Some(_)) => {
+            return Err("Only one of the ChatGPT system prompt and its file may be set".into())
+        }
+        (Some(prompt), None) => config.system_prompt = prompt.into(),
+        (None, Some(path)) => {
+            let prompt = std::fs::read_to_string(path)
+                .map_err(|e| format!("Unable to read the ChatGPT system prompt `{path}`: {e}"))?;
+            config.system_prompt = prompt.trim().into();
+        }
+        (None, None) => {}
+    }
 
-    // We don't care if deleting fails as binding will tell us.
-    let _ = fs::remove_file(CONTROL_SOCKET).await;
-    let listener = UnixListener::bind(CONTROL_SOCKET).unwrap();
-    // TODO: Find minimal appropriate permissions
-    fs::set_permissions(CONTROL_SOCKET, Permissions::from_mode(0o777))
-        .await
-        .unwrap();
+    let get = |p: &module_parameter::Integer| p.get_value().map(NonZeroI32::get);
+    let positive = |p| get(p).and_then(|v| u64::try_from(v).ok());
 
-    let (tx, mut rx) = mpsc::channel(3);
-    let (b_tx, b_rx) = broadcast::channel(3);
+    if let Some(ms) = positive(&CHATGPT_CONNECT_TIMEOUT) {
+        config.connect_timeout = Duration::from_millis(ms);
+    }
+    if let Some(ms) = positive(&CHATGPT_TIMEOUT) {
+        config.timeout = Duration::from_millis(ms);
+    }
+    if let Some(retries) = get(&CHATGPT_RETRIES) {
+        config.retries = retries.try_into().unwrap_or(0);
+    }
+    if let Some(ms) = positive(&CHATGPT_DEADLINE) {
+        config.deadline = Duration::from_millis(ms);
+    }
+    if let Some(threshold) = positive(&CHATGPT_BREAKER_THRESHOLD) {
+        config.breaker_threshold = threshold.try_into().unwrap_or(u32::MAX);
+    }
+    if let Some(secs) = positive(&CHATGPT_BREAKER_COOLDOWN) {
+        config.breaker_cooldown = Duration::from_secs(secs);
+    }
 
-    tokio::spawn(run_api_task(tx.clone()));
+    Ok(Some(config))
+}
 
-    loop {
-        select! {
-            connection = listener.accept() => {
-                match connection {
-                    Ok((stream, _addr)) => {
-                        info!("worker connected");
-
-                        let tx = tx.clone();
-                        let b_rx = b_rx.resubscribe();
-
-                        // TODO: track the spawned task
-                        tokio::spawn(run_server_child(stream, tx, b_rx));
-                    }
-                    Err(err) => {
-                        error!("{err} / {err:?}");
-                        break;
-                    }
-                }
-            }
+/// Returns `None` when answers aren't cached.
+fn cache_config() -> Option<cache::Config> {
+    let positive =
+        |p: &module_parameter::Integer| p.get_value().and_then(|v| u64::try_from(v.get()).ok());
 
-            Some(msg) = rx.recv() => {
-                b_tx.send(msg).unwrap();
-            }
-        }
+    let capacity = positive(&CHATGPT_CACHE_SOFT_SIZE)?;
+    let mut config = cache::Config {
+        soft_capacity: capacity.try_into().unwrap_or(usize::MAX),
+        ..Default::default()
+    };
+    if let Some(secs) = positive(&CHATGPT_CACHE_TTL) {
+        config.ttl = Duration::from_secs(secs);
     }
-}
 
-#[derive(Debug, serde::Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct RandomDogResponse {
-    // file_size_bytes: u64,
-    url: String,
+    Some(config)
 }
 
-async fn run_api_task(tx: mpsc::Sender<Message>) {
-    let mut interval = tokio::time::interval(Duration::from_secs(10));
-
-    // Burn the first tick as we want to wait a bit before making the first request
-    interval.tick().await;
-
-    loop {
-        interval.tick().await;
-        let random_dog = reqwest::get("https://random.dog/woof.json")
-            .await
-            .expect("Could not make HTTP request")
-            .json::<RandomDogResponse>()
-            .await
-            .expect("Could not deserialize API response");
+fn quota_config() -> Result<quota::Config, String> {
+    let positive =
+        |p: &module_parameter::Integer| p.get_value().and_then(|v| u64::try_from(v.get()).ok());
 
-        tx.send(Message::NewDog(random_dog.url)).await.unwrap();
+    let mut config = quota::Config {
+        key: parse_param(&CHATGPT_QUOTA_KEY)?,
+        daily_limit: positive(&CHATGPT_QUOTA),
+        ..Default::default()
+    };
+    if let Some(callers) = positive(&CHATGPT_QUOTA_CALLERS) {
+        config.max_callers = callers.try_into().unwrap_or(usize::MAX);
     }
+
+    Ok(config)
 }
 
-#[instrument(skip_all)]
-async fn run_server_child(
-    stream: UnixStream,
-    tx: mpsc::Sender<Message>,
-    mut b_rx: broadcast::Receiver<Message>,
-) {
-    info!("called");
+/// The templates of the question and of the system prompt, each
+/// `None` when not set.
+fn templates() -> Result<(Option<template::Template>, Option<template::Template>), String> {
+    let prompt;
+    let system;
+    let system_prompt_set;
 
-    let mut stream = BufReader::new(stream);
-    let mut data = String::with_capacity(1024);
+    // SAFETY: It is the responsibility of OpenSips to set these
+    // values to valid C strings.
+    unsafe {
+        prompt = CHATGPT_PROMPT_TEMPLATE.get_value();
+        system = CHATGPT_SYSTEM_TEMPLATE.get_value();
+        system_prompt_set = CHATGPT_SYSTEM_PROMPT.get_value().is_some()
+            || CHATGPT_SYSTEM_PROMPT_FILE.get_value().is_some();
+    }
 
-    loop {
-        data.clear();
-
-        select! {
-            Ok(msg) = b_rx.recv() => {
-                info!("Got data from broadcast, sending to worker");
-                let msg = serde_json::to_vec(&msg).unwrap();
-                stream.write_all(&msg).await.unwrap();
-                stream.write_all(&[b'\n']).await.unwrap();
-                stream.flush().await.unwrap();
-            }
+    if system.is_some() && system_prompt_set {
+        return Err(
+            "Only one of the ChatGPT system prompt, its file and its template may be set".into(),
+        );
+    }
 
-            Ok(n_bytes) = stream.read_line(&mut data) => {
-                if n_bytes == 0 { break }
+    let prompt = prompt.map(template::Template::parse).transpose()?;
+    let system = system.map(template::Template::parse).transpose()?;
+    Ok((prompt, system))
+}
 
-                info!("Got data from worker, broadcasting...");
-                let msg = serde_json::from_str(&data).expect("Data was not valid JSON");
+fn tools_config() -> tools::Config {
+    let mut config = tools::Config::default();
 
-                tx.send(msg).await.unwrap();
-            }
+    // SAFETY: It is the responsibility of OpenSips to set these
+    // values to valid C strings.
+    unsafe {
+        if let Some(allowed) = CHATGPT_TOOLS.get_value() {
+            config.allowed = allowed
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(Into::into)
+                .collect();
+        }
+        if let Some(domain) = CHATGPT_TOOLS_DOMAIN.get_value() {
+            config.domain = domain.into();
+        }
+        if let Some(variable) = CHATGPT_TOOLS_AOR_VARIABLE.get_value() {
+            config.aor_variable = Some(variable.into());
+        }
+        if let Some(variable) = CHATGPT_ROUTE_VARIABLE.get_value() {
+            config.route_variable = variable.into();
         }
     }
-}
 
-#[tokio::main(flavor = "current_thread")]
-#[instrument(skip_all)]
-async fn run_worker_loop(mut rx: mpsc::Receiver<Message>) {
-    info!("called");
+    config
+}
 
-    let stream = UnixStream::connect(CONTROL_SOCKET).await.unwrap();
-    let mut stream = BufReader::new(stream);
+/// Returns `None` when conversations aren't remembered.
+fn conversation_config() -> Result<Option<conversation::Config>, String> {
+    // SAFETY: It is the responsibility of OpenSips to set this value
+    // to a valid C string.
+    let Some(key) = (unsafe { CHATGPT_MEMORY.get_value() }) else {
+        return Ok(None);
+    };
 
-    let mut data = String::with_capacity(1024);
+    let mut config = conversation::Config {
+        key: key.parse()?,
+        ..Default::default()
+    };
 
-    loop {
-        data.clear();
-
-        select! {
-            Some(msg) = rx.recv() => {
-                info!("Got data from channel, sending to parent...");
-                let msg = serde_json::to_vec(&msg).unwrap();
-                stream.write_all(&msg).await.unwrap();
-                stream.write_all(&[b'\n']).await.unwrap();
-                stream.flush().await.unwrap();
-            }
+    let positive =
+        |p: &module_parameter::Integer| p.get_value().and_then(|v| u64::try_from(v.get()).ok());
 
-            Ok(n_bytes) = stream.read_line(&mut data) => {
-                if n_bytes == 0 { break }
-
-                info!("Received data from parent...");
-
-                let msg = serde_json::from_str(&data).expect("Data was not valid JSON");
-                match msg {
-                    Message::IncrementCounter => {
-                        let mut state = STATE.write().expect("Lock poisoned");
-                        let mut state = state.as_mut().expect("State uninitialized");
-                        state.counter = state.counter.wrapping_add(1);
-                        // unlock via drop
-                    }
-                    Message::NewDog(url) => {
-                        let mut state = STATE.write().expect("Lock poisoned");
-                        let mut state = state.as_mut().expect("State uninitialized");
-                        state.dog_url = url;
-                    }
-                }
-            }
-        }
+    if let Some(secs) = positive(&CHATGPT_MEMORY_TTL) {
+        config.ttl = Duration::from_secs(secs);
+    }
+    if let Some(size) = positive(&CHATGPT_MEMORY_SIZE) {
+        config.max_conversations = size.try_into().unwrap_or(usize::MAX);
+    }
+    if let Some(tokens) = positive(&CHATGPT_MEMORY_TOKENS) {
+        config.max_tokens = tokens.try_into().unwrap_or(usize::MAX);
     }
+
+    Ok(Some(config))
 }
 
-#[instrument(skip_all)]
-fn reply(msg: &mut opensips::sip_msg) -> i32 {
-    info!("called");
+extern "C" fn init() -> c_int {
+    let log_config = log_config();
 
-    let state = STATE.read().expect("Lock poisoned");
-    let state = state.as_ref().expect("Not initialized");
+    formatter::install(log_config.clone().unwrap_or_default());
 
-    let do_chatgpt = || {
-        let key = state.chatgpt_key.as_deref()?;
+    // `#[instrument]` doesn't work until the formatter is installed.
+    let _span = tracing::info_span!("init").entered();
 
-        let query = msg
-            .header_iter()
-            .map(|h| (h.name.as_str(), h.body.as_str()))
-            .find(|(n, _b)| n.eq_ignore_ascii_case("X-ChatGPT"))
-            .map(|(_h, b)| b)?;
+    info!("called");
 
-        Some(chatgpt::do_one(key, query))
-    };
+    if let Err(e) = log_config {
+        error!("{e}");
+        return -1;
+    }
 
-    let chatgpt_response = do_chatgpt();
+    let count = COUNT.get_value().map_or(0, NonZeroI32::get);
+    let count = count.try_into().unwrap_or(0);
 
-    let mut add_header = |name, value| {
-        let mut header = String::from(name);
-        header.push_str(": ");
-        header.push_str(value);
-        header.push('\n');
+    let dog_timer = DOG_TIMER.get_value().map_or(0, NonZeroI32::get);
+    let dog_timer = dog_timer.try_into().unwrap_or(0);
 
-        // SAFETY: `msg` is passed from OpenSIPS, the header is
-        // managed by Rust's `String`, and we instruct OpenSIPS to not
-        // free our memory.
-        let lump = unsafe {
-            opensips::add_lump_rpl(
-                msg,
-                header.as_mut_ptr(),
-                header.len().try_into().unwrap(),
-                opensips::LUMP_RPL_HDR | opensips::LUMP_RPL_NOFREE,
-            )
-        };
+    let name;
+    let chatgpt_fallback;
 
-        !lump.is_null()
+    // SAFETY: It is the responsibility of OpenSips to set these
+    // values to valid C strings.
+    unsafe {
+        name = NAME.get_value().unwrap_or(DEFAULT_NAME).into();
+        chatgpt_fallback = CHATGPT_FALLBACK.get_value().map(Into::into);
+    }
+
+    let mut chatgpt = match chatgpt_config() {
+        Ok(chatgpt) => chatgpt,
+        Err(e) => {
+            error!("{e}");
+            return -1;
+        }
     };
 
-    let rust_header_value = format!(
-        "{} / {} / {} / {}",
-        state.name, state.count, state.counter, state.dog_url
-    );
-    if !add_header("X-Rust", &rust_header_value) {
-        error!("Unable to add the X-Rust header");
-        return -1;
+    // Loads the APIs and parses the variables the tools use, which
+    // has to happen before the workers fork.
+    let tools = match tools::Tools::new(tools_config()) {
+        Ok(tools) => Some(tools).filter(|t| !t.is_empty() && chatgpt.is_some()),
+        Err(e) => {
+            error!("{e}");
+            return -1;
+        }
+    };
+    if let (Some(config), Some(tools)) = (&mut chatgpt, &tools) {
+        config.tools = tools.specs();
     }
 
-    if let Some(chatgpt_header_value) = chatgpt_response {
-        if !add_header("X-ChatGPT", &chatgpt_header_value) {
-            error!("Unable to add the X-ChatGPT header");
+    let conversation_config = match conversation_config() {
+        Ok(config) => config,
+        Err(e) => {
+            error!("{e}");
             return -1;
         }
-    }
+    };
 
-    let reply = state.sigb.reply.expect("reply function pointer missing");
+    // Script variables in templates are parsed into private memory,
+    // which has to happen before the workers fork.
+    let (prompt_template, system_template) = match templates() {
+        Ok(templates) => templates,
+        Err(e) => {
+            error!("{e}");
+            return -1;
+        }
+    };
 
-    let code = 200;
-    let reason = &"OK".as_opensips_str();
-    let tag = ptr::null_mut();
+    // SAFETY: It is the responsibility of OpenSips to set this value
+    // to a valid C string.
+    let intents_file = unsafe { CHATGPT_INTENTS_FILE.get_value() };
+    let intents = match intents_file.map(intent::Classifier::load).transpose() {
+        Ok(intents) => intents,
+        Err(e) => {
+            error!("{e}");
+            return -1;
+        }
+    };
+    let intent_threshold = CHATGPT_INTENT_THRESHOLD.get_value().map(NonZeroI32::get);
 
-    // SAFETY: `msg` comes from OpenSIPS, `code` is an integer,
+    let quota_config = match quota_config() {
+        Ok(config) => config,
+        Err(e) => {
+            error!("{e}");
+            return -1;
+        }
+    };
+
+    let quota_code = CHATGPT_QUOTA_CODE
+        .get_value()
+        .map_or(DEFAULT_QUOTA_CODE, NonZeroI32::get);
+    if !(400..700).contains(&quota_code) {
+        error!("The ChatGPT quota code {quota_code} isn't an error code");
+        return -1;
+    }
+
+    let stream_chunk = CHATGPT_STREAM_CHUNK
+        .get_value()
+        .and_then(|v| usize::try_from(v.get()).ok())
+        .unwrap_or(DEFAULT_STREAM_CHUNK);
+
+    let header_encoding = match parse_param(&HEADER_ENCODING) {
+        Ok(encoding) => encoding,
+        Err(e) => {
+            error!("{e}");
+            return -1;
+        }
+    };
+
+    let chatgpt_header_max_len = match CHATGPT_HEADER_MAX_LENGTH.get_value() {
+        None => Some(DEFAULT_CHATGPT_HEADER_MAX_LEN),
+        Some(len) => usize::try_from(len.get()).ok(),
+    };
+
+    let Some(sigb) = opensips::load_sig_api() else { return -1 };
+
+    let tm = tm::load_tm_api();
+    if tm.is_none() {
+        info!("The tm module isn't loaded, answers can't be streamed");
+    }
+
+    // Shared memory has to be allocated before the workers fork.
+    let Some(counter) = Shared::new(AtomicU32::new(0)) else {
+        error!("Unable to allocate the shared counter");
+        return -1;
+    };
+
+    let cache = match cache_config().filter(|_| chatgpt.is_some()) {
+        Some(config) => {
+            let Some(cache) = cache::Cache::new(config) else {
+                error!("Unable to allocate the ChatGPT cache");
+                return -1;
+            };
+            Some(cache)
+        }
+        None => None,
+    };
+
+    let quotas = match chatgpt.as_ref().map(|_| quota::Quotas::new(quota_config)) {
+        Some(None) => {
+            error!("Unable to allocate the ChatGPT quotas");
+            return -1;
+        }
+        quotas => quotas.flatten(),
+    };
+
+    let conversations = match conversation_config {
+        Some(config) => {
+            let interval = config.ttl.min(CONVERSATION_EXPIRY_INTERVAL);
+            let Some(store) = conversation::Store::new(config) else {
+                error!("Unable to allocate the conversations");
+                return -1;
+            };
+
+            let expiring = store.clone();
+            let registered = timer::register_timer(
+                "rust_experiment_conversations",
+                interval.max(Duration::from_secs(1)),
+                timer::OnDelay::Skip,
+                move || expiring.expire(),
+            );
+            if let Err(e) = registered {
+                error!("Unable to register the conversation timer: {e}");
+                return -1;
+            }
+
+            Some(store)
+        }
+        None => None,
+    };
+
+    // IPC handlers have to be registered before the workers fork.
+    let Some(messages) = ipc::register_handler::<Message>("rust_experiment_message") else {
+        error!("Unable to register the IPC handler");
+        return -1;
+    };
+
+    let mut state = STATE.write().expect("Lock poisoned");
+    assert!(state.is_none(), "Double-initializing the module");
+
+    *state = Some(GlobalState {
+        count,
+        name,
+        counter,
+        dog_url: "Dog URL not set yet".into(),
+        sigb,
+        tm,
+        stream_chunk,
+        header_encoding,
+        chatgpt_header_max_len,
+        chatgpt,
+        chatgpt_fallback,
+        cache,
+        quotas,
+        quota_code,
+        conversations,
+        tools,
+        prompt_template,
+        system_template,
+        intents,
+        intent_threshold,
+        messages,
+    });
+
+    if dog_timer == 0 {
+        // TODO: track the spawned thread
+        thread::spawn(move || run_api_loop(messages));
+    } else {
+        let interval = Duration::from_secs(dog_timer);
+        let registered = timer::register_timer(
+            "rust_experiment_dog",
+            interval,
+            timer::OnDelay::Skip,
+            move || run_api_timer(messages),
+        );
+
+        if let Err(e) = registered {
+            error!("Unable to register the dog timer: {e}");
+            return -1;
+        }
+    }
+
+    0
+}
+
+#[instrument]
+extern "C" fn init_child(rank: c_int) -> c_int {
+    info!("called");
+
+    if !opensips::is_worker_proc(rank) {
+        return 0;
+    }
+
+    let state = STATE.read().expect("Lock poisoned");
+    let state = state.as_ref().expect("Not initialized");
+
+    if let Some(config) = &state.chatgpt {
+        match llm::Client::start(config) {
+            Ok(client) => {
+                let _ = LLM.set(client);
+            }
+            Err(e) => {
+                error!("{e}");
+                return -1;
+            }
+        }
+    }
+
+    0
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+enum Message {
+    NewDog(String),
+    LogFilter(String),
+}
+
+impl ipc::Job for Message {
+    #[instrument(skip_all)]
+    fn run(self, sender: c_int) {
+        info!("Received data from process {sender}...");
+
+        match self {
+            Message::NewDog(url) => {
+                let mut state = STATE.write().expect("Lock poisoned");
+                let state = state.as_mut().expect("State uninitialized");
+                state.dog_url = url;
+            }
+
+            Message::LogFilter(directives) => {
+                if let Err(e) = formatter::set_filter(&directives) {
+                    error!("{e}");
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RandomDogResponse {
+    // file_size_bytes: u64,
+    url: String,
+}
+
+#[tokio::main(flavor = "current_thread")]
+#[instrument(skip_all)]
+async fn run_api_loop(messages: ipc::Handler<Message>) {
+    info!("called");
+
+    let Some(client) = dog_client() else {
+        return;
+    };
+    let mut interval = tokio::time::interval(Duration::from_secs(10));
+
+    // Burn the first tick as we want to wait a bit before making the first request
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+        if let Some(url) = fetch_dog(&client).await {
+            send_dog(messages, url);
+        }
+    }
+}
+
+/// Runs in the timer process, which all modules share, so the dog is
+/// fetched by a thread of its own instead of holding up the timers.
+#[instrument(skip_all)]
+fn run_api_timer(messages: ipc::Handler<Message>) {
+    info!("called");
+
+    let Some(fetcher) = DOG_FETCHER.get_or_init(|| start_dog_fetcher(messages)) else {
+        return;
+    };
+
+    match fetcher.try_send(()) {
+        Ok(()) => {}
+        Err(TrySendError::Full(())) => info!("Still fetching the previous dog"),
+        Err(TrySendError::Closed(())) => error!("The dog fetcher has stopped"),
+    }
+}
+
+fn start_dog_fetcher(messages: ipc::Handler<Message>) -> Option<mpsc::Sender<()>> {
+    let (tx, rx) = mpsc::channel(1);
+
+    let started = thread::Builder::new()
+        .name("dog".into())
+        .spawn(move || fetch_dogs(messages, rx));
+    if let Err(e) = started {
+        error!("Unable to start the dog fetcher: {e}");
+        return None;
+    }
+
+    Some(tx)
+}
+
+#[tokio::main(flavor = "current_thread")]
+#[instrument(skip_all)]
+async fn fetch_dogs(messages: ipc::Handler<Message>, mut ticks: mpsc::Receiver<()>) {
+    let Some(client) = dog_client() else {
+        return;
+    };
+
+    while ticks.recv().await.is_some() {
+        if let Some(url) = fetch_dog(&client).await {
+            send_dog(messages, url);
+        }
+    }
+}
+
+fn dog_client() -> Option<reqwest::Client> {
+    let client = reqwest::Client::builder()
+        .connect_timeout(DOG_CONNECT_TIMEOUT)
+        .timeout(DOG_TIMEOUT)
+        .build();
+
+    match client {
+        Ok(client) => Some(client),
+        Err(e) => {
+            error!("Could not create reqwest Client: {e}");
+            None
+        }
+    }
+}
+
+async fn fetch_dog(client: &reqwest::Client) -> Option<String> {
+    let response = client.get("https://random.dog/woof.json").send().await;
+    let random_dog = match response {
+        Ok(response) => response.json::<RandomDogResponse>().await,
+        Err(e) => Err(e),
+    };
+
+    match random_dog {
+        Ok(random_dog) => Some(random_dog.url),
+        Err(e) => {
+            warn!("Unable to fetch a dog: {e}");
+            None
+        }
+    }
+}
+
+fn send_dog(messages: ipc::Handler<Message>, url: String) {
+    match messages.broadcast(&Message::NewDog(url)) {
+        Ok(n) => info!("Sent the new dog to {n} processes"),
+        Err(e) => error!("Unable to send the new dog: {e}"),
+    }
+}
+
+#[instrument(skip_all)]
+fn reply(msg: &mut opensips::sip_msg) -> i32 {
+    info!("called");
+
+    let state = STATE.read().expect("Lock poisoned");
+    let state = state.as_ref().expect("Not initialized");
+
+    let conversation = state
+        .conversations
+        .as_ref()
+        .and_then(|store| Some((store, store.key(msg)?)));
+
+    let caller = state
+        .quotas
+        .as_ref()
+        .and_then(|quotas| Some((quotas, quotas.caller(msg)?)));
+
+    let prompt = prompt(state, msg, chatgpt_query(msg).map(String::from));
+
+    let mut over_quota = false;
+    let mut failure = None;
+
+    let mut do_chatgpt = || {
+        let client = LLM.get()?;
+        let prompt = prompt.as_ref()?;
+
+        if is_over_quota(state, caller.as_ref()) {
+            over_quota = true;
+            return None;
+        }
+
+        let conversation = conversation.as_ref();
+        match ask(state, client, msg, prompt, conversation, caller.as_ref()) {
+            Ok(answer) => Some(answer),
+            Err(e) => {
+                warn!("Unable to ask ChatGPT: {e}");
+                count_error(&e);
+                failure = Some(e);
+                state.chatgpt_fallback.clone()
+            }
+        }
+    };
+
+    let chatgpt_response = do_chatgpt();
+
+    // The answer could otherwise add headers of its own or break
+    // the reply.
+    let mut add_header = |name: &str, value: &str, max_len: Option<usize>| {
+        let mut header = String::from(name);
+        header.push_str(": ");
+        header.push_str(&encode_value(value, state.header_encoding, max_len));
+        header.push_str("\r\n");
+
+        // SAFETY: `msg` is passed from OpenSIPS, the header is
+        // managed by Rust's `String`, and we instruct OpenSIPS to not
+        // free our memory.
+        let lump = unsafe {
+            opensips::add_lump_rpl(
+                msg,
+                header.as_mut_ptr(),
+                header.len().try_into().unwrap(),
+                opensips::LUMP_RPL_HDR | opensips::LUMP_RPL_NOFREE,
+            )
+        };
+
+        !lump.is_null()
+    };
+
+    let rust_header_value = format!(
+        "{} / {} / {} / {}",
+        state.name,
+        state.count,
+        state.counter.load(Ordering::Relaxed),
+        state.dog_url
+    );
+    if !add_header("X-Rust", &rust_header_value, None) {
+        error!("Unable to add the X-Rust header");
+        return -1;
+    }
+
+    if let Some(chatgpt_header_value) = chatgpt_response {
+        let max_len = state.chatgpt_header_max_len;
+        if !add_header("X-ChatGPT", &chatgpt_header_value, max_len) {
+            error!("Unable to add the X-ChatGPT header");
+            return -1;
+        }
+    }
+
+    if let Some(e) = &failure {
+        if !add_header("X-ChatGPT-Error", e.code(), None) {
+            error!("Unable to add the X-ChatGPT-Error header");
+            return -1;
+        }
+    }
+
+    // With a fallback, the caller still gets an answer.
+    let (code, reason) = match &failure {
+        _ if over_quota => (state.quota_code, "Quota Exceeded"),
+        Some(e) if state.chatgpt_fallback.is_none() => sip_outcome(e),
+        _ => (200, "OK"),
+    };
+    send_reply(state, msg, code, reason)
+}
+
+/// Like `reply`, but the answer is sent back in MESSAGE requests
+/// within the dialog of `msg` while it is generated, instead of in
+/// a header of the reply. The request is answered right away.
+#[instrument(skip_all)]
+fn stream(msg: &mut opensips::sip_msg) -> i32 {
+    info!("called");
+
+    let state = STATE.read().expect("Lock poisoned");
+    let state = state.as_ref().expect("Not initialized");
+
+    let Some(tm) = &state.tm else {
+        error!("Streaming needs the tm module");
+        return -1;
+    };
+    let Some(client) = LLM.get() else {
+        error!("ChatGPT isn't configured");
+        return -1;
+    };
+    let query = chatgpt_query(msg).map(String::from);
+    let Some(prompt) = prompt(state, msg, query) else {
+        warn!("There is no question to answer");
+        return -1;
+    };
+
+    let caller = state
+        .quotas
+        .as_ref()
+        .and_then(|quotas| Some((quotas, quotas.caller(msg)?)));
+
+    if is_over_quota(state, caller.as_ref()) {
+        return send_reply(state, msg, state.quota_code, "Quota Exceeded");
+    }
+
+    let conversation = state
+        .conversations
+        .as_ref()
+        .and_then(|store| Some((store, store.key(msg)?)));
+    let history = conversation
+        .as_ref()
+        .map(|(store, key)| store.history(key))
+        .unwrap_or_default();
+
+    // Our requests have to use the To tag of our reply.
+    let Some(local_tag) = local_tag(state, msg) else {
+        error!("Unable to generate the To tag");
+        return -1;
+    };
+    let Some(dialog) = tm::Dialog::from_request(tm, msg, &local_tag) else {
+        error!("Unable to create the dialog to stream the answer in");
+        return -1;
+    };
+
+    let deltas = client.ask_stream(prompt.system.as_deref(), history, &prompt.question);
+
+    // Nothing has been sent yet, so the reply can still tell.
+    match &deltas {
+        Err(e) if state.chatgpt_fallback.is_none() => {
+            warn!("Unable to ask ChatGPT: {e}");
+            count_error(e);
+            let (code, reason) = sip_outcome(e);
+            return send_reply(state, msg, code, reason);
+        }
+        _ => {}
+    }
+
+    if send_reply(state, msg, 200, "OK") < 0 {
+        return -1;
+    }
+
+    let mut messages = stream::Messages::new(dialog, state.stream_chunk);
+    let mut answer = String::new();
+    let mut usage = None;
+
+    // Blocks until the whole answer has arrived.
+    let complete = deltas.and_then(|deltas| {
+        for piece in deltas {
+            let piece = piece?;
+            messages.push(&piece.content);
+            answer.push_str(&piece.content);
+            usage = piece.usage.or(usage);
+        }
+        Ok(())
+    });
+
+    if let Err(e) = &complete {
+        warn!("Unable to ask ChatGPT: {e}");
+        count_error(e);
+        // Whatever arrived has been sent already.
+        if answer.is_empty() {
+            if let Some(fallback) = &state.chatgpt_fallback {
+                messages.push(fallback);
+            }
+        }
+    }
+
+    let sent = messages.finish();
+    info!("Streamed the answer in {sent} messages");
+
+    if let Some(usage) = usage {
+        record_usage(caller.as_ref(), usage);
+    }
+    if complete.is_ok() {
+        if let Some((store, key)) = &conversation {
+            store.append(key, &prompt.question, &answer);
+        }
+    }
+
+    0
+}
+
+/// Answers the text of a MESSAGE request with a MESSAGE of its own,
+/// sent back to where the request came from. The request is
+/// answered right away, so failures can only be told with the
+/// fallback answer.
+#[instrument(skip_all)]
+fn chat(msg: &mut opensips::sip_msg) -> i32 {
+    info!("called");
+
+    let state = STATE.read().expect("Lock poisoned");
+    let state = state.as_ref().expect("Not initialized");
+
+    let Some(tm) = &state.tm else {
+        error!("Chatting needs the tm module");
+        return -1;
+    };
+    let Some(client) = LLM.get() else {
+        error!("ChatGPT isn't configured");
+        return -1;
+    };
+    if msg.method() != Some("MESSAGE") {
+        warn!("Only MESSAGE requests can be chatted with");
+        return -1;
+    }
+
+    let text = match im::text(msg) {
+        Ok(text) => text.map(String::from),
+        Err(content_type) => {
+            info!("Unable to chat about {content_type}");
+            return send_reply(state, msg, 415, "Unsupported Media Type");
+        }
+    };
+    let Some(prompt) = prompt(state, msg, text) else {
+        info!("There is no text to answer");
+        return send_reply(state, msg, 400, "Missing Text");
+    };
+
+    let caller = state
+        .quotas
+        .as_ref()
+        .and_then(|quotas| Some((quotas, quotas.caller(msg)?)));
+
+    if is_over_quota(state, caller.as_ref()) {
+        return send_reply(state, msg, state.quota_code, "Quota Exceeded");
+    }
+
+    let conversation = state
+        .conversations
+        .as_ref()
+        .and_then(|store| Some((store, store.key(msg)?)));
+
+    // The answer goes from whoever the request was for to whoever
+    // sent it, straight back to where it came from.
+    let Some(sender) = msg.from_uri().map(String::from) else {
+        error!("Unable to parse the From header");
+        return -1;
+    };
+    let Some(recipient) = msg.to_uri().map(String::from) else {
+        error!("Unable to parse the To header");
+        return -1;
+    };
+    let Some(next_hop) = tm::next_hop(msg) else {
+        error!("Unable to tell where the request came from");
+        return -1;
+    };
+
+    if send_reply(state, msg, 200, "OK") < 0 {
+        return -1;
+    }
+
+    // Blocks until the whole answer has arrived.
+    let conversation = conversation.as_ref();
+    let answer = match ask(state, client, msg, &prompt, conversation, caller.as_ref()) {
+        Ok(answer) => answer,
+        Err(e) => {
+            warn!("Unable to ask ChatGPT: {e}");
+            count_error(&e);
+            match &state.chatgpt_fallback {
+                Some(fallback) => fallback.clone(),
+                None => return 0,
+            }
+        }
+    };
+
+    let request = tm::Request {
+        method: "MESSAGE",
+        uri: &sender,
+        to: &sender,
+        from: &recipient,
+        headers: im::HEADERS,
+        body: &answer,
+        next_hop: Some(&next_hop),
+    };
+    if !tm.request(&request) {
+        error!("Unable to send the answer to {sender}");
+        return -1;
+    }
+
+    0
+}
+
+/// Asks the LLM about `prompt`, answering from the cache when the
+/// conversation has no earlier messages. Runs the tools the model
+/// calls, remembers the answer in the conversation and counts the
+/// tokens used against `caller`.
+fn ask(
+    state: &GlobalState,
+    client: &llm::Client,
+    msg: &mut opensips::sip_msg,
+    prompt: &Prompt,
+    conversation: Option<&(&conversation::Store, String)>,
+    caller: Option<&(&quota::Quotas, String)>,
+) -> Result<String, llm::Error> {
+    let query = prompt.question.as_str();
+
+    let history = conversation
+        .map(|(store, key)| store.history(key))
+        .unwrap_or_default();
+
+    // Answers which depend on earlier messages can't be reused.
+    let cached = state
+        .cache
+        .as_ref()
+        .zip(state.chatgpt.as_ref())
+        .filter(|_| history.is_empty())
+        .map(|(cache, config)| {
+            let system_prompt = prompt.system.as_deref().unwrap_or(&config.system_prompt);
+            let key = cache::Cache::key(config.model_name(), system_prompt, query);
+            (cache, key)
+        });
+
+    if let Some((cache, key)) = &cached {
+        if let Some(answer) = cache.get(key) {
+            CACHE_HITS.increment();
+            if let Some((store, key)) = conversation {
+                store.append(key, query, &answer);
+            }
+            return Ok(answer);
+        }
+        CACHE_MISSES.increment();
+    }
+
+    let mut messages = history;
+    messages.push(llm::Message::new(llm::Role::User, query));
+    let mut rounds = 0;
+
+    let answer = loop {
+        let answer = client.chat(prompt.system.as_deref(), messages.clone())?;
+        if let Some(usage) = answer.usage {
+            record_usage(caller, usage);
+        }
+
+        let Some(tools) = &state.tools else {
+            break answer.content;
+        };
+        if answer.tool_calls.is_empty() {
+            break answer.content;
+        }
+        if rounds == MAX_TOOL_ROUNDS {
+            return Err(llm::Error::Api("the model kept calling tools".into()));
+        }
+        rounds += 1;
+
+        let results: Vec<_> = answer
+            .tool_calls
+            .iter()
+            .map(|call| {
+                TOOL_CALLS.increment();
+                llm::Message::tool_result(call, tools.call(msg, call))
+            })
+            .collect();
+
+        let mut calls = llm::Message::new(llm::Role::Assistant, answer.content);
+        calls.tool_calls = answer.tool_calls;
+        messages.push(calls);
+        messages.extend(results);
+    };
+
+    // What the tools returned may have changed by the next time.
+    if let Some((cache, key)) = cached.filter(|_| rounds == 0) {
+        cache.insert(&key, &answer);
+    }
+    if let Some((store, key)) = conversation {
+        store.append(key, query, &answer);
+    }
+    Ok(answer)
+}
+
+/// Counts the rejection when `caller` has used up their quota, or
+/// when there is a limit but no telling who the caller is.
+fn is_over_quota(state: &GlobalState, caller: Option<&(&quota::Quotas, String)>) -> bool {
+    let Some((quotas, caller)) = caller else {
+        let limited = state
+            .quotas
+            .as_ref()
+            .is_some_and(|q| q.config().daily_limit.is_some());
+        if limited {
+            info!("Refusing a caller who can't be charged for ChatGPT");
+            QUOTA_REJECTED.increment();
+        }
+        return limited;
+    };
+    if quotas.allows(caller) {
+        return false;
+    }
+
+    info!("{caller} is over their ChatGPT quota");
+    QUOTA_REJECTED.increment();
+    true
+}
+
+/// How an error of the LLM is reported to the caller when there is
+/// no fallback answer.
+fn sip_outcome(e: &llm::Error) -> (c_int, &'static str) {
+    use llm::Error::*;
+
+    match e {
+        Busy | CircuitOpen | Stopped => (503, "Service Unavailable"),
+        RateLimited => (503, "LLM Rate Limited"),
+        InsufficientQuota => (503, "LLM Quota Exhausted"),
+        InvalidApiKey => (500, "LLM Key Rejected"),
+        ContextLengthExceeded => (413, "Request Entity Too Large"),
+        Timeout => (504, "Server Time-out"),
+        Request(_) | Status(_) | Decode(_) | Api(_) => (502, "Bad Gateway"),
+    }
+}
+
+fn count_error(e: &llm::Error) {
+    use llm::Error::*;
+
+    ERRORS.increment();
+    match e {
+        Busy | CircuitOpen | Stopped => ERRORS_UNAVAILABLE.increment(),
+        RateLimited => ERRORS_RATE_LIMIT.increment(),
+        InsufficientQuota => ERRORS_QUOTA.increment(),
+        InvalidApiKey => ERRORS_AUTH.increment(),
+        ContextLengthExceeded => ERRORS_CONTEXT_LENGTH.increment(),
+        Timeout => ERRORS_TIMEOUT.increment(),
+        Request(_) | Status(_) | Decode(_) | Api(_) => ERRORS_BACKEND.increment(),
+    }
+}
+
+/// What to ask the LLM about a request.
+struct Prompt {
+    /// Replaces the configured system prompt.
+    system: Option<String>,
+    question: String,
+}
+
+/// Without a template, `question` is asked. Returns `None` when
+/// there is nothing to ask.
+fn prompt(
+    state: &GlobalState,
+    msg: &mut opensips::sip_msg,
+    question: Option<String>,
+) -> Option<Prompt> {
+    let question = match &state.prompt_template {
+        Some(template) => template.render(msg),
+        None => question?,
+    };
+    if question.trim().is_empty() {
+        return None;
+    }
+
+    let system = state.system_template.as_ref().map(|t| t.render(msg));
+    Some(Prompt { system, question })
+}
+
+// Headers which aren't UTF-8 are skipped, as anyone can send them.
+fn chatgpt_query(msg: &opensips::sip_msg) -> Option<&str> {
+    msg.header_iter()
+        .filter(|h| {
+            h.name
+                .try_as_str()
+                .is_ok_and(|n| n.eq_ignore_ascii_case("X-ChatGPT"))
+        })
+        .find_map(|h| h.body.try_as_str().ok())
+}
+
+/// The To tag the signaling module uses when replying to `msg`.
+fn local_tag(state: &GlobalState, msg: &mut opensips::sip_msg) -> Option<String> {
+    let gen_totag = state.sigb.gen_totag?;
+    let mut tag = "".as_opensips_str();
+
+    // SAFETY: `msg` comes from OpenSIPS and `tag` is set to a buffer
+    // of the signaling module, which we copy right away.
+    if unsafe { gen_totag(msg, &mut tag) } < 0 {
+        return None;
+    }
+    tag.try_as_str().ok().map(Into::into)
+}
+
+fn send_reply(state: &GlobalState, msg: &mut opensips::sip_msg, code: c_int, reason: &str) -> i32 {
+    let reply = state.sigb.reply.expect("reply function pointer missing");
+
+    let reason = &reason.as_opensips_str();
+    let tag = ptr::null_mut();
+
+    // SAFETY: `msg` comes from OpenSIPS, `code` is an integer,
     // `reason` is a static string, and `tag` is NULL. Nothing bad can
     // happen with those values.
     if unsafe { reply(msg, code, reason, tag) } == -1 {
-        error!("failed to reply with 200");
+        error!("failed to reply with {code}");
         return -1;
     }
 
     0
 }
 
+fn record_usage(caller: Option<&(&quota::Quotas, String)>, usage: llm::Usage) {
+    let add = |stat: &opensips::statistic::Statistic, n: u64| {
+        stat.add(n.try_into().unwrap_or(usize::MAX));
+    };
+    add(&PROMPT_TOKENS, usage.prompt_tokens);
+    add(&COMPLETION_TOKENS, usage.completion_tokens);
+    add(&TOTAL_TOKENS, usage.total_tokens);
+
+    if let Some((quotas, caller)) = caller {
+        quotas.record(caller, usage);
+    }
+}
+
+/// Classifies `text`, e.g. `$rb` or `$hdr(Subject)`, as the closest
+/// of the configured intents. Its label goes into `label` and the
+/// similarity, in percent, into `score`. Fails when the score is
+/// below the threshold, after setting both, or when the text can't
+/// be classified.
+#[instrument(skip_all)]
+fn intent(
+    msg: &mut opensips::sip_msg,
+    text: &str,
+    label: &pv::Variable,
+    score: &pv::Variable,
+) -> i32 {
+    info!("called");
+
+    let state = STATE.read().expect("Lock poisoned");
+    let state = state.as_ref().expect("Not initialized");
+
+    let Some(intents) = &state.intents else {
+        error!("There are no intents to choose from");
+        return -1;
+    };
+    let Some(client) = LLM.get() else {
+        error!("ChatGPT isn't configured");
+        return -1;
+    };
+    if text.trim().is_empty() {
+        info!("There is no text to classify");
+        return -1;
+    }
+
+    let intent = match intents.classify(client, text) {
+        Ok(intent) => intent,
+        Err(e) => {
+            warn!("Unable to classify the text: {e}");
+            count_error(&e);
+            return -1;
+        }
+    };
+
+    let percent = (intent.score * 100.0).round() as i32;
+    info!("The intent is {} ({percent}%)", intent.label);
+
+    if !label.set(msg, intent.label) || !score.set_int(msg, percent) {
+        error!("Unable to store the intent");
+        return -1;
+    }
+    if state.intent_threshold.is_some_and(|t| percent < t) {
+        return -1;
+    }
+
+    1
+}
+
 #[instrument]
 fn test_str(_: &mut opensips::sip_msg, s1: &str, s2: &str) -> i32 {
     info!("called");
@@ -426,8 +1802,156 @@ extern "C" fn control(
     info!("called");
     let state = STATE.read().expect("Lock poisoned");
     let state = state.as_ref().expect("Not initialized");
-    let parent_tx = state.parent_tx.as_ref().expect("Can't talk to the network");
-    parent_tx.blocking_send(Message::IncrementCounter).unwrap();
+    state.counter.fetch_add(1, Ordering::Relaxed);
+
+    opensips::init_mi_result_ok()
+}
+
+#[instrument(skip_all)]
+extern "C" fn cache_list(
+    _params: *const opensips::mi_params_t,
+    _async_hdl: *mut opensips::mi_handler,
+) -> *mut opensips::mi_response_t {
+    info!("called");
+
+    let state = STATE.read().expect("Lock poisoned");
+    let state = state.as_ref().expect("Not initialized");
+
+    let Some(cache) = &state.cache else {
+        return mi::error(opensips::JSONRPC_SERVER_ERR_CODE, "The cache is disabled");
+    };
+
+    let entries = cache.entries();
+
+    mi::result_object(|result| {
+        result.add_number("soft_capacity", cache.config().soft_capacity as f64)?;
+        result.add_number("ttl", cache.config().ttl.as_secs() as f64)?;
+        result.add_number("hits", CACHE_HITS.get() as f64)?;
+        result.add_number("misses", CACHE_MISSES.get() as f64)?;
+
+        let answers = result.add_array("answers")?;
+        for entry in &entries {
+            let answer = answers.add_object("")?;
+            answer.add_string("model", &entry.model)?;
+            answer.add_string("question", &entry.question)?;
+            answer.add_string("answer", &entry.answer)?;
+            answer.add_number("expires_in", entry.expires_in.as_secs() as f64)?;
+        }
+
+        Some(())
+    })
+}
+
+#[instrument(skip_all)]
+extern "C" fn cache_flush(
+    _params: *const opensips::mi_params_t,
+    _async_hdl: *mut opensips::mi_handler,
+) -> *mut opensips::mi_response_t {
+    info!("called");
+
+    let state = STATE.read().expect("Lock poisoned");
+    let state = state.as_ref().expect("Not initialized");
+
+    let Some(cache) = &state.cache else {
+        return mi::error(opensips::JSONRPC_SERVER_ERR_CODE, "The cache is disabled");
+    };
+
+    let flushed = cache.len();
+    cache.clear();
+    info!("Flushed {flushed} cached answers");
+
+    opensips::init_mi_result_ok()
+}
+
+#[instrument(skip_all)]
+extern "C" fn usage_list(
+    _params: *const opensips::mi_params_t,
+    _async_hdl: *mut opensips::mi_handler,
+) -> *mut opensips::mi_response_t {
+    info!("called");
+
+    let state = STATE.read().expect("Lock poisoned");
+    let state = state.as_ref().expect("Not initialized");
+
+    let Some(quotas) = &state.quotas else {
+        return mi::error(opensips::JSONRPC_SERVER_ERR_CODE, "ChatGPT is disabled");
+    };
+
+    let tallies = quotas.tallies();
+
+    mi::result_object(|result| {
+        if let Some(limit) = quotas.config().daily_limit {
+            result.add_number("daily_limit", limit as f64)?;
+        }
+        result.add_number("prompt_tokens", PROMPT_TOKENS.get() as f64)?;
+        result.add_number("completion_tokens", COMPLETION_TOKENS.get() as f64)?;
+        result.add_number("total_tokens", TOTAL_TOKENS.get() as f64)?;
+        result.add_number("rejected", QUOTA_REJECTED.get() as f64)?;
+
+        let callers = result.add_array("callers")?;
+        for (caller, tally) in &tallies {
+            let item = callers.add_object("")?;
+            item.add_string("caller", caller)?;
+            item.add_number("requests", tally.requests as f64)?;
+            item.add_number("prompt_tokens", tally.prompt_tokens as f64)?;
+            item.add_number("completion_tokens", tally.completion_tokens as f64)?;
+            item.add_number("total_tokens", tally.total_tokens as f64)?;
+        }
+
+        Some(())
+    })
+}
+
+#[instrument(skip_all)]
+extern "C" fn usage_reset(
+    params: *const opensips::mi_params_t,
+    _async_hdl: *mut opensips::mi_handler,
+) -> *mut opensips::mi_response_t {
+    info!("called");
+
+    let state = STATE.read().expect("Lock poisoned");
+    let state = state.as_ref().expect("Not initialized");
+
+    let Some(quotas) = &state.quotas else {
+        return mi::error(opensips::JSONRPC_SERVER_ERR_CODE, "ChatGPT is disabled");
+    };
+
+    // SAFETY: [OpenSIPS::valid]
+    let caller = unsafe { mi::string_param(params, cstr_lit!("caller")) };
+    quotas.reset(caller);
+
+    opensips::init_mi_result_ok()
+}
+
+#[instrument(skip_all)]
+extern "C" fn log_filter(
+    params: *const opensips::mi_params_t,
+    _async_hdl: *mut opensips::mi_handler,
+) -> *mut opensips::mi_response_t {
+    info!("called");
+
+    // SAFETY: [OpenSIPS::valid]
+    let Some(directives) = (unsafe { mi::string_param(params, cstr_lit!("filter")) }) else {
+        // Called without a filter; show the current one.
+        let current = formatter::filter().unwrap_or_default();
+        return mi::result_string(&current);
+    };
+
+    if let Err(e) = formatter::set_filter(directives) {
+        return mi::error(opensips::JSONRPC_INVAL_PARAMS_CODE, &e);
+    }
+
+    let state = STATE.read().expect("Lock poisoned");
+    let state = state.as_ref().expect("Not initialized");
+
+    // Every process has its own copy of the filter.
+    match state.messages.broadcast(&Message::LogFilter(directives.into())) {
+        Ok(n) => info!("Sent the new log filter to {n} processes"),
+        Err(e) => {
+            error!("Unable to send the new log filter: {e}");
+            return mi::error(opensips::JSONRPC_SERVER_ERR_CODE, &e.to_string());
+        }
+    }
 
     opensips::init_mi_result_ok()
 }
@@ -0,0 +1,894 @@
+use futures_util::{stream, Stream, StreamExt};
+use reqwest::{
+    header::{self, HeaderMap, HeaderValue},
+    StatusCode,
+};
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    thread,
+    time::{Duration, Instant},
+};
+use tokio::{
+    runtime,
+    sync::{
+        mpsc::{self, error::TrySendError},
+        oneshot, Semaphore,
+    },
+};
+use tracing::{warn, Instrument, Span};
+
+mod mock;
+mod ollama;
+mod openai;
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Message {
+    pub role: Role,
+    // `null` when the assistant calls tools instead.
+    #[serde(default, deserialize_with = "null_as_empty")]
+    pub content: String,
+    /// What the assistant wants called before it answers.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    /// Which call a [`Role::Tool`] message is the result of.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+
+impl Message {
+    pub fn new(role: Role, content: impl Into<String>) -> Self {
+        Self {
+            role,
+            content: content.into(),
+            tool_calls: Vec::new(),
+            tool_call_id: None,
+        }
+    }
+
+    /// The result of calling a tool, as told to the model.
+    pub fn tool_result(call: &ToolCall, content: impl Into<String>) -> Self {
+        Self {
+            tool_call_id: Some(call.id.clone()),
+            ..Self::new(Role::Tool, content)
+        }
+    }
+}
+
+fn null_as_empty<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let content: Option<String> = serde::Deserialize::deserialize(deserializer)?;
+    Ok(content.unwrap_or_default())
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+    Tool,
+}
+
+/// A function the model may ask us to call, described for the model.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Tool {
+    pub name: String,
+    pub description: String,
+    /// The JSON schema of the arguments.
+    pub parameters: serde_json::Value,
+}
+
+/// A call of a [`Tool`] the model asked for.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(rename = "type", default)]
+    pub type_: ToolType,
+    pub function: FunctionCall,
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ToolType {
+    #[default]
+    Function,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct FunctionCall {
+    pub name: String,
+    /// The arguments as a JSON object, which the model may have
+    /// gotten wrong.
+    pub arguments: String,
+}
+
+/// What the LLM answered, and what that cost.
+#[derive(Debug, Clone)]
+pub struct Answer {
+    pub content: String,
+    /// When set, the model wants these called and their results
+    /// sent back before it answers.
+    pub tool_calls: Vec<ToolCall>,
+    /// `None` when the backend doesn't report it.
+    pub usage: Option<Usage>,
+}
+
+impl Answer {
+    fn new(content: String, usage: Option<Usage>) -> Self {
+        Self {
+            content,
+            tool_calls: Vec::new(),
+            usage,
+        }
+    }
+}
+
+/// Where a text lies in the model's vector space. Similar texts lie
+/// close to each other.
+pub type Embedding = Vec<f32>;
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, serde::Deserialize)]
+pub struct Usage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+}
+
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+pub type BoxStream<'a, T> = Pin<Box<dyn Stream<Item = T> + Send + 'a>>;
+
+/// Something that can continue a conversation.
+pub trait LanguageModel: Send + Sync {
+    /// Returns the next message of the assistant. `messages` starts
+    /// with the system prompt.
+    fn chat<'a>(&'a self, messages: &'a [Message]) -> BoxFuture<'a, Result<Answer, Attempt>>;
+
+    /// Like [`chat`][Self::chat], but the answer arrives in pieces
+    /// as it is generated. The usage, if known, comes with the last
+    /// piece. Backends that can't stream send everything at once.
+    fn chat_stream<'a>(
+        &'a self,
+        messages: &'a [Message],
+    ) -> BoxFuture<'a, Result<BoxStream<'a, Result<Answer, Error>>, Attempt>> {
+        Box::pin(async move {
+            let answer = self.chat(messages).await?;
+            Ok(stream::iter([Ok(answer)]).boxed())
+        })
+    }
+
+    /// Returns the embedding of each of `inputs`, in the same order,
+    /// using the embedding model.
+    fn embed<'a>(&'a self, inputs: &'a [String]) -> BoxFuture<'a, Result<Vec<Embedding>, Attempt>>;
+}
+
+/// Which [`LanguageModel`] answers.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum Backend {
+    /// Any server with an OpenAI-compatible `/chat/completions`
+    #[default]
+    OpenAi,
+    /// Ollama's own `/api/chat`
+    Ollama,
+    /// Answers without any network access, for testing
+    Mock,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "openai" => Ok(Self::OpenAi),
+            "ollama" => Ok(Self::Ollama),
+            "mock" => Ok(Self::Mock),
+            _ => Err(format!(
+                "Unknown LLM backend `{s}`, expected `openai`, `ollama` or `mock`"
+            )),
+        }
+    }
+}
+
+impl Backend {
+    fn build(self, client: reqwest::Client, config: &Config) -> Arc<dyn LanguageModel> {
+        match self {
+            Self::OpenAi => Arc::new(openai::OpenAi::new(client, config)),
+            Self::Ollama => Arc::new(ollama::Ollama::new(client, config)),
+            Self::Mock => Arc::new(mock::Mock),
+        }
+    }
+}
+
+// Requests beyond this are refused instead of waiting for room.
+const QUEUE_SIZE: usize = 64;
+
+// Pieces of a streamed answer the caller hasn't picked up yet.
+const STREAM_BUFFER: usize = 32;
+
+// How many requests may be waiting on ChatGPT at the same time.
+const MAX_IN_FLIGHT: usize = 16;
+
+// The first retry waits this long, doubling for each following one.
+const RETRY_BACKOFF: Duration = Duration::from_millis(250);
+
+// Don't let the server make us wait longer than this between tries.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(5);
+
+// Nor ourselves, however many retries there are.
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(5);
+
+pub const DEFAULT_SYSTEM_PROMPT: &str = "You are OpenSIPS, an Open Source SIP proxy/server for voice, video, IM, presence and any other SIP extensions. Limit all responses to a single sentence.";
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub backend: Backend,
+    /// Sent as a bearer token when set.
+    pub api_key: Option<String>,
+    /// The base URL of the server; each backend has its own default.
+    pub url: Option<String>,
+    /// Each backend has its own default.
+    pub model: Option<String>,
+    /// The model which embeds texts; each backend has its own default.
+    pub embedding_model: Option<String>,
+    pub temperature: Option<f32>,
+    pub max_tokens: Option<u32>,
+    pub system_prompt: String,
+    pub connect_timeout: Duration,
+    /// For each try, from connecting until the body has been read.
+    /// Streamed answers only need to start by then, and may then
+    /// take as long between two pieces.
+    pub timeout: Duration,
+    /// How often a request is retried after a 429 or 5xx response.
+    pub retries: u32,
+    /// For a whole request, including its retries and the time it
+    /// waited in the queue. Streamed answers only need to start by
+    /// then.
+    pub deadline: Duration,
+    /// Consecutive failures before requests are refused, counted
+    /// separately for answers and embeddings.
+    pub breaker_threshold: u32,
+    /// How long requests are refused before trying again.
+    pub breaker_cooldown: Duration,
+    /// Offered to the model, except for streamed answers. Only the
+    /// OpenAI backend and the mock call them.
+    pub tools: Vec<Tool>,
+}
+
+impl Config {
+    /// The model which answers, falling back to the backend's default.
+    pub fn model_name(&self) -> &str {
+        self.model.as_deref().unwrap_or(match self.backend {
+            Backend::OpenAi => openai::DEFAULT_MODEL,
+            Backend::Ollama => ollama::DEFAULT_MODEL,
+            Backend::Mock => "mock",
+        })
+    }
+
+    /// The model which embeds texts, falling back to the backend's
+    /// default.
+    pub fn embedding_model_name(&self) -> &str {
+        self.embedding_model
+            .as_deref()
+            .unwrap_or(match self.backend {
+                Backend::OpenAi => openai::DEFAULT_EMBEDDING_MODEL,
+                Backend::Ollama => ollama::DEFAULT_EMBEDDING_MODEL,
+                Backend::Mock => "mock",
+            })
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            backend: Backend::default(),
+            api_key: None,
+            url: None,
+            model: None,
+            embedding_model: None,
+            temperature: None,
+            max_tokens: None,
+            system_prompt: DEFAULT_SYSTEM_PROMPT.into(),
+            connect_timeout: Duration::from_secs(2),
+            timeout: Duration::from_secs(10),
+            retries: 2,
+            deadline: Duration::from_secs(30),
+            breaker_threshold: 5,
+            breaker_cooldown: Duration::from_secs(30),
+            tools: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The queue is full.
+    Busy,
+    /// Too many requests have failed recently.
+    CircuitOpen,
+    /// The LLM thread is gone.
+    Stopped,
+    Timeout,
+    Request(reqwest::Error),
+    /// An unexpected HTTP status without a known error in the body.
+    Status(StatusCode),
+    /// The body of the response wasn't what we expected.
+    Decode(String),
+    /// The account has run out of credit.
+    InsufficientQuota,
+    /// Too many requests or tokens in a short time.
+    RateLimited,
+    /// The key is missing, wrong or revoked.
+    InvalidApiKey,
+    /// The conversation doesn't fit in what the model can read.
+    ContextLengthExceeded,
+    /// Any other error reported by the API.
+    Api(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Busy => f.write_str("too many LLM requests are queued"),
+            Self::CircuitOpen => f.write_str("the LLM is failing, not trying for now"),
+            Self::Stopped => f.write_str("the LLM thread has stopped"),
+            Self::Timeout => f.write_str("the LLM request timed out"),
+            Self::Request(e) => write!(f, "the LLM request failed: {e}"),
+            Self::Status(s) => write!(f, "the LLM responded with {s}"),
+            Self::Decode(e) => write!(f, "the LLM response was invalid: {e}"),
+            Self::InsufficientQuota => f.write_str("the LLM account has no quota left"),
+            Self::RateLimited => f.write_str("the LLM is rate limiting us"),
+            Self::InvalidApiKey => f.write_str("the LLM key was rejected"),
+            Self::ContextLengthExceeded => {
+                f.write_str("the conversation is too long for the model")
+            }
+            Self::Api(m) => write!(f, "the LLM responded with an error: {m}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            Self::Timeout
+        } else if e.is_decode() {
+            Self::Decode(e.to_string())
+        } else if let Some(status) = e.status() {
+            Self::Status(status)
+        } else {
+            Self::Request(e)
+        }
+    }
+}
+
+impl Error {
+    fn is_retryable(&self) -> bool {
+        match self {
+            Self::Status(s) => *s == StatusCode::TOO_MANY_REQUESTS || s.is_server_error(),
+            Self::RateLimited => true,
+            _ => false,
+        }
+    }
+
+    /// A short, stable name for the kind of error, e.g. for headers.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Busy => "busy",
+            Self::CircuitOpen => "circuit_open",
+            Self::Stopped => "stopped",
+            Self::Timeout => "timeout",
+            Self::Request(_) => "request",
+            Self::Status(_) => "status",
+            Self::Decode(_) => "decode",
+            Self::InsufficientQuota => "insufficient_quota",
+            Self::RateLimited => "rate_limit",
+            Self::InvalidApiKey => "invalid_api_key",
+            Self::ContextLengthExceeded => "context_length_exceeded",
+            Self::Api(_) => "api",
+        }
+    }
+}
+
+/// Stops sending requests for a while after `threshold` consecutive
+/// failures. Once the cooldown is over, requests are let through
+/// again; the first failure reopens it.
+#[derive(Debug)]
+struct Breaker {
+    /// What is asked for, in the log.
+    task: &'static str,
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<BreakerState>,
+}
+
+#[derive(Debug, Default)]
+struct BreakerState {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl Breaker {
+    fn new(config: &Config, task: &'static str) -> Self {
+        Self {
+            task,
+            threshold: config.breaker_threshold.max(1),
+            cooldown: config.breaker_cooldown,
+            state: Default::default(),
+        }
+    }
+
+    fn is_open(&self) -> bool {
+        let state = self.state.lock().expect("Lock poisoned");
+        state.open_until.is_some_and(|t| Instant::now() < t)
+    }
+
+    fn record(&self, success: bool) {
+        let mut state = self.state.lock().expect("Lock poisoned");
+
+        if success {
+            *state = Default::default();
+            return;
+        }
+
+        state.failures = state.failures.saturating_add(1);
+        if state.failures >= self.threshold {
+            if state.open_until.is_none() {
+                warn!(
+                    "Not asking the LLM for {} for {:?}",
+                    self.task, self.cooldown
+                );
+            }
+            state.open_until = Some(Instant::now() + self.cooldown);
+        }
+    }
+}
+
+/// Chats and embeddings use different models, so one of them failing
+/// doesn't stop the other.
+#[derive(Debug)]
+struct Breakers {
+    chat: Breaker,
+    embed: Breaker,
+}
+
+impl Breakers {
+    fn new(config: &Config) -> Self {
+        Self {
+            chat: Breaker::new(config, "answers"),
+            embed: Breaker::new(config, "embeddings"),
+        }
+    }
+
+    fn of(&self, task: &Task) -> &Breaker {
+        match task {
+            Task::Chat { .. } => &self.chat,
+            Task::Embed { .. } => &self.embed,
+        }
+    }
+}
+
+struct Job {
+    task: Task,
+    deadline: Instant,
+    span: Span,
+}
+
+enum Task {
+    Chat {
+        /// Replaces the configured system prompt.
+        system_prompt: Option<String>,
+        /// Everything but the system prompt.
+        messages: Vec<Message>,
+        reply: Reply,
+    },
+    Embed {
+        inputs: Vec<String>,
+        reply: oneshot::Sender<Result<Vec<Embedding>, Error>>,
+    },
+}
+
+enum Reply {
+    Whole(oneshot::Sender<Result<Answer, Error>>),
+    Streamed(mpsc::Sender<Result<Answer, Error>>),
+}
+
+/// The pieces of an answer as they arrive. Async code can use it as
+/// a [`Stream`], anything else iterates over it, which blocks until
+/// the next piece arrives.
+#[derive(Debug)]
+pub struct Deltas(mpsc::Receiver<Result<Answer, Error>>);
+
+impl Stream for Deltas {
+    type Item = Result<Answer, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.0.poll_recv(cx)
+    }
+}
+
+impl Iterator for Deltas {
+    type Item = Result<Answer, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.blocking_recv()
+    }
+}
+
+/// A long-lived LLM client running on its own thread, so that
+/// connections (and their TLS sessions) are reused across requests.
+///
+/// Threads don't survive a fork, so each process needs its own.
+#[derive(Debug)]
+pub struct Client {
+    tx: mpsc::Sender<Job>,
+    breakers: Arc<Breakers>,
+    deadline: Duration,
+}
+
+impl Client {
+    pub fn start(config: &Config) -> Result<Self, String> {
+        let mut headers = HeaderMap::new();
+        if let Some(api_key) = &config.api_key {
+            let value = HeaderValue::from_maybe_shared(format!("Bearer {api_key}"))
+                .map_err(|e| format!("Invalid LLM key: {e}"))?;
+            headers.append(header::AUTHORIZATION, value);
+        }
+
+        let client = reqwest::Client::builder()
+            .default_headers(headers)
+            .connect_timeout(config.connect_timeout)
+            .build()
+            .map_err(|e| format!("Could not create reqwest Client: {e}"))?;
+
+        let runtime = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| format!("Could not create the LLM runtime: {e}"))?;
+
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        let breakers = Arc::new(Breakers::new(config));
+        let worker = Worker {
+            model: config.backend.build(client, config),
+            system_prompt: config.system_prompt.clone(),
+            retries: config.retries,
+            breakers: breakers.clone(),
+        };
+
+        thread::Builder::new()
+            .name("llm".into())
+            .spawn(move || runtime.block_on(worker.run(rx)))
+            .map_err(|e| format!("Could not start the LLM thread: {e}"))?;
+
+        Ok(Self {
+            tx,
+            breakers,
+            deadline: config.deadline,
+        })
+    }
+
+    /// Asks the LLM and waits for the answer. `messages` is the
+    /// conversation so far without the system prompt, ending with the
+    /// question or the results of the tools the model called.
+    /// `system_prompt` replaces the configured one.
+    pub fn chat(
+        &self,
+        system_prompt: Option<&str>,
+        messages: Vec<Message>,
+    ) -> Result<Answer, Error> {
+        let (reply, rx) = oneshot::channel();
+        self.send(Task::Chat {
+            system_prompt: system_prompt.map(Into::into),
+            messages,
+            reply: Reply::Whole(reply),
+        })?;
+
+        rx.blocking_recv().map_err(|_| Error::Stopped)?
+    }
+
+    /// Embeds `inputs` with the embedding model and waits for the
+    /// result, one [`Embedding`] for each input.
+    pub fn embed(&self, inputs: Vec<String>) -> Result<Vec<Embedding>, Error> {
+        let count = inputs.len();
+        let (reply, rx) = oneshot::channel();
+        self.send(Task::Embed { inputs, reply })?;
+
+        let embeddings = rx.blocking_recv().map_err(|_| Error::Stopped)??;
+        if embeddings.len() != count {
+            return Err(Error::Decode(format!(
+                "expected {count} embeddings, got {}",
+                embeddings.len()
+            )));
+        }
+
+        Ok(embeddings)
+    }
+
+    /// Like [`chat`][Self::chat] for a new `message` after
+    /// `history`, but returns as soon as the request is queued. A
+    /// failure after some pieces have arrived ends the answer with an
+    /// error.
+    pub fn ask_stream(
+        &self,
+        system_prompt: Option<&str>,
+        mut history: Vec<Message>,
+        message: &str,
+    ) -> Result<Deltas, Error> {
+        history.push(Message::new(Role::User, message));
+
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+        self.send(Task::Chat {
+            system_prompt: system_prompt.map(Into::into),
+            messages: history,
+            reply: Reply::Streamed(tx),
+        })?;
+
+        Ok(Deltas(rx))
+    }
+
+    fn send(&self, task: Task) -> Result<(), Error> {
+        if self.breakers.of(&task).is_open() {
+            return Err(Error::CircuitOpen);
+        }
+
+        let job = Job {
+            task,
+            deadline: Instant::now() + self.deadline,
+            span: Span::current(),
+        };
+
+        self.tx.try_send(job).map_err(|e| match e {
+            TrySendError::Full(_) => Error::Busy,
+            TrySendError::Closed(_) => Error::Stopped,
+        })
+    }
+}
+
+#[derive(Clone)]
+struct Worker {
+    model: Arc<dyn LanguageModel>,
+    system_prompt: String,
+    retries: u32,
+    breakers: Arc<Breakers>,
+}
+
+impl Worker {
+    async fn run(self, mut rx: mpsc::Receiver<Job>) {
+        let in_flight = Arc::new(Semaphore::new(MAX_IN_FLIGHT));
+
+        while let Some(job) = rx.recv().await {
+            // Leaving jobs in the queue keeps it bounded.
+            let Ok(permit) = in_flight.clone().acquire_owned().await else {
+                break;
+            };
+            let worker = self.clone();
+            let span = job.span.clone();
+
+            tokio::spawn(
+                async move {
+                    match job.task {
+                        Task::Chat {
+                            system_prompt,
+                            messages,
+                            reply,
+                        } => {
+                            let messages = worker.messages(system_prompt, messages);
+                            worker.chat(&messages, reply, job.deadline).await;
+                        }
+                        Task::Embed { inputs, reply } => {
+                            let embeddings = worker.embed(&inputs, job.deadline).await;
+                            worker.breakers.embed.record(embeddings.is_ok());
+
+                            // The caller may have given up.
+                            let _ = reply.send(embeddings);
+                        }
+                    }
+                    drop(permit);
+                }
+                .instrument(span),
+            );
+        }
+    }
+
+    async fn chat(&self, messages: &[Message], reply: Reply, deadline: Instant) {
+        match reply {
+            Reply::Whole(reply) => {
+                let answer = self.ask(messages, deadline).await;
+                self.breakers.chat.record(answer.is_ok());
+
+                // The caller may have given up.
+                let _ = reply.send(answer);
+            }
+            Reply::Streamed(tx) => {
+                let succeeded = self.stream(messages, tx, deadline).await;
+                self.breakers.chat.record(succeeded);
+            }
+        }
+    }
+
+    fn messages(&self, system_prompt: Option<String>, messages: Vec<Message>) -> Vec<Message> {
+        let system_prompt = system_prompt.unwrap_or_else(|| self.system_prompt.clone());
+
+        let mut with_system = Vec::with_capacity(messages.len() + 1);
+        with_system.push(Message::new(Role::System, system_prompt));
+        with_system.extend(messages);
+        with_system
+    }
+
+    async fn ask(&self, messages: &[Message], deadline: Instant) -> Result<Answer, Error> {
+        self.with_retries(deadline, || self.model.chat(messages))
+            .await
+    }
+
+    async fn embed(&self, inputs: &[String], deadline: Instant) -> Result<Vec<Embedding>, Error> {
+        self.with_retries(deadline, || self.model.embed(inputs))
+            .await
+    }
+
+    // Forwards the pieces of the answer, returning if all of them
+    // arrived. Only starting the request is retried, as the caller
+    // may already have used some pieces.
+    async fn stream(
+        &self,
+        messages: &[Message],
+        tx: mpsc::Sender<Result<Answer, Error>>,
+        deadline: Instant,
+    ) -> bool {
+        let start = self.with_retries(deadline, || self.model.chat_stream(messages));
+        let mut pieces = match start.await {
+            Ok(pieces) => pieces,
+            Err(e) => {
+                let _ = tx.send(Err(e)).await;
+                return false;
+            }
+        };
+
+        while let Some(piece) = pieces.next().await {
+            let failed = piece.is_err();
+
+            // The caller may have given up.
+            if tx.send(piece).await.is_err() || failed {
+                return !failed;
+            }
+        }
+
+        true
+    }
+
+    /// Gives up with [`Error::Timeout`] at `deadline`, whatever try
+    /// it is at.
+    async fn with_retries<'a, T, F>(&self, deadline: Instant, f: F) -> Result<T, Error>
+    where
+        F: FnMut() -> BoxFuture<'a, Result<T, Attempt>>,
+    {
+        let deadline = tokio::time::Instant::from_std(deadline);
+        tokio::time::timeout_at(deadline, self.retry(f))
+            .await
+            .unwrap_or(Err(Error::Timeout))
+    }
+
+    async fn retry<'a, T, F>(&self, mut f: F) -> Result<T, Error>
+    where
+        F: FnMut() -> BoxFuture<'a, Result<T, Attempt>>,
+    {
+        let mut attempt = 0;
+
+        loop {
+            let retry_after = match f().await {
+                Err(Attempt { error, retry_after })
+                    if error.is_retryable() && attempt < self.retries =>
+                {
+                    warn!("Retrying the LLM request: {error}");
+                    retry_after
+                }
+                result => return result.map_err(|a| a.error),
+            };
+
+            let backoff = RETRY_BACKOFF.saturating_mul(2u32.saturating_pow(attempt));
+            let backoff = backoff.min(MAX_RETRY_BACKOFF);
+            let delay = retry_after.map_or(backoff, |d| d.min(MAX_RETRY_AFTER));
+            tokio::time::sleep(delay).await;
+
+            attempt += 1;
+        }
+    }
+}
+
+/// A failed try, possibly with the server's opinion on when to try
+/// again.
+pub struct Attempt {
+    error: Error,
+    retry_after: Option<Duration>,
+}
+
+impl<E: Into<Error>> From<E> for Attempt {
+    fn from(e: E) -> Self {
+        Self {
+            error: e.into(),
+            retry_after: None,
+        }
+    }
+}
+
+/// Sends `request`, giving up after `timeout`. A total timeout would
+/// cut off streamed answers, so they only need to start by then and
+/// [`lines`] watches the time between their pieces.
+async fn send(
+    request: reqwest::RequestBuilder,
+    timeout: Duration,
+    stream: bool,
+) -> Result<reqwest::Response, Error> {
+    if !stream {
+        return Ok(request.timeout(timeout).send().await?);
+    }
+
+    match tokio::time::timeout(timeout, request.send()).await {
+        Ok(response) => Ok(response?),
+        Err(_) => Err(Error::Timeout),
+    }
+}
+
+/// Turns a 429 or 5xx response into a retryable [`Attempt`].
+fn check_status(response: reqwest::Response) -> Result<reqwest::Response, Attempt> {
+    let status = response.status();
+    if status != StatusCode::TOO_MANY_REQUESTS && !status.is_server_error() {
+        return Ok(response);
+    }
+
+    Err(Attempt {
+        error: Error::Status(status),
+        retry_after: retry_after(&response),
+    })
+}
+
+fn retry_after(response: &reqwest::Response) -> Option<Duration> {
+    response
+        .headers()
+        .get(header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok()?.parse().ok())
+        .map(Duration::from_secs)
+}
+
+/// Splits a streamed response body into lines, without the line
+/// endings. Fails when nothing arrives for `idle`.
+fn lines(
+    response: reqwest::Response,
+    idle: Duration,
+) -> impl Stream<Item = Result<String, Error>> + Send {
+    let state = (response, Vec::new(), false);
+
+    stream::unfold(
+        state,
+        move |(mut response, mut buffer, mut done)| async move {
+            loop {
+                if let Some(end) = buffer.iter().position(|&b| b == b'\n') {
+                    let line: Vec<u8> = buffer.drain(..=end).collect();
+                    let line = String::from_utf8_lossy(&line).trim_end().to_owned();
+                    return Some((Ok(line), (response, buffer, done)));
+                }
+
+                if done {
+                    if buffer.is_empty() {
+                        return None;
+                    }
+                    let line = String::from_utf8_lossy(&buffer).trim_end().to_owned();
+                    return Some((Ok(line), (response, Vec::new(), true)));
+                }
+
+                match tokio::time::timeout(idle, response.chunk()).await {
+                    Ok(Ok(Some(chunk))) => buffer.extend_from_slice(&chunk),
+                    Ok(Ok(None)) => done = true,
+                    Ok(Err(e)) => return Some((Err(e.into()), (response, Vec::new(), true))),
+                    Err(_) => return Some((Err(Error::Timeout), (response, Vec::new(), true))),
+                }
+            }
+        },
+    )
+}
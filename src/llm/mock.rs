@@ -0,0 +1,202 @@
+use futures_util::{stream, StreamExt};
+use reqwest::StatusCode;
+
+use super::{
+    Answer, Attempt, BoxFuture, BoxStream, Embedding, Error, FunctionCall, LanguageModel, Message,
+    Role, ToolCall, ToolType, Usage,
+};
+
+// The length of the embeddings.
+const DIMENSIONS: usize = 64;
+
+/// Answers without any network access, so the `X-ChatGPT` flow can
+/// be tested offline. The answer only depends on the last user
+/// message:
+///
+/// - `!status <code>` fails as if the server responded with `<code>`
+/// - `!error <message>` fails as if the API reported `<message>`
+/// - `!tool <name> <arguments>` calls the tool `<name>`, then echoes
+///   its result
+/// - anything else is echoed back
+///
+/// Every word counts as a token, and streamed answers arrive one
+/// word at a time.
+///
+/// Embeddings count the words of each input, so texts sharing words
+/// are similar. The `!status` and `!error` inputs fail as above.
+pub struct Mock;
+
+impl Mock {
+    fn answer(messages: &[Message]) -> Result<Answer, Attempt> {
+        let question = messages
+            .iter()
+            .rev()
+            .find(|m| m.role == Role::User)
+            .map_or("", |m| m.content.as_str());
+
+        Self::check(question)?;
+
+        let last = messages.last().filter(|m| m.role == Role::Tool);
+        let call = question.strip_prefix("!tool ").filter(|_| last.is_none());
+
+        let content = match (last, call) {
+            (Some(result), _) => format!("The tool said: {}", result.content),
+            (None, Some(_)) => String::new(),
+            (None, None) => format!("You said: {question}"),
+        };
+        let words = |s: &str| s.split_whitespace().count() as u64;
+        let prompt_tokens = messages.iter().map(|m| words(&m.content)).sum();
+        let completion_tokens = words(&content);
+
+        let tool_calls = call.map(|call| {
+            let (name, arguments) = call.split_once(' ').unwrap_or((call, "{}"));
+            ToolCall {
+                id: "call_mock".into(),
+                type_: ToolType::Function,
+                function: FunctionCall {
+                    name: name.into(),
+                    arguments: arguments.into(),
+                },
+            }
+        });
+
+        Ok(Answer {
+            content,
+            tool_calls: tool_calls.into_iter().collect(),
+            usage: Some(Usage {
+                prompt_tokens,
+                completion_tokens,
+                total_tokens: prompt_tokens + completion_tokens,
+            }),
+        })
+    }
+
+    // Fails the way the input asks for.
+    fn check(input: &str) -> Result<(), Attempt> {
+        if let Some(code) = input.strip_prefix("!status ") {
+            let status = code
+                .trim()
+                .parse::<u16>()
+                .ok()
+                .and_then(|c| StatusCode::from_u16(c).ok())
+                .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+            return Err(Error::Status(status).into());
+        }
+
+        if let Some(message) = input.strip_prefix("!error ") {
+            return Err(Error::Api(message.into()).into());
+        }
+
+        Ok(())
+    }
+
+    fn embedding(input: &str) -> Result<Embedding, Attempt> {
+        Self::check(input)?;
+
+        let mut embedding = vec![0.0; DIMENSIONS];
+        for word in input.split(|c: char| !c.is_alphanumeric()) {
+            if word.is_empty() {
+                continue;
+            }
+
+            // FNV-1a, which doesn't change between builds.
+            let hash = word
+                .to_lowercase()
+                .bytes()
+                .fold(0xcbf2_9ce4_8422_2325, |h, b| {
+                    (h ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+                });
+            embedding[(hash % DIMENSIONS as u64) as usize] += 1.0;
+        }
+
+        Ok(embedding)
+    }
+}
+
+impl LanguageModel for Mock {
+    fn chat<'a>(&'a self, messages: &'a [Message]) -> BoxFuture<'a, Result<Answer, Attempt>> {
+        Box::pin(std::future::ready(Self::answer(messages)))
+    }
+
+    fn chat_stream<'a>(
+        &'a self,
+        messages: &'a [Message],
+    ) -> BoxFuture<'a, Result<BoxStream<'a, Result<Answer, Error>>, Attempt>> {
+        let pieces = Self::answer(messages).map(|answer| {
+            let words: Vec<_> = answer
+                .content
+                .split_inclusive(' ')
+                .map(String::from)
+                .collect();
+            let last = words.len().saturating_sub(1);
+
+            let pieces = words.into_iter().enumerate().map(move |(i, content)| {
+                Ok(Answer::new(content, answer.usage.filter(|_| i == last)))
+            });
+            stream::iter(pieces).boxed()
+        });
+
+        Box::pin(std::future::ready(pieces))
+    }
+
+    fn embed<'a>(&'a self, inputs: &'a [String]) -> BoxFuture<'a, Result<Vec<Embedding>, Attempt>> {
+        let embeddings = inputs.iter().map(|input| Self::embedding(input)).collect();
+        Box::pin(std::future::ready(embeddings))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use super::super::{Answer, Breakers, Config, Error, Message, Role, Worker};
+    use super::Mock;
+
+    async fn ask(question: &str) -> Result<Answer, Error> {
+        let config = Config::default();
+        let worker = Worker {
+            model: Arc::new(Mock),
+            system_prompt: config.system_prompt.clone(),
+            retries: 0,
+            breakers: Arc::new(Breakers::new(&config)),
+        };
+
+        let messages = worker.messages(None, vec![Message::new(Role::User, question)]);
+        let deadline = Instant::now() + Duration::from_secs(1);
+        worker.ask(&messages, deadline).await
+    }
+
+    #[tokio::test]
+    async fn echoes_the_question() {
+        let answer = ask("is anyone there").await.expect("the mock answers");
+
+        assert_eq!(answer.content, "You said: is anyone there");
+        assert!(answer.tool_calls.is_empty());
+        let usage = answer.usage.expect("the mock counts words");
+        assert_eq!(usage.completion_tokens, 5);
+        assert_eq!(usage.total_tokens, usage.prompt_tokens + 5);
+    }
+
+    #[tokio::test]
+    async fn fails_with_the_status() {
+        let error = ask("!status 503").await.expect_err("the mock fails");
+        assert!(
+            matches!(error, Error::Status(s) if s.as_u16() == 503),
+            "{error:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn fails_with_the_error() {
+        let error = ask("!error model is overloaded")
+            .await
+            .expect_err("the mock fails");
+        assert!(
+            matches!(&error, Error::Api(m) if m == "model is overloaded"),
+            "{error:?}"
+        );
+    }
+}
@@ -0,0 +1,187 @@
+use futures_util::{future::ready, StreamExt};
+use std::time::Duration;
+
+use super::{
+    Answer, Attempt, BoxFuture, BoxStream, Config, Embedding, Error, LanguageModel, Message, Usage,
+};
+
+const DEFAULT_URL: &str = "http://127.0.0.1:11434";
+pub(super) const DEFAULT_MODEL: &str = "llama3";
+pub(super) const DEFAULT_EMBEDDING_MODEL: &str = "nomic-embed-text";
+
+#[derive(Debug, serde::Serialize)]
+struct Request<'a> {
+    model: &'a str,
+    messages: &'a [Message],
+    stream: bool,
+    options: Options,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct Options {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    num_predict: Option<u32>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct EmbedRequest<'a> {
+    model: &'a str,
+    input: &'a [String],
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(untagged)]
+enum EmbedResponse {
+    Error { error: String },
+    Success { embeddings: Vec<Embedding> },
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(untagged)]
+enum Response {
+    Error {
+        error: String,
+    },
+    Success {
+        message: Message,
+        #[serde(default)]
+        prompt_eval_count: u64,
+        #[serde(default)]
+        eval_count: u64,
+        // Streamed answers only have the counts in the last line.
+        #[serde(default)]
+        done: bool,
+    },
+}
+
+impl Response {
+    fn into_answer(self) -> Result<Answer, Error> {
+        match self {
+            Self::Error { error } => Err(Error::Api(error)),
+            Self::Success {
+                message,
+                prompt_eval_count,
+                eval_count,
+                done,
+            } => {
+                let usage = done.then_some(Usage {
+                    prompt_tokens: prompt_eval_count,
+                    completion_tokens: eval_count,
+                    total_tokens: prompt_eval_count + eval_count,
+                });
+                Ok(Answer::new(message.content, usage))
+            }
+        }
+    }
+}
+
+/// Ollama's native chat endpoint, which exposes its own options.
+pub struct Ollama {
+    client: reqwest::Client,
+    url: String,
+    model: String,
+    embed_url: String,
+    embedding_model: String,
+    temperature: Option<f32>,
+    max_tokens: Option<u32>,
+    timeout: Duration,
+}
+
+impl Ollama {
+    pub fn new(client: reqwest::Client, config: &Config) -> Self {
+        let url = config.url.as_deref().unwrap_or(DEFAULT_URL);
+        let url = url.trim_end_matches('/');
+
+        Self {
+            client,
+            url: format!("{url}/api/chat"),
+            model: config.model_name().into(),
+            embed_url: format!("{url}/api/embed"),
+            embedding_model: config.embedding_model_name().into(),
+            temperature: config.temperature,
+            max_tokens: config.max_tokens,
+            timeout: config.timeout,
+        }
+    }
+
+    async fn send(&self, messages: &[Message], stream: bool) -> Result<reqwest::Response, Attempt> {
+        let request = Request {
+            model: &self.model,
+            messages,
+            stream,
+            options: Options {
+                temperature: self.temperature,
+                num_predict: self.max_tokens,
+            },
+        };
+
+        let request = self.client.post(&self.url).json(&request);
+        let response = super::send(request, self.timeout, stream).await?;
+
+        // Errors other than these come with a JSON body.
+        super::check_status(response)
+    }
+
+    async fn do_chat(&self, messages: &[Message]) -> Result<Answer, Attempt> {
+        let response = self.send(messages, false).await?;
+        let response = response.json::<Response>().await?;
+
+        Ok(response.into_answer()?)
+    }
+
+    async fn do_chat_stream(
+        &self,
+        messages: &[Message],
+    ) -> Result<BoxStream<'static, Result<Answer, Error>>, Attempt> {
+        let response = self.send(messages, true).await?;
+
+        // One JSON object per line.
+        let pieces = super::lines(response, self.timeout).filter_map(|line| {
+            ready(match line {
+                Ok(line) if line.is_empty() => None,
+                Ok(line) => Some(match serde_json::from_str::<Response>(&line) {
+                    Ok(response) => response.into_answer(),
+                    Err(e) => Err(Error::Decode(e.to_string())),
+                }),
+                Err(e) => Some(Err(e)),
+            })
+        });
+
+        Ok(pieces.boxed())
+    }
+
+    async fn do_embed(&self, inputs: &[String]) -> Result<Vec<Embedding>, Attempt> {
+        let request = EmbedRequest {
+            model: &self.embedding_model,
+            input: inputs,
+        };
+
+        let request = self.client.post(&self.embed_url).json(&request);
+        let response = super::send(request, self.timeout, false).await?;
+        let response = super::check_status(response)?;
+
+        match response.json::<EmbedResponse>().await? {
+            EmbedResponse::Error { error } => Err(Error::Api(error).into()),
+            EmbedResponse::Success { embeddings } => Ok(embeddings),
+        }
+    }
+}
+
+impl LanguageModel for Ollama {
+    fn chat<'a>(&'a self, messages: &'a [Message]) -> BoxFuture<'a, Result<Answer, Attempt>> {
+        Box::pin(self.do_chat(messages))
+    }
+
+    fn chat_stream<'a>(
+        &'a self,
+        messages: &'a [Message],
+    ) -> BoxFuture<'a, Result<BoxStream<'a, Result<Answer, Error>>, Attempt>> {
+        Box::pin(async move { self.do_chat_stream(messages).await })
+    }
+
+    fn embed<'a>(&'a self, inputs: &'a [String]) -> BoxFuture<'a, Result<Vec<Embedding>, Attempt>> {
+        Box::pin(self.do_embed(inputs))
+    }
+}
@@ -0,0 +1,320 @@
+use futures_util::{future::ready, StreamExt};
+use reqwest::StatusCode;
+use std::time::Duration;
+
+use super::{
+    Answer, Attempt, BoxFuture, BoxStream, Config, Embedding, Error, LanguageModel, Message, Tool,
+    Usage,
+};
+
+const DEFAULT_URL: &str = "https://api.openai.com/v1";
+pub(super) const DEFAULT_MODEL: &str = "gpt-3.5-turbo";
+pub(super) const DEFAULT_EMBEDDING_MODEL: &str = "text-embedding-3-small";
+
+#[derive(Debug, serde::Serialize)]
+struct Request<'a> {
+    model: &'a str,
+    messages: &'a [Message],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<StreamOptions>,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    tools: &'a [ToolSpec],
+}
+
+#[derive(Debug, serde::Serialize)]
+struct EmbeddingRequest<'a> {
+    model: &'a str,
+    input: &'a [String],
+}
+
+#[derive(Debug, serde::Serialize)]
+struct ToolSpec {
+    #[serde(rename = "type")]
+    type_: super::ToolType,
+    function: Tool,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct StreamOptions {
+    // Otherwise streamed answers come without usage.
+    include_usage: bool,
+}
+
+// The body of every response with an error status.
+#[derive(Debug, serde::Deserialize)]
+struct ErrorBody {
+    error: ErrorResponse,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ErrorResponse {
+    message: String,
+    #[serde(rename = "type")]
+    type_: Option<String>,
+    // param: null,
+    // A string for OpenAI, but some compatible servers use numbers.
+    code: Option<serde_json::Value>,
+}
+
+impl ErrorResponse {
+    fn into_error(self, status: StatusCode) -> Error {
+        let code = self.code.as_ref().and_then(|c| c.as_str());
+        let type_ = self.type_.as_deref();
+
+        match (code, type_) {
+            (Some("insufficient_quota"), _) | (_, Some("insufficient_quota")) => {
+                Error::InsufficientQuota
+            }
+            (Some("rate_limit_exceeded"), _) => Error::RateLimited,
+            (Some("invalid_api_key"), _) => Error::InvalidApiKey,
+            (Some("context_length_exceeded"), _) => Error::ContextLengthExceeded,
+            _ if status == StatusCode::TOO_MANY_REQUESTS => Error::RateLimited,
+            _ if status == StatusCode::UNAUTHORIZED => Error::InvalidApiKey,
+            _ if status.is_server_error() => Error::Status(status),
+            _ => Error::Api(self.message),
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct SuccessResponse {
+    // id: String,
+    // object: String, // "chat.completion" -- enum?
+    // created: u64, // 1677652288,
+    choices: Vec<Choice>,
+    // Some compatible servers leave it out.
+    usage: Option<Usage>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct Choice {
+    // index: u64,
+    message: Message,
+    // finish_reason: String // "stop" -- enum?
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct EmbeddingResponse {
+    data: Vec<EmbeddingData>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct EmbeddingData {
+    index: usize,
+    embedding: Embedding,
+}
+
+// Each `data:` line of a streamed answer has one of these.
+#[derive(Debug, serde::Deserialize)]
+#[serde(untagged)]
+enum StreamResponse {
+    Error { error: ErrorResponse },
+    Chunk(ChunkResponse),
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ChunkResponse {
+    #[serde(default)]
+    choices: Vec<ChunkChoice>,
+    // Only in the last chunk, which has no choices.
+    usage: Option<Usage>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ChunkChoice {
+    delta: Delta,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct Delta {
+    content: Option<String>,
+}
+
+/// Any server with an OpenAI-compatible chat completions endpoint.
+pub struct OpenAi {
+    client: reqwest::Client,
+    url: String,
+    model: String,
+    embeddings_url: String,
+    embedding_model: String,
+    temperature: Option<f32>,
+    max_tokens: Option<u32>,
+    tools: Vec<ToolSpec>,
+    timeout: Duration,
+}
+
+impl OpenAi {
+    pub fn new(client: reqwest::Client, config: &Config) -> Self {
+        let url = config.url.as_deref().unwrap_or(DEFAULT_URL);
+        let url = url.trim_end_matches('/');
+
+        Self {
+            client,
+            url: format!("{url}/chat/completions"),
+            model: config.model_name().into(),
+            embeddings_url: format!("{url}/embeddings"),
+            embedding_model: config.embedding_model_name().into(),
+            temperature: config.temperature,
+            max_tokens: config.max_tokens,
+            tools: config
+                .tools
+                .iter()
+                .map(|tool| ToolSpec {
+                    type_: super::ToolType::Function,
+                    function: tool.clone(),
+                })
+                .collect(),
+            timeout: config.timeout,
+        }
+    }
+
+    async fn send(
+        &self,
+        url: &str,
+        request: &impl serde::Serialize,
+        stream: bool,
+    ) -> Result<reqwest::Response, Attempt> {
+        let request = self.client.post(url).json(request);
+        let response = super::send(request, self.timeout, stream).await?;
+
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+
+        let retry_after = super::retry_after(&response);
+        let body = response.text().await?;
+
+        // Proxies in front of the API may answer with anything.
+        let error = match serde_json::from_str::<ErrorBody>(&body) {
+            Ok(body) => body.error.into_error(status),
+            Err(_) => Error::Status(status),
+        };
+
+        Err(Attempt { error, retry_after })
+    }
+
+    async fn do_chat(&self, messages: &[Message]) -> Result<Answer, Attempt> {
+        let request = Request {
+            model: &self.model,
+            messages,
+            temperature: self.temperature,
+            max_tokens: self.max_tokens,
+            stream: false,
+            stream_options: None,
+            tools: &self.tools,
+        };
+
+        let response = self.send(&self.url, &request, false).await?;
+        let mut success = response.json::<SuccessResponse>().await?;
+
+        let Some(choice) = success.choices.pop() else {
+            let content = "I have nothing to say for that".into();
+            return Ok(Answer::new(content, success.usage));
+        };
+        Ok(Answer {
+            content: choice.message.content,
+            tool_calls: choice.message.tool_calls,
+            usage: success.usage,
+        })
+    }
+
+    async fn do_chat_stream(
+        &self,
+        messages: &[Message],
+    ) -> Result<BoxStream<'static, Result<Answer, Error>>, Attempt> {
+        let request = Request {
+            model: &self.model,
+            messages,
+            temperature: self.temperature,
+            max_tokens: self.max_tokens,
+            stream: true,
+            stream_options: Some(StreamOptions {
+                include_usage: true,
+            }),
+            // Calls would arrive in pieces too.
+            tools: &[],
+        };
+
+        let response = self.send(&self.url, &request, true).await?;
+
+        // Server-sent events; the answer ends with `data: [DONE]`.
+        let pieces = super::lines(response, self.timeout)
+            .take_while(|line| ready(!matches!(line, Ok(l) if Self::data(l) == Some("[DONE]"))))
+            .filter_map(|line| ready(Self::piece(line)));
+
+        Ok(pieces.boxed())
+    }
+
+    async fn do_embed(&self, inputs: &[String]) -> Result<Vec<Embedding>, Attempt> {
+        let request = EmbeddingRequest {
+            model: &self.embedding_model,
+            input: inputs,
+        };
+
+        let response = self.send(&self.embeddings_url, &request, false).await?;
+        let mut response = response.json::<EmbeddingResponse>().await?;
+
+        // The order isn't promised, the index is.
+        response.data.sort_by_key(|d| d.index);
+        Ok(response.data.into_iter().map(|d| d.embedding).collect())
+    }
+
+    fn data(line: &str) -> Option<&str> {
+        line.strip_prefix("data:").map(str::trim)
+    }
+
+    // Blank lines, comments and other fields carry nothing for us.
+    fn piece(line: Result<String, Error>) -> Option<Result<Answer, Error>> {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => return Some(Err(e)),
+        };
+        let data = Self::data(&line)?;
+
+        let chunk = match serde_json::from_str::<StreamResponse>(data) {
+            Ok(chunk) => chunk,
+            Err(e) => return Some(Err(Error::Decode(e.to_string()))),
+        };
+
+        match chunk {
+            StreamResponse::Error { error } => Some(Err(error.into_error(StatusCode::OK))),
+
+            StreamResponse::Chunk(chunk) => {
+                let content: String = chunk
+                    .choices
+                    .into_iter()
+                    .filter_map(|c| c.delta.content)
+                    .collect();
+
+                if content.is_empty() && chunk.usage.is_none() {
+                    return None;
+                }
+                Some(Ok(Answer::new(content, chunk.usage)))
+            }
+        }
+    }
+}
+
+impl LanguageModel for OpenAi {
+    fn chat<'a>(&'a self, messages: &'a [Message]) -> BoxFuture<'a, Result<Answer, Attempt>> {
+        Box::pin(self.do_chat(messages))
+    }
+
+    fn chat_stream<'a>(
+        &'a self,
+        messages: &'a [Message],
+    ) -> BoxFuture<'a, Result<BoxStream<'a, Result<Answer, Error>>, Attempt>> {
+        Box::pin(async move { self.do_chat_stream(messages).await })
+    }
+
+    fn embed<'a>(&'a self, inputs: &'a [String]) -> BoxFuture<'a, Result<Vec<Embedding>, Attempt>> {
+        Box::pin(self.do_embed(inputs))
+    }
+}
@@ -0,0 +1,121 @@
+use opensips::log::{self, Level};
+use std::{
+    ffi::{CStr, CString},
+    fmt::Write,
+    os::raw::c_char,
+};
+use tracing_core::{span, subscriber::Interest, Event, Metadata, Subscriber};
+use tracing_subscriber::{
+    fmt::{
+        format::{DefaultFields, Writer},
+        FormatFields, FormattedFields,
+    },
+    layer::{Context, Layer},
+    registry::LookupSpan,
+};
+
+/// Sends events through the OpenSIPS logging (`LM_*`), so they end up
+/// in the same place as the logs of C modules and honor the OpenSIPS
+/// log settings.
+///
+/// TARGET: SPAN{FIELDS}: <message>
+pub struct OpenSipsLayer {
+    fields: DefaultFields,
+}
+
+impl OpenSipsLayer {
+    pub fn new() -> Self {
+        Self {
+            fields: DefaultFields::new(),
+        }
+    }
+}
+
+const MODULE_NAME: *const c_char = opensips::cstr_lit!("rust-experiment");
+
+fn opensips_level(level: &tracing_core::Level) -> Level {
+    match *level {
+        tracing_core::Level::ERROR => Level::Error,
+        tracing_core::Level::WARN => Level::Warning,
+        tracing_core::Level::INFO => Level::Info,
+        tracing_core::Level::DEBUG | tracing_core::Level::TRACE => Level::Debug,
+    }
+}
+
+impl<S> Layer<S> for OpenSipsLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn register_callsite(&self, _metadata: &'static Metadata<'static>) -> Interest {
+        // The OpenSIPS log level can be changed at runtime, so we
+        // can't cache the answer.
+        Interest::sometimes()
+    }
+
+    fn enabled(&self, metadata: &Metadata<'_>, _ctx: Context<'_, S>) -> bool {
+        log::is_printable(opensips_level(metadata.level()))
+    }
+
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let span = ctx.span(id).expect("will never be `None`");
+        let mut ext = span.extensions_mut();
+
+        if ext.get_mut::<FormattedFields<DefaultFields>>().is_none() {
+            let mut fields = FormattedFields::<DefaultFields>::new(String::new());
+            if self.fields.format_fields(fields.as_writer(), attrs).is_ok() {
+                ext.insert(fields);
+            }
+        }
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        let span = ctx.span(id).expect("will never be `None`");
+        let mut ext = span.extensions_mut();
+
+        if let Some(fields) = ext.get_mut::<FormattedFields<DefaultFields>>() {
+            let _ = self.fields.add_fields(fields, values);
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        let mut message = String::new();
+        let mut func = "";
+
+        let _ = write!(message, "{}: ", metadata.target());
+
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                let _ = write!(message, "{}", span.name());
+
+                let ext = span.extensions();
+                if let Some(fields) = ext.get::<FormattedFields<DefaultFields>>() {
+                    if !fields.is_empty() {
+                        let _ = write!(message, "{{{}}}", fields);
+                    }
+                }
+                let _ = write!(message, ": ");
+
+                func = span.name();
+            }
+        }
+
+        let _ = self.fields.format_fields(Writer::new(&mut message), event);
+
+        let func = CString::new(func).unwrap_or_default();
+        // SAFETY: `cstr_lit!` adds the NUL byte.
+        let module = unsafe { CStr::from_ptr(MODULE_NAME) };
+        log::print(opensips_level(metadata.level()), module, &func, &message);
+    }
+}
+
+/// Logs straight through OpenSIPS, for problems with our own logging.
+pub fn error(message: &str) {
+    if !log::is_printable(Level::Error) {
+        return;
+    }
+
+    // SAFETY: `cstr_lit!` adds the NUL byte.
+    let module = unsafe { CStr::from_ptr(MODULE_NAME) };
+    log::print(Level::Error, module, Default::default(), message);
+}
@@ -0,0 +1,312 @@
+use crate::llm::Usage;
+use opensips::{
+    map::{unix_time, Map},
+    shared::{Shared, ShmSafe},
+};
+use std::str::FromStr;
+use tracing::warn;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Who is charged for a question.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum Key {
+    /// The user part of the From URI, or the source address of
+    /// requests without one
+    #[default]
+    FromUser,
+    /// The IP address the request came from
+    Source,
+}
+
+impl FromStr for Key {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "from-user" => Ok(Self::FromUser),
+            "source" => Ok(Self::Source),
+            _ => Err(format!(
+                "Unknown quota key `{s}`, expected `from-user` or `source`"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub key: Key,
+    /// Tokens each caller may use per (UTC) day. Without it, usage
+    /// is only counted.
+    pub daily_limit: Option<u64>,
+    /// Callers beyond this aren't counted, and are refused when
+    /// there is a limit.
+    pub max_callers: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            key: Key::default(),
+            daily_limit: None,
+            max_callers: 10000,
+        }
+    }
+}
+
+/// The usage of one caller on one day.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Tally {
+    /// Days since the Unix epoch
+    pub day: u64,
+    pub requests: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+}
+
+// SAFETY: There are only integers.
+unsafe impl ShmSafe for Tally {}
+
+impl Tally {
+    /// The tokens used on `day`; none when the tally is of another.
+    fn used_on(&self, day: u64) -> u64 {
+        if self.day == day {
+            self.total_tokens
+        } else {
+            0
+        }
+    }
+
+    /// Whether another question may be asked on `day`, until `limit`
+    /// tokens have been used.
+    fn allows(&self, day: u64, limit: u64) -> bool {
+        self.used_on(day) < limit
+    }
+
+    /// Counts a question asked on `day`, starting over on a new one.
+    fn add(&mut self, day: u64, usage: Usage) {
+        if self.day != day {
+            *self = Tally {
+                day,
+                ..Default::default()
+            };
+        }
+
+        self.requests += 1;
+        self.prompt_tokens += usage.prompt_tokens;
+        self.completion_tokens += usage.completion_tokens;
+        self.total_tokens += usage.total_tokens;
+    }
+}
+
+/// Token usage per caller, shared by all processes.
+///
+/// Usage is only known once the LLM has answered, so concurrent
+/// questions of the same caller can overshoot the limit a little.
+#[derive(Debug, Clone)]
+pub struct Quotas {
+    config: Config,
+    tallies: Shared<Map<Tally>>,
+}
+
+impl Quotas {
+    /// Allocates the tallies in shared memory, see [`Map::new`].
+    pub fn new(config: Config) -> Option<Self> {
+        let tallies = Shared::new(Map::new()?)?;
+        Some(Self { config, tallies })
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Who `msg` is charged to. Leaving out the user part of the
+    /// From URI doesn't get around the limit.
+    pub fn caller(&self, msg: &mut opensips::sip_msg) -> Option<String> {
+        let source = msg.source().map(|s| s.ip().to_string());
+        caller_of(
+            self.config.key,
+            || msg.from_user().map(String::from),
+            source,
+        )
+    }
+
+    /// Whether `caller` may ask another question today.
+    pub fn allows(&self, caller: &str) -> bool {
+        let Some(limit) = self.config.daily_limit else {
+            return true;
+        };
+        let today = today();
+
+        match self.tallies.get(caller, |t| t.allows(today, limit)) {
+            Some(allowed) => allowed,
+            // Callers that can't be counted can't be limited either.
+            None => self.tallies.len() < self.config.max_callers || self.purge(today),
+        }
+    }
+
+    pub fn record(&self, caller: &str, usage: Usage) {
+        let today = today();
+        let full = self.tallies.len() >= self.config.max_callers && !self.purge(today);
+
+        let recorded = self.tallies.update(caller, |tally| {
+            if tally.is_none() && full {
+                return false;
+            }
+            tally.get_or_insert_with(Tally::default).add(today, usage);
+            true
+        });
+
+        match recorded {
+            Ok(true) => {}
+            Ok(false) => warn!("Not counting the usage of {caller}: too many callers"),
+            Err(e) => warn!("Not counting the usage of {caller}: {e}"),
+        }
+    }
+
+    /// Today's usage of every caller.
+    pub fn tallies(&self) -> Vec<(String, Tally)> {
+        let today = today();
+        let mut tallies = Vec::new();
+
+        self.tallies.retain(|caller, t| {
+            if t.day == today {
+                tallies.push((caller.into(), *t));
+            }
+            true
+        });
+
+        tallies
+    }
+
+    /// Forgets the usage of `caller`, or of everyone.
+    pub fn reset(&self, caller: Option<&str>) {
+        match caller {
+            Some(caller) => {
+                self.tallies.remove(caller);
+            }
+            None => self.tallies.clear(),
+        }
+    }
+
+    // Forgets the callers of earlier days, returning if that made
+    // room for another one.
+    fn purge(&self, today: u64) -> bool {
+        self.tallies.retain(|_, t| t.day == today);
+        self.tallies.len() < self.config.max_callers
+    }
+}
+
+fn today() -> u64 {
+    unix_time() / SECONDS_PER_DAY
+}
+
+/// The caller according to `key`, falling back to the `source`
+/// address.
+fn caller_of(
+    key: Key,
+    from_user: impl FnOnce() -> Option<String>,
+    source: Option<String>,
+) -> Option<String> {
+    let user = match key {
+        Key::FromUser => from_user(),
+        Key::Source => None,
+    };
+    user.or(source)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{caller_of, Key, Tally};
+    use crate::llm::Usage;
+
+    fn usage(prompt_tokens: u64, completion_tokens: u64) -> Usage {
+        Usage {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+        }
+    }
+
+    #[test]
+    fn parses_keys() {
+        assert_eq!("from-user".parse(), Ok(Key::FromUser));
+        assert_eq!("source".parse(), Ok(Key::Source));
+        assert!("to-user".parse::<Key>().is_err());
+    }
+
+    #[test]
+    fn charges_the_from_user() {
+        let caller = caller_of(
+            Key::FromUser,
+            || Some("alice".into()),
+            Some("10.0.0.1".into()),
+        );
+        assert_eq!(caller.as_deref(), Some("alice"));
+    }
+
+    #[test]
+    fn charges_the_source_without_a_from_user() {
+        let caller = caller_of(Key::FromUser, || None, Some("10.0.0.1".into()));
+        assert_eq!(caller.as_deref(), Some("10.0.0.1"));
+        assert_eq!(caller_of(Key::FromUser, || None, None), None);
+    }
+
+    #[test]
+    fn charges_the_source_by_key() {
+        let caller = caller_of(
+            Key::Source,
+            || panic!("The From user isn't looked at"),
+            Some("10.0.0.1".into()),
+        );
+        assert_eq!(caller.as_deref(), Some("10.0.0.1"));
+    }
+
+    #[test]
+    fn adds_up_a_day() {
+        let mut tally = Tally::default();
+        tally.add(100, usage(10, 5));
+        tally.add(100, usage(20, 7));
+
+        assert_eq!(tally.day, 100);
+        assert_eq!(tally.requests, 2);
+        assert_eq!(tally.prompt_tokens, 30);
+        assert_eq!(tally.completion_tokens, 12);
+        assert_eq!(tally.total_tokens, 42);
+        assert_eq!(tally.used_on(100), 42);
+    }
+
+    #[test]
+    fn starts_over_the_next_day() {
+        let mut tally = Tally::default();
+        tally.add(100, usage(10, 5));
+        assert_eq!(tally.used_on(101), 0);
+
+        tally.add(101, usage(1, 2));
+        assert_eq!(tally.day, 101);
+        assert_eq!(tally.requests, 1);
+        assert_eq!(tally.total_tokens, 3);
+        assert_eq!(tally.used_on(100), 0);
+    }
+
+    #[test]
+    fn allows_until_the_limit() {
+        let mut tally = Tally::default();
+        tally.add(100, usage(30, 10));
+
+        assert!(tally.allows(100, 41));
+        assert!(!tally.allows(100, 40));
+        assert!(!tally.allows(100, 39));
+        assert!(!tally.allows(100, 0));
+    }
+
+    #[test]
+    fn allows_again_the_next_day() {
+        let mut tally = Tally::default();
+        tally.add(100, usage(300, 100));
+
+        assert!(!tally.allows(100, 40));
+        assert!(tally.allows(101, 40));
+    }
+}
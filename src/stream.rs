@@ -0,0 +1,99 @@
+use crate::im;
+use opensips::tm::Dialog;
+use tracing::{debug, warn};
+
+/// Sends an answer as it arrives in MESSAGE requests within a
+/// dialog. Pieces are collected until they end a sentence and are at
+/// least `min_len` bytes long, so the caller isn't sent every word
+/// on its own. A `min_len` of 0 is taken as 1.
+pub struct Messages<'a> {
+    dialog: Dialog<'a>,
+    min_len: usize,
+    pending: String,
+    sent: usize,
+}
+
+impl<'a> Messages<'a> {
+    pub fn new(dialog: Dialog<'a>, min_len: usize) -> Self {
+        Self {
+            dialog,
+            min_len: min_len.max(1),
+            pending: String::new(),
+            sent: 0,
+        }
+    }
+
+    pub fn push(&mut self, piece: &str) {
+        self.pending.push_str(piece);
+        if is_ready(&self.pending, self.min_len) {
+            self.flush();
+        }
+    }
+
+    /// Sends whatever is left, returning how many messages were sent.
+    pub fn finish(mut self) -> usize {
+        self.flush();
+        self.sent
+    }
+
+    fn flush(&mut self) {
+        let body = self.pending.trim();
+        if !body.is_empty() {
+            if self.dialog.request("MESSAGE", im::HEADERS, body) {
+                self.sent += 1;
+                debug!("Sent {} bytes of the answer", body.len());
+            } else {
+                warn!("Unable to send part of the answer");
+            }
+        }
+        self.pending.clear();
+    }
+}
+
+/// Whether `pending` is worth a message of its own.
+fn is_ready(pending: &str, min_len: usize) -> bool {
+    let ends_sentence = pending
+        .trim_end_matches(' ')
+        .ends_with(['.', '!', '?', '\n']);
+
+    // Don't wait forever for the end of a sentence.
+    (pending.len() >= min_len && ends_sentence) || pending.len() >= min_len.saturating_mul(4)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_ready;
+
+    #[test]
+    fn waits_for_the_end_of_a_sentence() {
+        assert!(!is_ready("The dog", 5));
+        assert!(!is_ready("The dog is", 5));
+        assert!(is_ready("The dog is here.", 5));
+        assert!(is_ready("Is it? ", 5));
+        assert!(is_ready("Woof!", 5));
+        assert!(is_ready("One\n", 4));
+    }
+
+    #[test]
+    fn waits_for_enough_bytes() {
+        assert!(!is_ready("Hi.", 5));
+        assert!(!is_ready("Yes. ", 6));
+        assert!(is_ready("Hello.", 6));
+    }
+
+    #[test]
+    fn sends_long_sentences_at_four_times_the_minimum() {
+        assert!(!is_ready("a b c d e f g h i", 5));
+        assert!(!is_ready("abcdefghijklmnopqrs", 5));
+        assert!(is_ready("abcdefghijklmnopqrst", 5));
+        assert!(is_ready("abcdefghijklmnopqrstu", 5));
+    }
+
+    #[test]
+    fn sends_every_sentence_with_the_smallest_minimum() {
+        assert!(is_ready("a.", 1));
+        assert!(!is_ready("abc", 1));
+        assert!(is_ready("abcd", 1));
+        assert!(!is_ready("", 1));
+    }
+}
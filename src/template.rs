@@ -0,0 +1,255 @@
+use opensips::pv;
+use std::str::FromStr;
+
+/// A prompt with placeholders which are filled in from each request.
+///
+/// `{name}` is one of the [`Field`]s of the SIP message and anything
+/// starting with `$` and a letter or `(` is a script variable, e.g.
+/// `$var(topic)`; any other `$`, as in `$5`, is plain text. `{{`, `}}`
+/// and `$$` stand for themselves. Placeholders without a value are
+/// left empty.
+#[derive(Debug)]
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Segment<V = pv::Spec> {
+    Text(String),
+    Field(Field),
+    Variable(V),
+}
+
+/// The parts of a SIP message a template can refer to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Field {
+    /// The X-ChatGPT header.
+    Query,
+    FromDisplay,
+    FromUser,
+    RuriUser,
+    UserAgent,
+    /// The body, e.g. the text of a MESSAGE request.
+    Body,
+    /// The text of a MESSAGE request, unwrapped from CPIM.
+    Text,
+}
+
+impl FromStr for Field {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "query" => Ok(Self::Query),
+            "from_display" => Ok(Self::FromDisplay),
+            "from_user" => Ok(Self::FromUser),
+            "ruri_user" => Ok(Self::RuriUser),
+            "user_agent" => Ok(Self::UserAgent),
+            "body" => Ok(Self::Body),
+            "text" => Ok(Self::Text),
+            _ => Err(format!(
+                "Unknown template field `{{{s}}}`, expected `query`, `from_display`, \
+                 `from_user`, `ruri_user`, `user_agent`, `body` or `text`"
+            )),
+        }
+    }
+}
+
+impl Field {
+    fn value(self, msg: &mut opensips::sip_msg) -> Option<&str> {
+        match self {
+            Self::Query => crate::chatgpt_query(msg),
+            Self::FromDisplay => msg.from_display(),
+            Self::FromUser => msg.from_user(),
+            Self::RuriUser => msg.ruri_user(),
+            Self::UserAgent => msg.user_agent(),
+            Self::Body => msg.body(),
+            Self::Text => crate::im::text(msg).ok().flatten(),
+        }
+    }
+}
+
+impl Template {
+    /// Script variables are parsed by OpenSIPS, so this has to be
+    /// called during module initialization.
+    pub fn parse(template: &str) -> Result<Self, String> {
+        let segments = segments(template, pv::Spec::parse_prefix)?;
+        Ok(Self { segments })
+    }
+
+    pub fn render(&self, msg: &mut opensips::sip_msg) -> String {
+        let mut rendered = String::new();
+
+        for segment in &self.segments {
+            match segment {
+                Segment::Text(text) => rendered.push_str(text),
+                Segment::Field(field) => {
+                    if let Some(value) = field.value(msg) {
+                        rendered.push_str(value);
+                    }
+                }
+                Segment::Variable(spec) => {
+                    if let Some(value) = spec.get(msg) {
+                        rendered.push_str(&value);
+                    }
+                }
+            }
+        }
+
+        rendered
+    }
+}
+
+/// Splits `template` up, parsing script variables with `variable`,
+/// which returns the variable at the start of its argument and the
+/// number of bytes it spans.
+fn segments<V>(
+    template: &str,
+    variable: impl Fn(&str) -> Option<(V, usize)>,
+) -> Result<Vec<Segment<V>>, String> {
+    let mut segments = Vec::new();
+    let mut text = String::new();
+    let mut rest = template;
+
+    while let Some(i) = rest.find(['{', '}', '$']) {
+        text.push_str(&rest[..i]);
+        rest = &rest[i..];
+
+        if let Some(escaped) = ["{{", "}}", "$$"].iter().find(|e| rest.starts_with(*e)) {
+            text.push_str(&escaped[..1]);
+            rest = &rest[2..];
+            continue;
+        }
+
+        let segment = if let Some(field) = rest.strip_prefix('{') {
+            let Some((field, after)) = field.split_once('}') else {
+                return Err(format!("Unclosed `{{` in the template `{template}`"));
+            };
+            rest = after;
+            Segment::Field(field.parse()?)
+        } else if rest.starts_with('}') {
+            return Err(format!("Unmatched `}}` in the template `{template}`"));
+        } else if !rest[1..].starts_with(|c: char| c.is_ascii_alphabetic() || c == '(') {
+            text.push('$');
+            rest = &rest[1..];
+            continue;
+        } else {
+            let Some((spec, len)) = variable(rest) else {
+                return Err(format!("Invalid variable in the template `{template}`"));
+            };
+            rest = &rest[len..];
+            Segment::Variable(spec)
+        };
+
+        if !text.is_empty() {
+            segments.push(Segment::Text(std::mem::take(&mut text)));
+        }
+        segments.push(segment);
+    }
+
+    text.push_str(rest);
+    if !text.is_empty() {
+        segments.push(Segment::Text(text));
+    }
+
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{segments, Field, Segment};
+
+    // Only `$var(name)`, the way OpenSIPS parses it.
+    fn var(s: &str) -> Option<(String, usize)> {
+        let name = s.strip_prefix("$var(")?;
+        let end = name.find(')')?;
+        Some((name[..end].into(), "$var()".len() + end))
+    }
+
+    fn parse(template: &str) -> Result<Vec<Segment<String>>, String> {
+        segments(template, var)
+    }
+
+    fn text(s: &str) -> Segment<String> {
+        Segment::Text(s.into())
+    }
+
+    #[test]
+    fn parses_plain_text() {
+        assert_eq!(parse("Hello"), Ok(vec![text("Hello")]));
+        assert_eq!(parse(""), Ok(vec![]));
+    }
+
+    #[test]
+    fn parses_fields() {
+        assert_eq!(
+            parse("{from_display} writes: {body}"),
+            Ok(vec![
+                Segment::Field(Field::FromDisplay),
+                text(" writes: "),
+                Segment::Field(Field::Body),
+            ])
+        );
+        assert_eq!(
+            parse("{query}{text}"),
+            Ok(vec![
+                Segment::Field(Field::Query),
+                Segment::Field(Field::Text)
+            ])
+        );
+    }
+
+    #[test]
+    fn parses_variables() {
+        assert_eq!(
+            parse("About $var(topic)."),
+            Ok(vec![
+                text("About "),
+                Segment::Variable("topic".into()),
+                text("."),
+            ])
+        );
+    }
+
+    #[test]
+    fn parses_escapes() {
+        assert_eq!(
+            parse("{{json}} costs $$5"),
+            Ok(vec![text("{json} costs $5")])
+        );
+        assert_eq!(
+            parse("{{{user_agent}}}"),
+            Ok(vec![text("{"), Segment::Field(Field::UserAgent), text("}"),])
+        );
+    }
+
+    #[test]
+    fn keeps_dollars_without_a_variable() {
+        assert_eq!(parse("It costs $5"), Ok(vec![text("It costs $5")]));
+        assert_eq!(parse("$ and $"), Ok(vec![text("$ and $")]));
+        assert_eq!(
+            parse("$1 for {ruri_user}"),
+            Ok(vec![text("$1 for "), Segment::Field(Field::RuriUser)])
+        );
+    }
+
+    #[test]
+    fn refuses_unknown_fields() {
+        let e = parse("{to_user}").expect_err("There's no `to_user` field");
+        assert!(e.contains("`{to_user}`"), "{e}");
+    }
+
+    #[test]
+    fn refuses_unbalanced_braces() {
+        let e = parse("Hello {body").expect_err("The brace isn't closed");
+        assert!(e.starts_with("Unclosed `{`"), "{e}");
+        let e = parse("Hello body}").expect_err("The brace isn't opened");
+        assert!(e.starts_with("Unmatched `}`"), "{e}");
+    }
+
+    #[test]
+    fn refuses_invalid_variables() {
+        let e = parse("About $var(topic").expect_err("The variable isn't closed");
+        assert!(e.starts_with("Invalid variable"), "{e}");
+    }
+}
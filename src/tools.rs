@@ -0,0 +1,412 @@
+use crate::llm::{self, ToolCall};
+use opensips::{pv, statistic, usrloc};
+use serde_json::{json, Value};
+use std::time::SystemTime;
+use tracing::{info, warn};
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// The names of the tools the model may call.
+    pub allowed: Vec<String>,
+    /// The usrloc table `lookup_contacts` reads.
+    pub domain: String,
+    /// Holds the address of record `lookup_contacts` may read, or a
+    /// domain whose addresses it may read. Unset, that's the From URI
+    /// of the request. A domain needs usrloc to use domains.
+    pub aor_variable: Option<String>,
+    /// Where `route_to_extension` leaves the extension for the
+    /// script.
+    pub route_variable: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            allowed: Vec::new(),
+            domain: "location".into(),
+            aor_variable: None,
+            route_variable: "$avp(chatgpt_route)".into(),
+        }
+    }
+}
+
+/// A function the model can call, run in the process handling the
+/// request.
+#[derive(Debug)]
+struct Tool {
+    name: &'static str,
+    description: &'static str,
+    /// The JSON schema of the arguments.
+    parameters: fn() -> Value,
+    call: fn(&Tools, &mut opensips::sip_msg, &Value) -> Result<Value, String>,
+}
+
+static REGISTRY: &[Tool] = &[
+    Tool {
+        name: "lookup_contacts",
+        description: "Lists the devices registered for the caller's own SIP address of record.",
+        parameters: || {
+            json!({
+                "type": "object",
+                "properties": {
+                    "aor": {
+                        "type": "string",
+                        "description": "The address of record, e.g. alice@example.com",
+                    },
+                },
+                "required": ["aor"],
+            })
+        },
+        call: lookup_contacts,
+    },
+    Tool {
+        name: "get_statistic",
+        description: "Returns the current value of an OpenSIPS statistic.",
+        parameters: || {
+            json!({
+                "type": "object",
+                "properties": {
+                    "name": {
+                        "type": "string",
+                        "description": "The statistic, e.g. rcv_requests or registered_users",
+                    },
+                },
+                "required": ["name"],
+            })
+        },
+        call: get_statistic,
+    },
+    Tool {
+        name: "route_to_extension",
+        description: "Routes the current call to an extension once the answer is given.",
+        parameters: || {
+            json!({
+                "type": "object",
+                "properties": {
+                    "extension": {
+                        "type": "string",
+                        "description": "The extension, digits only",
+                    },
+                },
+                "required": ["extension"],
+            })
+        },
+        call: route_to_extension,
+    },
+];
+
+/// The tools the model may call, and what they need.
+#[derive(Debug)]
+pub struct Tools {
+    allowed: Vec<&'static Tool>,
+    domain: String,
+    usrloc: Option<usrloc::Usrloc>,
+    aor_variable: Option<pv::Spec>,
+    route_variable: Option<pv::Spec>,
+}
+
+impl Tools {
+    /// Loads what the allowed tools need, so this has to be called
+    /// during module initialization.
+    pub fn new(config: Config) -> Result<Self, String> {
+        let allowed = config
+            .allowed
+            .iter()
+            .map(|name| {
+                REGISTRY.iter().find(|t| t.name == *name).ok_or_else(|| {
+                    let known: Vec<_> = REGISTRY.iter().map(|t| t.name).collect();
+                    format!(
+                        "Unknown tool `{name}`, expected one of {}",
+                        known.join(", ")
+                    )
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let is_allowed = |name| allowed.iter().any(|t| t.name == name);
+
+        let mut usrloc = None;
+        let mut aor_variable = None;
+        if is_allowed("lookup_contacts") {
+            let api = usrloc::load_ul_api();
+            usrloc = Some(api.ok_or("The lookup_contacts tool needs the usrloc module")?);
+            aor_variable = config
+                .aor_variable
+                .as_deref()
+                .map(|variable| parse_variable(variable, "AOR"))
+                .transpose()?;
+        }
+
+        let mut route_variable = None;
+        if is_allowed("route_to_extension") {
+            route_variable = Some(parse_variable(&config.route_variable, "route")?);
+        }
+
+        Ok(Self {
+            allowed,
+            domain: config.domain,
+            usrloc,
+            aor_variable,
+            route_variable,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.allowed.is_empty()
+    }
+
+    /// The allowed tools, described for the model.
+    pub fn specs(&self) -> Vec<llm::Tool> {
+        self.allowed
+            .iter()
+            .map(|tool| llm::Tool {
+                name: tool.name.into(),
+                description: tool.description.into(),
+                parameters: (tool.parameters)(),
+            })
+            .collect()
+    }
+
+    /// Runs the tool the model asked for, returning the result for
+    /// the model. Failures are told to the model too, which may try
+    /// something else.
+    pub fn call(&self, msg: &mut opensips::sip_msg, call: &ToolCall) -> String {
+        let name = call.function.name.as_str();
+
+        // The model could ask for anything, not only what we offered.
+        let Some(tool) = self.allowed.iter().find(|t| t.name == name) else {
+            warn!("The model called the unknown tool `{name}`");
+            return json!({ "error": "unknown tool" }).to_string();
+        };
+
+        let result = serde_json::from_str(&call.function.arguments)
+            .map_err(|e| format!("invalid arguments: {e}"))
+            .and_then(|arguments| {
+                info!("Calling the tool {name} with {arguments}");
+                (tool.call)(self, msg, &arguments)
+            });
+
+        match result {
+            Ok(result) => result.to_string(),
+            Err(e) => {
+                warn!("The tool {name} failed: {e}");
+                json!({ "error": e }).to_string()
+            }
+        }
+    }
+}
+
+fn parse_variable(variable: &str, what: &str) -> Result<pv::Spec, String> {
+    pv::Spec::parse_prefix(variable)
+        .filter(|(_, len)| *len == variable.len())
+        .map(|(spec, _)| spec)
+        .ok_or_else(|| format!("Invalid {what} variable `{variable}`"))
+}
+
+fn string_argument<'a>(arguments: &'a Value, name: &str) -> Result<&'a str, String> {
+    arguments
+        .get(name)
+        .and_then(Value::as_str)
+        .ok_or_else(|| format!("missing the string argument `{name}`"))
+}
+
+// The model may be talked into asking for anyone's contacts, so it
+// only gets those of the caller, or of the addresses the script
+// allows.
+fn lookup_contacts(
+    tools: &Tools,
+    msg: &mut opensips::sip_msg,
+    arguments: &Value,
+) -> Result<Value, String> {
+    let usrloc = tools.usrloc.as_ref().ok_or("usrloc isn't loaded")?;
+
+    let allowed = match &tools.aor_variable {
+        Some(variable) => variable.get(msg),
+        None => msg.from_uri().map(String::from),
+    };
+    let allowed = allowed
+        .as_deref()
+        .map(address)
+        .filter(|a| !a.is_empty())
+        .ok_or("there is no address of record the caller may look up")?;
+    if !usrloc.use_domain() && !allowed.contains('@') {
+        return Err("a domain is allowed, but usrloc doesn't use domains".into());
+    }
+
+    let aor = address(string_argument(arguments, "aor")?);
+    if !is_within(aor, allowed, usrloc.use_domain()) {
+        return Err(format!("the caller may not look up `{aor}`"));
+    }
+
+    let aor = if usrloc.use_domain() {
+        aor
+    } else {
+        aor.split('@').next().unwrap_or(aor)
+    };
+
+    let contacts = usrloc
+        .contacts(&tools.domain, aor)
+        .ok_or_else(|| format!("there is no usrloc table `{}`", tools.domain))?;
+
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64);
+    let contacts: Vec<_> = contacts
+        .into_iter()
+        .map(|c| {
+            json!({
+                "uri": c.uri,
+                "expires_in": c.expires.map(|e| (e - now).max(0)),
+                "user_agent": c.user_agent,
+            })
+        })
+        .collect();
+
+    Ok(json!({ "aor": aor, "contacts": contacts }))
+}
+
+/// The address of record of a SIP URI, without the scheme and the
+/// parameters.
+fn address(uri: &str) -> &str {
+    let aor = uri
+        .strip_prefix("sip:")
+        .or_else(|| uri.strip_prefix("sips:"))
+        .unwrap_or(uri);
+    aor.split([';', '?']).next().unwrap_or(aor)
+}
+
+/// Whether `aor` is the address of record `allowed`, or one of its
+/// addresses when `allowed` is a domain. Hosts are compared ignoring
+/// case, and not at all when usrloc doesn't use domains. Without
+/// domains, nothing is within a domain, as usrloc would look up the
+/// user in any domain.
+fn is_within(aor: &str, allowed: &str, use_domain: bool) -> bool {
+    let (user, host) = aor.rsplit_once('@').unwrap_or((aor, ""));
+
+    match allowed.rsplit_once('@') {
+        Some((allowed_user, _)) if !use_domain => user == allowed_user,
+        Some((allowed_user, allowed_host)) => {
+            user == allowed_user && host.eq_ignore_ascii_case(allowed_host)
+        }
+        None if !use_domain => false,
+        None => host.eq_ignore_ascii_case(allowed),
+    }
+}
+
+fn get_statistic(
+    _tools: &Tools,
+    _msg: &mut opensips::sip_msg,
+    arguments: &Value,
+) -> Result<Value, String> {
+    let name = string_argument(arguments, "name")?;
+    let value = statistic::value(name).ok_or_else(|| format!("unknown statistic `{name}`"))?;
+
+    Ok(json!({ "name": name, "value": value }))
+}
+
+fn route_to_extension(
+    tools: &Tools,
+    msg: &mut opensips::sip_msg,
+    arguments: &Value,
+) -> Result<Value, String> {
+    let variable = tools
+        .route_variable
+        .as_ref()
+        .ok_or("routing isn't set up")?;
+
+    let extension = string_argument(arguments, "extension")?;
+    let valid = (1..=32).contains(&extension.len())
+        && extension
+            .chars()
+            .all(|c| c.is_ascii_digit() || c == '*' || c == '#');
+    if !valid {
+        return Err(format!("`{extension}` isn't an extension"));
+    }
+
+    if !variable.set(msg, extension) {
+        return Err("unable to store the extension".into());
+    }
+
+    Ok(json!({ "routed_to": extension }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{address, is_within};
+
+    #[test]
+    fn strips_the_scheme() {
+        assert_eq!(address("sip:alice@example.com"), "alice@example.com");
+        assert_eq!(address("sips:alice@example.com"), "alice@example.com");
+        assert_eq!(address("alice@example.com"), "alice@example.com");
+    }
+
+    #[test]
+    fn strips_parameters_and_headers() {
+        assert_eq!(
+            address("sip:alice@example.com;transport=tls"),
+            "alice@example.com"
+        );
+        assert_eq!(
+            address("sip:alice@example.com?subject=hi"),
+            "alice@example.com"
+        );
+        assert_eq!(
+            address("sips:alice@example.com;gr=abc?subject=hi;x=y"),
+            "alice@example.com"
+        );
+    }
+
+    #[test]
+    fn keeps_the_port() {
+        assert_eq!(
+            address("sip:alice@example.com:5061;transport=tls"),
+            "alice@example.com:5061"
+        );
+    }
+
+    #[test]
+    fn allows_the_address_of_record() {
+        assert!(is_within("alice@example.com", "alice@example.com", true));
+        assert!(!is_within("bob@example.com", "alice@example.com", true));
+        assert!(!is_within("alice@example.org", "alice@example.com", true));
+        assert!(!is_within("alice", "alice@example.com", true));
+    }
+
+    #[test]
+    fn compares_hosts_ignoring_case() {
+        assert!(is_within("alice@Example.COM", "alice@example.com", true));
+        assert!(is_within("bob@EXAMPLE.com", "example.com", true));
+        assert!(!is_within("Alice@example.com", "alice@example.com", true));
+    }
+
+    #[test]
+    fn compares_ports_as_part_of_the_host() {
+        let allowed = address("sip:alice@example.com:5061;transport=tls");
+        assert!(is_within("alice@example.com:5061", allowed, true));
+        assert!(!is_within("alice@example.com", allowed, true));
+        assert!(!is_within("alice@example.com:5060", allowed, true));
+    }
+
+    #[test]
+    fn allows_the_addresses_of_a_domain() {
+        assert!(is_within("alice@example.com", "example.com", true));
+        assert!(is_within("bob@example.com", "example.com", true));
+        assert!(!is_within("bob@example.org", "example.com", true));
+        assert!(!is_within("bob@sub.example.com", "example.com", true));
+        assert!(!is_within("bob", "example.com", true));
+    }
+
+    #[test]
+    fn ignores_hosts_without_domains() {
+        assert!(is_within("alice@example.com", "alice@example.com", false));
+        assert!(is_within("alice@example.org", "alice@example.com", false));
+        assert!(is_within("alice", "alice@example.com", false));
+        assert!(!is_within("bob@example.com", "alice@example.com", false));
+    }
+
+    #[test]
+    fn allows_no_domain_without_domains() {
+        assert!(!is_within("alice@example.com", "example.com", false));
+        assert!(!is_within("alice", "example.com", false));
+    }
+}
@@ -0,0 +1,527 @@
+use crate::opensips_log;
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    os::unix::{fs::MetadataExt, net::UnixDatagram},
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, SystemTime},
+};
+use tracing_core::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+// Lines beyond this are dropped instead of making the caller wait.
+const QUEUE_SIZE: usize = 1024;
+
+const SYSLOG_SOCKET: &str = "/dev/log";
+const SYSLOG_TAG: &str = "opensips";
+
+/// Somewhere complete log lines can be written to. Sinks are only
+/// used from the worker thread of [`NonBlocking`].
+pub trait Sink: Send {
+    fn write_line(&mut self, level: Level, line: &[u8]) -> io::Result<()>;
+}
+
+type OpenSink = dyn Fn() -> io::Result<Box<dyn Sink>> + Send + Sync;
+
+/// Hands log lines to a worker thread which writes them to a
+/// [`Sink`], so a slow destination never stalls a SIP worker. When
+/// the queue is full, lines are dropped and counted.
+///
+/// Threads don't survive a fork, so each process starts its own
+/// worker (and opens its own sink) the first time it logs.
+pub struct NonBlocking {
+    open: Arc<OpenSink>,
+    // The lock is only held to check the PID and clone the sender.
+    worker: Mutex<Option<Worker>>,
+    dropped: Arc<AtomicU64>,
+}
+
+struct Worker {
+    pid: u32,
+    // `None` when the sink couldn't be opened in this process.
+    tx: Option<SyncSender<(Level, Vec<u8>)>>,
+}
+
+impl NonBlocking {
+    pub fn new<F>(open: F) -> Self
+    where
+        F: Fn() -> io::Result<Box<dyn Sink>> + Send + Sync + 'static,
+    {
+        Self {
+            open: Arc::new(open),
+            worker: Mutex::new(None),
+            dropped: Default::default(),
+        }
+    }
+
+    fn send(&self, level: Level, line: Vec<u8>) {
+        let Some(tx) = self.sender() else { return };
+
+        if let Err(TrySendError::Full(_)) = tx.try_send((level, line)) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn sender(&self) -> Option<SyncSender<(Level, Vec<u8>)>> {
+        let mut worker = self.worker.lock().ok()?;
+        let pid = process::id();
+
+        if worker.as_ref().is_none_or(|w| w.pid != pid) {
+            *worker = Some(self.spawn(pid));
+        }
+
+        worker.as_ref().and_then(|w| w.tx.clone())
+    }
+
+    // Failures go to the OpenSIPS log, as this is where our own logs
+    // would have gone. They aren't retried until the next fork.
+    fn spawn(&self, pid: u32) -> Worker {
+        let tx = match self.start() {
+            Ok(tx) => Some(tx),
+            Err(e) => {
+                opensips_log::error(&format!("Unable to start the Rust log writer: {e}"));
+                None
+            }
+        };
+
+        Worker { pid, tx }
+    }
+
+    fn start(&self) -> io::Result<SyncSender<(Level, Vec<u8>)>> {
+        let mut sink = (self.open)()?;
+        let (tx, rx) = mpsc::sync_channel::<(Level, Vec<u8>)>(QUEUE_SIZE);
+        let dropped = self.dropped.clone();
+
+        // Anything counted belongs to the parent process.
+        dropped.store(0, Ordering::Relaxed);
+
+        thread::Builder::new()
+            .name("rust-log".into())
+            .spawn(move || {
+                for (level, line) in rx {
+                    let dropped = dropped.swap(0, Ordering::Relaxed);
+                    if dropped != 0 {
+                        let warning = format!("Dropped {dropped} log lines\n");
+                        let _ = sink.write_line(Level::WARN, warning.as_bytes());
+                    }
+
+                    let _ = sink.write_line(level, &line);
+                }
+            })?;
+
+        Ok(tx)
+    }
+}
+
+impl<'a> MakeWriter<'a> for NonBlocking {
+    type Writer = LineWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.make_line_writer(Level::INFO)
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        self.make_line_writer(*meta.level())
+    }
+}
+
+impl NonBlocking {
+    fn make_line_writer(&self, level: Level) -> LineWriter<'_> {
+        LineWriter {
+            parent: self,
+            level,
+            buf: Vec::new(),
+        }
+    }
+}
+
+/// Collects one formatted event and queues it when dropped.
+pub struct LineWriter<'a> {
+    parent: &'a NonBlocking,
+    level: Level,
+    buf: Vec<u8>,
+}
+
+impl Write for LineWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for LineWriter<'_> {
+    fn drop(&mut self) {
+        if !self.buf.is_empty() {
+            let buf = std::mem::take(&mut self.buf);
+            self.parent.send(self.level, buf);
+        }
+    }
+}
+
+/// The syslog facilities, as in `syslog.h`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Facility(u8);
+
+impl Default for Facility {
+    // Same as the OpenSIPS `log_facility` default.
+    fn default() -> Self {
+        Self(3)
+    }
+}
+
+impl FromStr for Facility {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let names = [
+            ("kern", 0),
+            ("user", 1),
+            ("mail", 2),
+            ("daemon", 3),
+            ("auth", 4),
+            ("syslog", 5),
+            ("lpr", 6),
+            ("news", 7),
+            ("uucp", 8),
+            ("cron", 9),
+            ("authpriv", 10),
+            ("ftp", 11),
+            ("local0", 16),
+            ("local1", 17),
+            ("local2", 18),
+            ("local3", 19),
+            ("local4", 20),
+            ("local5", 21),
+            ("local6", 22),
+            ("local7", 23),
+        ];
+
+        let name = s.trim_start_matches("LOG_").to_ascii_lowercase();
+        names
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|&(_, code)| Self(code))
+            .ok_or_else(|| format!("Unknown syslog facility `{s}`"))
+    }
+}
+
+/// Sends each line as a datagram to the local syslog daemon.
+pub struct Syslog {
+    socket: UnixDatagram,
+    facility: Facility,
+}
+
+impl Syslog {
+    pub fn open(facility: Facility) -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(SYSLOG_SOCKET)?;
+        Ok(Self { socket, facility })
+    }
+
+    fn severity(level: Level) -> u8 {
+        match level {
+            Level::ERROR => 3,
+            Level::WARN => 4,
+            Level::INFO => 6,
+            Level::DEBUG | Level::TRACE => 7,
+        }
+    }
+}
+
+impl Sink for Syslog {
+    fn write_line(&mut self, level: Level, line: &[u8]) -> io::Result<()> {
+        let priority = self.facility.0 * 8 + Self::severity(level);
+        let pid = process::id();
+
+        // The daemon adds the timestamp and hostname.
+        let mut message = format!("<{priority}>{SYSLOG_TAG}[{pid}]: ").into_bytes();
+        message.extend_from_slice(line.strip_suffix(b"\n").unwrap_or(line));
+
+        if self.socket.send(&message).is_err() {
+            // The daemon may have been restarted.
+            self.socket.connect(SYSLOG_SOCKET)?;
+            self.socket.send(&message)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FileConfig {
+    pub path: PathBuf,
+    /// Rotate once the file is larger than this many bytes.
+    pub max_size: Option<u64>,
+    /// Rotate when the wall clock crosses a multiple of this.
+    pub interval: Option<Duration>,
+    /// How many rotated files (`<path>.1`, `<path>.2`, ...) to keep.
+    pub keep: usize,
+}
+
+/// Appends to a file, rotating it by size and / or time.
+///
+/// Every process appends to the same file. Whichever process first
+/// notices that the file needs rotating renames it; the others
+/// notice that the path refers to a new file and reopen it.
+pub struct RotatingFile {
+    config: FileConfig,
+    file: File,
+    inode: u64,
+    period: u64,
+}
+
+impl RotatingFile {
+    pub fn open(config: FileConfig) -> io::Result<Self> {
+        let (file, inode) = Self::open_file(&config.path)?;
+        let period = Self::period(&config);
+
+        Ok(Self {
+            config,
+            file,
+            inode,
+            period,
+        })
+    }
+
+    /// Whether `path` can be appended to, without creating the file
+    /// yet when it doesn't exist.
+    pub fn check(path: &Path) -> io::Result<()> {
+        match OpenOptions::new().append(true).open(path) {
+            Ok(_) => return Ok(()),
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            Err(_) => {}
+        }
+
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let metadata = fs::metadata(dir)?;
+        let problem = if !metadata.is_dir() {
+            "isn't a directory"
+        } else if metadata.permissions().readonly() {
+            "is read-only"
+        } else {
+            return Ok(());
+        };
+        Err(io::Error::other(format!("`{}` {problem}", dir.display())))
+    }
+
+    fn open_file(path: &Path) -> io::Result<(File, u64)> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let inode = file.metadata()?.ino();
+        Ok((file, inode))
+    }
+
+    fn period(config: &FileConfig) -> u64 {
+        let Some(interval) = config.interval.filter(|i| !i.is_zero()) else {
+            return 0;
+        };
+
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        now.as_secs() / interval.as_secs().max(1)
+    }
+
+    fn reopen(&mut self) -> io::Result<()> {
+        (self.file, self.inode) = Self::open_file(&self.config.path)?;
+        self.period = Self::period(&self.config);
+        Ok(())
+    }
+
+    fn rotated_path(&self, suffix: impl fmt::Display) -> PathBuf {
+        let mut path = self.config.path.clone().into_os_string();
+        path.push(format!(".{suffix}"));
+        path.into()
+    }
+
+    // Whether the path still refers to the file this process writes
+    // to, rather than one another process put in its place.
+    fn is_current(&self) -> bool {
+        fs::metadata(&self.config.path).is_ok_and(|m| m.ino() == self.inode)
+    }
+
+    // Several processes may find that the file needs rotating at the
+    // same time. Whoever moves it out of the way first rotates it, the
+    // others only reopen the new one.
+    fn rotate(&mut self) -> io::Result<()> {
+        let claimed = self.rotated_path(format_args!("rotating-{}", process::id()));
+        if !self.is_current() || fs::rename(&self.config.path, &claimed).is_err() {
+            return self.reopen();
+        }
+
+        if self.config.keep == 0 {
+            let _ = fs::remove_file(&claimed);
+        } else {
+            for n in (1..self.config.keep).rev() {
+                let _ = fs::rename(self.rotated_path(n), self.rotated_path(n + 1));
+            }
+            let _ = fs::rename(&claimed, self.rotated_path(1));
+        }
+
+        self.reopen()
+    }
+
+    fn check_rotation(&mut self) -> io::Result<()> {
+        // Another process already rotated the file.
+        if !self.is_current() {
+            return self.reopen();
+        }
+
+        let too_big = self
+            .config
+            .max_size
+            .is_some_and(|max| self.file.metadata().is_ok_and(|m| m.len() >= max));
+        let too_old = Self::period(&self.config) != self.period;
+
+        if too_big || too_old {
+            self.rotate()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Sink for RotatingFile {
+    fn write_line(&mut self, _level: Level, line: &[u8]) -> io::Result<()> {
+        self.check_rotation()?;
+        self.file.write_all(line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+        process,
+    };
+
+    use tracing_core::Level;
+
+    use super::{Facility, FileConfig, RotatingFile, Sink};
+
+    // A fresh directory per test, as they run in parallel.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rust-log-{name}-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).expect("the temporary directory is writable");
+        dir
+    }
+
+    fn config(dir: &Path, keep: usize) -> FileConfig {
+        FileConfig {
+            path: dir.join("rust.log"),
+            max_size: Some(10),
+            interval: None,
+            keep,
+        }
+    }
+
+    fn write(file: &mut RotatingFile, line: &str) {
+        file.write_line(Level::INFO, line.as_bytes())
+            .expect("the line is written");
+    }
+
+    fn read(dir: &Path, name: &str) -> Option<String> {
+        fs::read_to_string(dir.join(name)).ok()
+    }
+
+    #[test]
+    fn parses_facilities() {
+        assert_eq!("daemon".parse(), Ok(Facility(3)));
+        assert_eq!("LOG_LOCAL0".parse(), Ok(Facility(16)));
+        assert_eq!("Local7".parse(), Ok(Facility(23)));
+        assert!("LOG_".parse::<Facility>().is_err());
+        assert!("local8".parse::<Facility>().is_err());
+    }
+
+    #[test]
+    fn rotates_by_size() {
+        let dir = temp_dir("size");
+        let mut file = RotatingFile::open(config(&dir, 2)).expect("the file opens");
+
+        // Each line is over the limit, so every write rotates.
+        for line in [
+            "first line\n",
+            "second line\n",
+            "third line\n",
+            "fourth line\n",
+        ] {
+            write(&mut file, line);
+        }
+
+        assert_eq!(read(&dir, "rust.log").as_deref(), Some("fourth line\n"));
+        assert_eq!(read(&dir, "rust.log.1").as_deref(), Some("third line\n"));
+        assert_eq!(read(&dir, "rust.log.2").as_deref(), Some("second line\n"));
+        assert_eq!(read(&dir, "rust.log.3"), None);
+    }
+
+    #[test]
+    fn rotates_without_keeping_anything() {
+        let dir = temp_dir("keep");
+        let mut file = RotatingFile::open(config(&dir, 0)).expect("the file opens");
+
+        write(&mut file, "first line\n");
+        write(&mut file, "second line\n");
+
+        assert_eq!(read(&dir, "rust.log").as_deref(), Some("second line\n"));
+        assert_eq!(read(&dir, "rust.log.1"), None);
+    }
+
+    #[test]
+    fn reopens_after_another_writer_rotated() {
+        let dir = temp_dir("reopen");
+        let mut one = RotatingFile::open(config(&dir, 2)).expect("the file opens");
+        let mut other = RotatingFile::open(config(&dir, 2)).expect("the file opens");
+
+        write(&mut one, "first line\n");
+        write(&mut other, "second line\n");
+        write(&mut one, "third\n");
+
+        assert_eq!(
+            read(&dir, "rust.log").as_deref(),
+            Some("second line\nthird\n")
+        );
+        assert_eq!(read(&dir, "rust.log.1").as_deref(), Some("first line\n"));
+    }
+
+    #[test]
+    fn rotates_once_when_both_writers_see_it_full() {
+        let dir = temp_dir("race");
+        let mut one = RotatingFile::open(config(&dir, 2)).expect("the file opens");
+        let mut other = RotatingFile::open(config(&dir, 2)).expect("the file opens");
+
+        write(&mut one, "first line\n");
+        one.rotate().expect("the file rotates");
+        other.rotate().expect("the file reopens");
+        write(&mut other, "second\n");
+
+        assert_eq!(read(&dir, "rust.log").as_deref(), Some("second\n"));
+        assert_eq!(read(&dir, "rust.log.1").as_deref(), Some("first line\n"));
+        assert_eq!(read(&dir, "rust.log.2"), None);
+    }
+
+    #[test]
+    fn checks_without_creating() {
+        let dir = temp_dir("check");
+
+        RotatingFile::check(&dir.join("rust.log")).expect("the directory is writable");
+        assert_eq!(read(&dir, "rust.log"), None);
+        assert!(RotatingFile::check(&dir.join("missing/rust.log")).is_err());
+    }
+}